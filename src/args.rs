@@ -1,11 +1,19 @@
+use std::time::Duration;
+
 use clap::{Args, Parser, Subcommand};
 
+use crate::config::DiffEngineChoice;
+
 #[derive(Debug, Parser)]
 #[clap(author, version, about)]
 // Program arguments
 pub struct MineGitArgs {
     #[clap(subcommand)]
     pub command: Commands,
+
+    /// Wait for other MineGit processes to release the repository instead of failing
+    #[clap(long, global = true, default_value_t = false)]
+    pub wait: bool,
 }
 // Command types
 #[derive(Debug, Subcommand)]
@@ -13,13 +21,108 @@ pub enum Commands {
     /// Create repo
     Init,
     /// List all commits
-    List,
+    List(ListArgs),
+    /// Show uncommitted changes of the world, including renamed files
+    Status,
     /// Restore specific commit
     Restore(RestoreArgs),
     /// Add new commit
     Commit(CommitArgs),
     /// Compare hashes of 2 files
     Compare(CompareArgs),
+    /// Watch the world and commit changes automatically
+    Watch(WatchArgs),
+    /// Read or change the repository configuration
+    #[clap(subcommand)]
+    Config(ConfigCommands),
+    /// Remove commits not kept by the retention rules of the config
+    Prune,
+    /// Migrate a repository created by an older MineGit to the current format
+    Upgrade,
+    /// Write a commit into a .zip or .tar.zst archive without touching the world
+    Export(ExportArgs),
+    /// Write a commit into another directory, optionally only some regions or paths
+    Checkout(CheckoutArgs),
+    /// Add backups (world directories, .zip, .tar or .tar.zst) as commits in chronological order
+    Import(ImportArgs),
+    /// Manage the repositories commits are pushed to and fetched from
+    #[clap(subcommand)]
+    Remote(RemoteCommands),
+    /// Send missing commits to a remote repository, which must not have world files
+    Push(RemoteArgs),
+    /// Receive missing commits from a remote repository without changing the world
+    Fetch(RemoteArgs),
+    /// Fetch from a remote repository and restore its head
+    Pull(PullArgs),
+    /// Serve the repository to push and fetch over the network
+    Serve(ServeArgs),
+    /// Move commits through a single file instead of a remote
+    #[clap(subcommand)]
+    Bundle(BundleCommands),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BundleCommands {
+    /// Write commits into a bundle file. The range is A..B (after A up to B), A.. (after A)
+    /// or B (up to B), e.g. 10..HEAD
+    Create { file: String, range: String },
+    /// Add the commits of a bundle file without changing the world
+    Unbundle { file: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RemoteCommands {
+    /// Add a remote repository by the path of its world directory
+    Add { name: String, url: String },
+    /// Remove a remote
+    Remove { name: String },
+    /// List remotes
+    List,
+}
+
+#[derive(Debug, Args)]
+pub struct RemoteArgs {
+    /// Name or path of the remote, may be omitted if only one remote is configured
+    pub remote: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Talk the protocol over stdin and stdout, used as the remote command of ssh remotes
+    #[clap(long, default_value_t = false, conflicts_with = "listen")]
+    pub stdio: bool,
+
+    /// TCP address to listen on (e.g. 0.0.0.0:7878), clients use tcp://host:port remotes
+    #[clap(long, required_unless_present = "stdio")]
+    pub listen: Option<String>,
+
+    /// Accept pushes, the server is read-only otherwise. TCP connections are not
+    /// authenticated, so anyone who can reach the address can then push
+    #[clap(long, default_value_t = false)]
+    pub allow_push: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct PullArgs {
+    /// Name or path of the remote, may be omitted if only one remote is configured
+    pub remote: Option<String>,
+
+    /// Discard uncommitted changes instead of saving them in a hidden pre-restore commit
+    #[clap(long, default_value_t = false)]
+    pub discard: bool,
+
+    /// Restore even if the world is open in the game or a running server
+    #[clap(short, long, default_value_t = false)]
+    pub force: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommands {
+    /// Print the value of a key (e.g. core.compression)
+    Get { key: String },
+    /// Set the value of a key (e.g. core.compression 3). user.name and user.email are only
+    /// passed to hooks, commits do not record an author
+    Set { key: String, value: String },
 }
 
 #[derive(Debug, Args)]
@@ -30,6 +133,13 @@ pub struct CompareArgs {
     pub meta: bool,
 }
 
+#[derive(Debug, Args)]
+pub struct ListArgs {
+    /// Also show hidden pre-restore commits
+    #[clap(short, long, default_value_t = false)]
+    pub all: bool,
+}
+
 #[derive(Debug, Args)]
 pub struct CommitArgs {
     /// Commit tag
@@ -38,6 +148,14 @@ pub struct CommitArgs {
     /// List of 3-element integer arrays dimension,x,z (e.g. --regions -1,0,0 0,1,0)
     #[clap(short, long, value_parser=parse_region, num_args=1.., value_delimiter = ' ', allow_hyphen_values = true)]
     pub regions: Vec<[i32; 3]>,
+
+    /// Commit even if the world is open in the game or a running server
+    #[clap(short, long, default_value_t = false)]
+    pub force: bool,
+
+    /// Delta engine of every file of this commit instead of the configured ones
+    #[clap(long, value_enum)]
+    pub engine: Option<DiffEngineChoice>,
 }
 
 #[derive(Debug, Args)]
@@ -48,6 +166,73 @@ pub struct RestoreArgs {
     /// List of 3-element integer arrays dimension,x,z (e.g. --regions -1,0,0 0,1,0)
     #[clap(short, long, value_parser=parse_region, num_args=1.., value_delimiter = ' ', allow_hyphen_values = true)]
     pub regions: Vec<[i32; 3]>,
+
+    /// Discard uncommitted changes instead of saving them in a hidden pre-restore commit
+    #[clap(long, default_value_t = false)]
+    pub discard: bool,
+
+    /// Restore even if the world is open in the game or a running server
+    #[clap(short, long, default_value_t = false)]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Commit id, HEAD or HEAD~n
+    pub rev: String,
+
+    /// Archive to create, the format is taken from the extension (.zip or .tar.zst)
+    #[clap(short, long)]
+    pub output: String,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckoutArgs {
+    /// Commit id, HEAD or HEAD~n
+    pub rev: String,
+
+    /// Directory to write the files into, created if missing
+    #[clap(long)]
+    pub into: String,
+
+    /// List of 3-element integer arrays dimension,x,z (e.g. --regions -1,0,0 0,1,0)
+    #[clap(short, long, value_parser=parse_region, num_args=1.., value_delimiter = ' ', allow_hyphen_values = true)]
+    pub regions: Vec<[i32; 3]>,
+
+    /// Paths or glob patterns of files to write (e.g. --paths level.dat DIM-1)
+    #[clap(short, long, num_args = 1..)]
+    pub paths: Vec<String>,
+
+    /// Overwrite files in a directory that is not empty
+    #[clap(short, long, default_value_t = false)]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// Backup directories or archives
+    #[clap(required = true, num_args = 1..)]
+    pub sources: Vec<String>,
+
+    /// Take the commit dates from dates in the backup names (e.g. world_2021-03-04.zip)
+    /// instead of the modification times of their files
+    #[clap(long, default_value_t = false)]
+    pub date_from_name: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    /// Minimal time between automatic commits (e.g. 10m, 1h)
+    #[clap(short, long, value_parser = humantime::parse_duration, default_value = "10m")]
+    pub interval: Duration,
+
+    /// Time without changes to wait for before committing (e.g. 30s)
+    #[clap(short, long, value_parser = humantime::parse_duration, default_value = "30s")]
+    pub debounce: Duration,
+
+    /// Commit even if the world is open in the game or a running server
+    #[clap(short, long, default_value_t = false)]
+    pub force: bool,
 }
 
 // Custom parser for [i32; 3]
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use bitcode::{Decode, Encode};
+
+use crate::committer;
+use crate::protocol::{self, WireCommit, WirePack};
+use crate::sync::{self, RepoState};
+use crate::utils::fs_utils;
+
+// A bundle is a single file holding a range of commits, to move history between machines
+// without a connection (e.g. on a USB stick or as a mail attachment).
+//
+// It starts with the magic bytes and the format version, followed by frames like those of
+// the wire protocol: a `BundleHeader` and one `WirePack` per commit,
+// each followed by the chunk frames of its snapshots. The snapshots of a
+// bundle are deltas against the packages of the receiver, so a bundle that does not start
+// at the initial commit names the commit it builds on and the package lengths it expects.
+
+const MAGIC: &[u8; 8] = b"MGBUNDLE";
+const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Debug, Encode, Decode)]
+struct BundleHeader {
+    // Last commit before the range, the receiver must have it
+    prerequisite: Option<WireCommit>,
+    // Package lengths after the prerequisite, for the packages the bundle appends to
+    packages: Vec<(String, u64)>,
+    first: u32,
+    count: u32,
+    stash_ids: Vec<u32>,
+}
+
+// Parses `A..B` (the commits after A up to B), `A..` (the commits after A) or `B` (every
+// commit up to B), where A and B are revisions
+pub fn parse_range(target_path: &str, range: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    let (first, last) = match range.split_once("..") {
+        Some((base, last)) => {
+            let base = committer::resolve_rev(target_path, base)?;
+            let last = match last {
+                "" => committer::get_commit_count(target_path)?
+                    .checked_sub(1)
+                    .ok_or("The repository has no commits")?,
+                last => committer::resolve_rev(target_path, last)?,
+            };
+            (base + 1, last)
+        }
+        None => (0, committer::resolve_rev(target_path, range)?),
+    };
+    if first > last {
+        return Err(format!("The range {range} contains no commits").into());
+    }
+    Ok((first, last))
+}
+
+// Writes the commits `first..=last` into a bundle, returns the number of bundled commits
+pub fn create(
+    target_path: &str,
+    output_path: &str,
+    first: u32,
+    last: u32,
+) -> Result<u32, Box<dyn Error>> {
+    let base_lens = sync::package_lens_at(target_path, first)?;
+    let prerequisite = match first {
+        0 => None,
+        first => Some(WireCommit::from(&committer::get_commit_by_id(
+            target_path,
+            first - 1,
+        )?)),
+    };
+
+    // Only the packages the bundle appends to matter to the receiver. A package whose end is
+    // rewritten first has a length depending on when the receiver got it, see `sync`. The
+    // packs are read again while writing, so only one is held at a time.
+    let mut package_lens = base_lens.clone();
+    let mut first_snapshots: HashMap<String, u64> = HashMap::new();
+    for id in first..=last {
+        for snapshot in sync::read_commit_pack(target_path, id, &mut package_lens)?.snapshots {
+            first_snapshots.entry(snapshot.path).or_insert(snapshot.pos);
+        }
+    }
+    let mut packages: Vec<(String, u64)> = first_snapshots
+        .into_iter()
+        .map(|(path, pos)| {
+            let len = base_lens.get(&path).copied().unwrap_or(0);
+            (path, pos, len)
+        })
+        .filter(|&(_, pos, len)| pos == len)
+        .map(|(path, _, len)| (path, len))
+        .collect();
+    packages.sort();
+
+    let stash_ids = committer::read_stash_ids(target_path)?
+        .into_iter()
+        .filter(|id| (first..=last).contains(id))
+        .collect();
+    let header = BundleHeader {
+        prerequisite,
+        packages,
+        first,
+        count: last - first + 1,
+        stash_ids,
+    };
+
+    // Write next to the output first, so a failed bundle does not look complete
+    let temp_path = format!("{output_path}.tmp");
+    let result = write_bundle(target_path, &temp_path, &header, base_lens);
+    if let Err(e) = result {
+        let _ = fs_utils::remove_file(&temp_path);
+        return Err(e);
+    }
+    std::fs::rename(&temp_path, output_path)?;
+    Ok(header.count)
+}
+
+fn write_bundle(
+    target_path: &str,
+    path: &str,
+    header: &BundleHeader,
+    mut package_lens: HashMap<String, u64>,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&BUNDLE_VERSION.to_be_bytes())?;
+    protocol::write_frame(&mut writer, header)?;
+    for id in header.first..header.first + header.count {
+        let pack = sync::read_commit_pack(target_path, id, &mut package_lens)?;
+        protocol::write_frame(&mut writer, &WirePack::from(&pack))?;
+        protocol::write_snapshots(&mut writer, &pack)?;
+    }
+    writer.into_inner()?.sync_all()?;
+    Ok(())
+}
+
+// Adds the commits of a bundle the repository misses. Like fetch, the world and head are not
+// changed. Returns the number of added commits and the last commit of the bundle.
+pub fn unbundle(target_path: &str, bundle_path: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(bundle_path)?);
+
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| format!("{bundle_path} is not a bundle"))?;
+    if &magic != MAGIC {
+        return Err(format!("{bundle_path} is not a bundle").into());
+    }
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_be_bytes(version);
+    if version != BUNDLE_VERSION {
+        return Err(format!(
+            "{bundle_path} has bundle version {version}, this MineGit reads version {BUNDLE_VERSION}"
+        )
+        .into());
+    }
+
+    let header: BundleHeader =
+        protocol::read_frame(&mut reader)?.ok_or("The bundle has no header")?;
+    if header.count == 0 {
+        return Err("The bundle contains no commits".into());
+    }
+    let last = header.first + header.count - 1;
+    let local = RepoState::read(target_path)?;
+    let local_count = local.commits.len() as u32;
+
+    if let Some(prerequisite) = &header.prerequisite {
+        let prerequisite = prerequisite.to_commit()?;
+        match local.commits.get(prerequisite.id as usize) {
+            Some(commit) if sync::is_same_commit(commit, &prerequisite) => {}
+            Some(_) => {
+                return Err(format!(
+                    "The bundle builds on commit {}, which differs from the local one",
+                    prerequisite.id
+                )
+                .into())
+            }
+            None => {
+                return Err(format!(
+                    "The bundle builds on commit {}, get the commits up to it first",
+                    prerequisite.id
+                )
+                .into())
+            }
+        }
+    }
+    if local_count == header.first {
+        check_packages(&local.packages, &header.packages)?;
+    }
+
+    let mut count = 0;
+    for _ in 0..header.count {
+        let pack: WirePack = protocol::read_frame(&mut reader)?.ok_or("The bundle is truncated")?;
+        // The snapshots follow the pack and are read even for commits that are skipped
+        let pack = protocol::read_snapshots(&mut reader, pack, target_path)?;
+        let id = pack.commit.id;
+
+        // Commits the repository already has must be the same
+        if let Some(commit) = local.commits.get(id as usize) {
+            if !sync::is_same_commit(commit, &pack.commit) {
+                return Err(
+                    format!("The bundle and the repository diverged at commit {id}").into(),
+                );
+            }
+            continue;
+        }
+        sync::apply_commit_pack(target_path, &pack)?;
+        count += 1;
+    }
+
+    sync::remove_incoming(target_path)?;
+    sync::merge_stash_ids(target_path, &header.stash_ids)?;
+    Ok((count, last))
+}
+
+fn check_packages(
+    local: &HashMap<String, u64>,
+    expected: &[(String, u64)],
+) -> Result<(), Box<dyn Error>> {
+    for (path, len) in expected {
+        if local.get(path).copied().unwrap_or(0) != *len {
+            return Err(format!(
+                "The package of {path} does not match the one the bundle was created against"
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initializer;
+    use crate::savefiles::{DATA_DIRECTORY_NAME, DIRECTORY_NAME};
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+
+    // Repository with an initial commit and two commits changing a region file
+    fn source_repo(name: &str) -> String {
+        let world = temp_dir(name);
+        let mut region: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        initializer::init(&world).unwrap();
+        for (version, range) in [(1, 100..200), (2, 5000..5100)] {
+            region[range].fill(version);
+            write_world_file(&world, "region/r.0.0.mca", &region);
+            committer::add_commit(&world, &format!("version {version}"), Vec::new()).unwrap();
+        }
+        world
+    }
+
+    fn package(world: &str) -> Vec<u8> {
+        read_world_file(
+            world,
+            &format!("{DIRECTORY_NAME}/{DATA_DIRECTORY_NAME}/region/r.0.0.mca.pkg"),
+        )
+    }
+
+    #[test]
+    fn unbundles_what_create_bundled() {
+        let source = source_repo("bundle-source");
+        let bundles = temp_dir("bundle-files");
+        let full = format!("{bundles}/full.bundle");
+        let next = format!("{bundles}/next.bundle");
+        let (first, last) = parse_range(&source, "1").unwrap();
+        assert_eq!(create(&source, &full, first, last).unwrap(), 2);
+        let (first, last) = parse_range(&source, "1..").unwrap();
+        assert_eq!(create(&source, &next, first, last).unwrap(), 1);
+
+        // The rest of the history needs the commits before it
+        let clone = temp_dir("bundle-clone");
+        initializer::init_empty(&clone).unwrap();
+        assert!(unbundle(&clone, &next).is_err());
+        assert_eq!(unbundle(&clone, &full).unwrap(), (2, 1));
+        assert_eq!(unbundle(&clone, &next).unwrap(), (1, 2));
+        assert_eq!(unbundle(&clone, &next).unwrap(), (0, 2));
+
+        assert_eq!(
+            committer::read_all_commits(&clone).unwrap().len(),
+            committer::read_all_commits(&source).unwrap().len()
+        );
+        assert_eq!(package(&clone), package(&source));
+        committer::restore(&clone, 2, Vec::new(), true).unwrap();
+        assert_eq!(
+            read_world_file(&clone, "region/r.0.0.mca"),
+            read_world_file(&source, "region/r.0.0.mca")
+        );
+    }
+
+    #[test]
+    fn rejects_a_bundle_of_another_version() {
+        let source = source_repo("bundle-version-source");
+        let path = format!("{}/full.bundle", temp_dir("bundle-version-files"));
+        create(&source, &path, 0, 2).unwrap();
+
+        let mut bundle = std::fs::read(&path).unwrap();
+        bundle[MAGIC.len()..][..4].copy_from_slice(&(BUNDLE_VERSION + 1).to_be_bytes());
+        std::fs::write(&path, bundle).unwrap();
+
+        let clone = temp_dir("bundle-version-clone");
+        initializer::init_empty(&clone).unwrap();
+        let error = unbundle(&clone, &path).unwrap_err();
+        assert!(error.to_string().contains("bundle version"));
+        assert_eq!(committer::get_commit_count(&clone).unwrap(), 0);
+    }
+}
@@ -1,7 +1,21 @@
 use crate::args::*;
+use crate::bundle;
 use crate::committer;
+use crate::config::{self, Config};
+use crate::exporter;
+use crate::hooks;
+use crate::importer;
 use crate::initializer;
+use crate::journal;
+use crate::lock::{DataLock, RepoLock};
+use crate::migrate;
+use crate::protocol;
+use crate::remote;
+use crate::retention;
+use crate::savefiles::{DIRECTORY_NAME, FORMAT_VERSION};
+use crate::session_lock;
 use crate::utils::fs_utils;
+use crate::watcher;
 
 use clap::Parser;
 // Runs the CLI application
@@ -11,20 +25,126 @@ pub fn run() {
     // Get current path
     let root_path = fs_utils::get_current_path().unwrap();
 
+    // A broken config is reported by every command except those that repair it
+    let config = match args.command {
+        Commands::Config(_) => Config::default(),
+        _ => Config::load(&root_path)
+            .unwrap_or_else(|e| exit_with_error(format!("Invalid .minegit/config: {e}").into())),
+    };
+
+    // Refuse to touch a world that is loaded by the game.
+    // A commit is safe with RCON configured, since the server stops saving meanwhile.
+    match &args.command {
+        Commands::Commit(args) => {
+            ensure_world_not_in_use(&root_path, args.force || config.rcon.is_some())
+        }
+        Commands::Restore(args) => ensure_world_not_in_use(&root_path, args.force),
+        Commands::Pull(args) => ensure_world_not_in_use(&root_path, args.force),
+        _ => {}
+    }
+
+    // Fetching into a directory without a repository clones the remote
+    if matches!(
+        args.command,
+        Commands::Fetch(_) | Commands::Pull(_) | Commands::Bundle(BundleCommands::Unbundle { .. })
+    ) && !fs_utils::is_path_exists(&fs_utils::build_path([&root_path, DIRECTORY_NAME]).unwrap())
+    {
+        initializer::init_empty(&root_path).unwrap_or_else(|e| exit_with_error(e));
+    }
+
+    // Mutating commands hold the repository lock until they finish
+    let mutating = matches!(
+        args.command,
+        Commands::Commit(_)
+            | Commands::Restore(_)
+            | Commands::Prune
+            | Commands::Import(_)
+            | Commands::Push(_)
+            | Commands::Fetch(_)
+            | Commands::Pull(_)
+            | Commands::Bundle(BundleCommands::Unbundle { .. })
+    );
+    // Reading commands run alongside a commit of another process, they only share the data
+    // lock while a commit with reverse deltas rewrites the end of packages
+    let reading = matches!(
+        args.command,
+        Commands::List(_)
+            | Commands::Status
+            | Commands::Export(_)
+            | Commands::Checkout(_)
+            | Commands::Bundle(BundleCommands::Create { .. })
+    );
+    let (lock, _data_lock) = if mutating || matches!(args.command, Commands::Upgrade) {
+        let lock = RepoLock::acquire(&root_path, args.wait).unwrap_or_else(|e| exit_with_error(e));
+
+        // Roll back a commit or restore that was interrupted on a previous run
+        journal::recover_all(&root_path).unwrap();
+        (Some(lock), None)
+    } else if reading {
+        // Nothing is rolled back while another process holds the lock, it may still be
+        // committing. The lock is released right away, so a commit started meanwhile is
+        // not refused.
+        if let Ok(_lock) = RepoLock::acquire(&root_path, false) {
+            journal::recover_all(&root_path).unwrap();
+        }
+        let data_lock = DataLock::shared(&root_path).unwrap_or_else(|e| exit_with_error(e.into()));
+        (None, Some(data_lock))
+    } else {
+        (None, None)
+    };
+
+    // Repositories of another format are only read after `minegit upgrade`
+    if !matches!(
+        args.command,
+        Commands::Init | Commands::Compare(_) | Commands::Upgrade
+    ) && fs_utils::is_path_exists(&fs_utils::build_path([&root_path, DIRECTORY_NAME]).unwrap())
+    {
+        if let Err(e) = migrate::check_format(&root_path) {
+            drop(lock);
+            exit_with_error(e);
+        }
+    }
+
     // Handle arguments
+    let wait = args.wait;
     match args.command {
         Commands::Init => {
             println!("Init called");
             initializer::init(&root_path).unwrap_or_else(|e| println!("{e}"));
         }
         Commands::Commit(args) => {
-            committer::add_commit(&root_path, &args.tag, args.regions).unwrap();
+            let result = committer::commit_world(
+                &root_path,
+                &args.tag,
+                args.regions.clone(),
+                args.engine,
+                config.rcon.as_ref(),
+            );
+            if let Err(e) = result {
+                drop(lock);
+                exit_with_error(e);
+            }
         }
-        Commands::List => {
-            committer::print_all_commits(&root_path).unwrap();
+        Commands::List(args) => {
+            if let Err(e) = committer::print_all_commits(&root_path, args.all) {
+                drop(lock);
+                exit_with_error(e);
+            }
+        }
+        Commands::Status => {
+            if let Err(e) = committer::print_status(&root_path) {
+                drop(lock);
+                exit_with_error(e);
+            }
         }
         Commands::Restore(args) => {
-            committer::restore(&root_path, args.id, args.regions).unwrap();
+            let result = hooks::with_restore_hooks(&root_path, args.id, &args.regions, || {
+                committer::restore(&root_path, args.id, args.regions.clone(), args.discard)
+            });
+            if let Err(e) = result {
+                drop(lock);
+                exit_with_error(e);
+            }
         }
         Commands::Compare(args) => {
             // Compare files
@@ -32,5 +152,166 @@ pub fn run() {
                 .unwrap_or_else(|e| panic!("{e}"));
             println!("Files are{} equal.", if equal { "" } else { " not" });
         }
+        Commands::Watch(args) => {
+            watcher::watch(&root_path, args.interval, args.debounce, args.force).unwrap();
+        }
+        Commands::Config(ConfigCommands::Get { key }) => {
+            match config::get_value(&root_path, &key) {
+                Ok(value) => println!("{value}"),
+                Err(e) => exit_with_error(e),
+            }
+        }
+        Commands::Config(ConfigCommands::Set { key, value }) => {
+            config::set_value(&root_path, &key, &value).unwrap_or_else(|e| exit_with_error(e));
+        }
+        Commands::Prune => {
+            if let Err(e) = retention::prune(&root_path) {
+                drop(lock);
+                exit_with_error(e);
+            }
+        }
+        Commands::Upgrade => match migrate::upgrade(&root_path) {
+            Ok(FORMAT_VERSION) => {
+                println!("The repository already has format version {FORMAT_VERSION}.")
+            }
+            Ok(version) => println!(
+                "Upgraded the repository from format version {version} to {FORMAT_VERSION}."
+            ),
+            Err(e) => {
+                drop(lock);
+                exit_with_error(e);
+            }
+        },
+        Commands::Export(args) => {
+            let result = committer::resolve_rev(&root_path, &args.rev)
+                .and_then(|id| exporter::export(&root_path, id, &args.output));
+            match result {
+                Ok(()) => println!("Exported {} to {}", args.rev, args.output),
+                Err(e) => {
+                    drop(lock);
+                    exit_with_error(e);
+                }
+            }
+        }
+        Commands::Import(args) => {
+            match importer::import(&root_path, &args.sources, args.date_from_name) {
+                Ok(ids) if !ids.is_empty() => println!(
+                    "Imported {} backup(s), the world was not changed. Use `minegit restore {}` to load the newest one.",
+                    ids.len(),
+                    ids[ids.len() - 1]
+                ),
+                Ok(_) => {}
+                Err(e) => {
+                    drop(lock);
+                    exit_with_error(e);
+                }
+            }
+        }
+        Commands::Remote(command) => {
+            let result = match command {
+                RemoteCommands::Add { name, url } => remote::add(&root_path, &name, &url),
+                RemoteCommands::Remove { name } => remote::remove(&root_path, &name),
+                RemoteCommands::List => remote::list(&root_path),
+            };
+            result.unwrap_or_else(|e| exit_with_error(e));
+        }
+        Commands::Push(args) => {
+            if let Err(e) = remote::push(&root_path, args.remote.as_deref(), wait) {
+                drop(lock);
+                exit_with_error(e);
+            }
+        }
+        Commands::Fetch(args) => {
+            if let Err(e) = remote::fetch(&root_path, args.remote.as_deref(), wait) {
+                drop(lock);
+                exit_with_error(e);
+            }
+        }
+        Commands::Pull(args) => {
+            let result = remote::fetch(&root_path, args.remote.as_deref(), wait).and_then(|head| {
+                if committer::get_head(&root_path).ok() == Some(head)
+                    && committer::get_uncommitted_changes(&root_path, &[])?.is_empty()
+                {
+                    println!("Already up to date.");
+                    return Ok(());
+                }
+                hooks::with_restore_hooks(&root_path, head, &[], || {
+                    committer::restore(&root_path, head, Vec::new(), args.discard)
+                })
+            });
+            if let Err(e) = result {
+                drop(lock);
+                exit_with_error(e);
+            }
+        }
+        Commands::Bundle(BundleCommands::Create { file, range }) => {
+            let result = bundle::parse_range(&root_path, &range)
+                .and_then(|(first, last)| bundle::create(&root_path, &file, first, last));
+            match result {
+                Ok(count) => println!("Bundled {count} commit(s) into {file}"),
+                Err(e) => {
+                    drop(lock);
+                    exit_with_error(e);
+                }
+            }
+        }
+        Commands::Bundle(BundleCommands::Unbundle { file }) => {
+            match bundle::unbundle(&root_path, &file) {
+                Ok((count, last)) => {
+                    println!("Added {count} commit(s) from {file}");
+                    println!("Run `minegit restore {last}` to switch the world to the newest one");
+                }
+                Err(e) => {
+                    drop(lock);
+                    exit_with_error(e);
+                }
+            }
+        }
+        Commands::Serve(args) => {
+            let result = match args.listen {
+                Some(address) => protocol::serve_tcp(&root_path, &address, !args.allow_push),
+                None => protocol::serve_stdio(&root_path, !args.allow_push),
+            };
+            result.unwrap_or_else(|e| exit_with_error(e));
+        }
+        Commands::Checkout(args) => {
+            let result = committer::resolve_rev(&root_path, &args.rev).and_then(|id| {
+                exporter::checkout(
+                    &root_path,
+                    id,
+                    &args.into,
+                    &args.regions,
+                    &args.paths,
+                    args.force,
+                )
+            });
+            match result {
+                Ok(count) => println!(
+                    "Checked out {count} file(s) of {} into {}",
+                    args.rev, args.into
+                ),
+                Err(e) => {
+                    drop(lock);
+                    exit_with_error(e);
+                }
+            }
+        }
+    }
+}
+
+// Exits if the world is loaded by the game, unless forced
+fn ensure_world_not_in_use(root_path: &str, force: bool) {
+    if force || !session_lock::is_world_in_use(root_path).unwrap() {
+        return;
     }
+    eprintln!(
+        "The world is open in Minecraft or a running server (session.lock is held).\n\
+        Stop the server or close the world first, or pass --force to continue anyway."
+    );
+    std::process::exit(1);
+}
+
+fn exit_with_error(e: Box<dyn std::error::Error>) -> ! {
+    eprintln!("{e}");
+    std::process::exit(1);
 }
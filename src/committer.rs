@@ -1,21 +1,107 @@
 use bytemuck::{cast_slice, from_bytes};
 use chrono::{DateTime, Local};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
-use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
-use std::sync::Arc;
-use std::u64::MAX;
-use tokio::runtime::Runtime;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::runtime;
+use tokio::sync::Semaphore;
 use zstd::{decode_all, encode_all};
 
+use crate::config::{Config, CoreConfig, DiffEngineChoice, Layout, RconConfig};
+use crate::hooks;
 use crate::ignore_filter::IgnoreFilter;
+use crate::journal::{Journal, RestoreJournal};
+use crate::lock::DataLock;
+use crate::objects::{ObjectIndex, ObjectLocation};
+use crate::rcon;
+use crate::recover::diff_gen::DiffEngine;
+use crate::recover::recover::Instruction;
 use crate::recover::snapshot::SnapshotHeader;
-use crate::savefiles::{CommitInfo, FileInfo, HEAD_FILE_NAME};
+use crate::renames;
+use crate::savefiles::{
+    CommitInfo, FileInfo, COMMITS_INFO_MAGIC, COMMITS_MAGIC, DATA_DIRECTORY_NAME, HEAD_FILE_NAME,
+    PACKAGE_MAGIC, STASH_FILE_NAME,
+};
+use crate::session_lock;
 use crate::{
     savefiles::{Commit, COMMITS_FILE_NAME, COMMITS_INFO_FILE_NAME, DIRECTORY_NAME},
     utils::fs_utils,
 };
 
+// Number of references followed while recovering a file
+const MAX_REFERENCE_DEPTH: u32 = 8;
+
+const MIB: u64 = 1024 * 1024;
+
+// Largest file diffed at once by `engine` within the memory budget, larger ones are diffed
+// in windows of this size
+fn diff_window(core: &CoreConfig, engine: DiffEngine) -> u64 {
+    match core.memory_budget {
+        0 => u64::MAX,
+        budget => (budget.saturating_mul(MIB) / engine.memory_factor()).max(1),
+    }
+}
+
+// Memory taken by recovering a byte of a file, for the buffers of its payloads
+const RESTORE_MEMORY_FACTOR: u64 = 4;
+
+// Part of a file recovered at once within the memory budget
+fn restore_window(core: &CoreConfig) -> u64 {
+    match core.memory_budget {
+        0 => u64::MAX,
+        budget => (budget.saturating_mul(MIB) / RESTORE_MEMORY_FACTOR).max(1),
+    }
+}
+
+// Permits of the memory budget taken by a file, files are only saved or restored in parallel
+// while their estimated memory fits in it
+fn memory_cost(core: &CoreConfig, memory_factor: u64, len: u64, window: u64) -> u32 {
+    let cost = (memory_factor * len.min(window)).div_ceil(MIB);
+    cost.clamp(1, core.memory_budget.max(1))
+        .min(u32::MAX as u64) as u32
+}
+
+fn build_runtime(core: &CoreConfig) -> io::Result<runtime::Runtime> {
+    let mut rt = runtime::Builder::new_multi_thread();
+    if core.threads != 0 {
+        rt.worker_threads(core.threads);
+    }
+    rt.enable_all().build()
+}
+
+// Number of restored files, shown while restoring on a terminal
+struct Progress {
+    done: AtomicUsize,
+    total: usize,
+    visible: bool,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        Self {
+            done: AtomicUsize::new(0),
+            total,
+            visible: total != 0 && io::stderr().is_terminal(),
+        }
+    }
+
+    fn advance(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.visible {
+            eprint!("\rRestored {done}/{} files", self.total);
+        }
+    }
+
+    fn finish(&self) {
+        if self.visible {
+            eprintln!();
+        }
+    }
+}
+
 fn get_root_path(target_path: &str) -> io::Result<String> {
     fs_utils::build_path([target_path, DIRECTORY_NAME])
 }
@@ -31,50 +117,174 @@ fn get_commits_info_path(target_path: &str) -> io::Result<String> {
     fs_utils::build_path([&get_root_path(target_path)?, COMMITS_INFO_FILE_NAME])
 }
 
+// Fails unless the file starts with `magic`. Files of another format have another magic,
+// `check_format` normally catches those before anything is read.
+fn check_magic<R: Read + Seek>(file: &mut R, magic: &[u8; 8], path: &str) -> io::Result<()> {
+    let mut found = [0u8; 8];
+    file.seek(SeekFrom::Start(0))?;
+    if file.read_exact(&mut found).is_err() || &found != magic {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{path} does not have the format of this MineGit, run `minegit upgrade` if an older version wrote it"),
+        ));
+    }
+    Ok(())
+}
+
+fn read_commits_file(target_path: &str) -> io::Result<File> {
+    let path = get_commits_path(target_path)?;
+    let mut file = fs_utils::read_file(&path)?;
+    check_magic(&mut file, COMMITS_MAGIC, &path)?;
+    Ok(file)
+}
+
+fn read_commits_info_file(target_path: &str) -> io::Result<File> {
+    let path = get_commits_info_path(target_path)?;
+    let mut file = fs_utils::read_file(&path)?;
+    check_magic(&mut file, COMMITS_INFO_MAGIC, &path)?;
+    Ok(file)
+}
+
+// Opens a package to read its snapshots
+pub fn read_package(package_path: &str) -> io::Result<File> {
+    let mut package = fs_utils::read_file(package_path)?;
+    check_magic(&mut package, PACKAGE_MAGIC, package_path)?;
+    Ok(package)
+}
+
 pub fn add_commit(
     target_path: &str,
     tag: &str,
     regions: Vec<[i32; 3]>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    add_commit_with(target_path, tag, regions, None)
+}
+
+// Like `add_commit` with the delta engine of every file given, `None` uses the configured ones
+pub fn add_commit_with(
+    target_path: &str,
+    tag: &str,
+    regions: Vec<[i32; 3]>,
+    engine: Option<DiffEngineChoice>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    add_commit_at(
+        target_path,
+        target_path,
+        tag,
+        regions,
+        Local::now().timestamp(),
+        engine,
+        None,
+    )
+}
+
+// Commits the world the way `minegit commit` does: between the commit hooks and, with `rcon`
+// given, while the server has saving paused so the hooks see the flushed world
+pub fn commit_world(
+    target_path: &str,
+    tag: &str,
+    regions: Vec<[i32; 3]>,
+    engine: Option<DiffEngineChoice>,
+    rcon: Option<&RconConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let commit = || {
+        hooks::with_commit_hooks(target_path, tag, &regions, || {
+            add_commit_with(target_path, tag, regions.clone(), engine)
+        })
+    };
+    match rcon {
+        Some(rcon_config) => rcon::with_saving_paused(rcon_config, commit),
+        None => commit(),
+    }
+}
+
+// Adds a commit of the files in `world_path` with the given creation time instead of the
+// current one. The world may be another directory than the one of the repository. With a
+// `parent` the commit is added on top of it and the head is left alone, like a fetched one.
+pub fn add_commit_at(
+    target_path: &str,
+    world_path: &str,
+    tag: &str,
+    regions: Vec<[i32; 3]>,
+    timestamp: i64,
+    engine: Option<DiffEngineChoice>,
+    parent: Option<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load(target_path)?;
+    if let Some(engine) = engine {
+        config.diff.engine = engine;
+        config.diff.patterns.clear();
+    }
     let commits_path = get_commits_path(target_path)?;
     let commits_info_path = get_commits_info_path(target_path)?;
 
+    let mut objects = ObjectIndex::load(target_path)?;
+
+    // Every write below is journaled so an interrupted commit can be rolled back
+    let journal = Arc::new(Mutex::new(Journal::begin(target_path)?));
+
     // Get current head
     let mut parent_id = 0;
-    match get_head(target_path) {
-        Ok(value) => {
+    match (parent, get_head(target_path)) {
+        (Some(parent), _) => parent_id = parent,
+        (None, Ok(value)) => {
             parent_id = value;
         }
-        Err(_) => {
+        (None, Err(_)) => {
             write_head(target_path, 0)?;
         }
     }
     // Get commit id
     let id = get_commit_count(target_path)?;
     // Create commit info
-    let rt = Runtime::new().unwrap();
-    let commit_info = rt.block_on(create_commit_info(target_path, id, parent_id, regions))?;
+    let rt = build_runtime(&config.core)?;
+    let file_info = rt.block_on(create_file_info(
+        target_path,
+        world_path,
+        parent_id,
+        regions,
+        &config,
+        Arc::clone(&journal),
+        &objects,
+    ))?;
+    let commit_info = CommitInfo { id, file_info };
 
     let commit_info_bytes = fs_utils::encode_to_bytes(&commit_info);
 
     // Compress using zstd
-    let compressed_commit_info = encode_all(Cursor::new(commit_info_bytes), 0)?;
+    let compressed_commit_info =
+        encode_all(Cursor::new(commit_info_bytes), config.core.info_compression)?;
 
     // append commit info file
-    let (_, commit_info_pos) = fs_utils::append_file(&commits_info_path, &compressed_commit_info)?;
+    let (commits_info_file, commit_info_pos) =
+        append_commit_info(&commits_info_path, &compressed_commit_info)?;
+    commits_info_file.sync_all()?;
 
     // Create commit
     let commit = create_commit(
         tag,
         id,
         parent_id,
+        timestamp,
         commit_info_pos,
         compressed_commit_info.len(),
     )?;
-    let commit_bytes = bytemuck::bytes_of(&commit);
-    fs_utils::append_file(&commits_path, &commit_bytes)?;
+    append_commit(&commits_path, &commit)?.sync_all()?;
+
+    if parent.is_none() {
+        write_head(target_path, id)?;
+    }
 
-    write_head(target_path, id)?;
+    // Commit point: once the journal is gone the commit is visible
+    let journal = Arc::try_unwrap(journal)
+        .map_err(|_| "journal is still in use")?
+        .into_inner()?;
+    journal.finish()?;
+
+    // The index is only a cache, it is rebuilt from the commit infos when out of date
+    if let Err(e) = objects.add_commit(&commit, &commit_info) {
+        eprintln!("Failed to update the object index: {e}");
+    }
     Ok(())
 }
 
@@ -88,19 +298,46 @@ pub fn get_head(target_path: &str) -> Result<u32, Box<dyn Error>> {
 }
 
 pub fn write_head(target_path: &str, value: u32) -> Result<(), Box<dyn Error>> {
-    let mut file = fs_utils::open_to_write(&get_head_path(target_path)?, true)?;
     let arr = [value];
     let bytes = cast_slice::<u32, u8>(&arr);
-    file.write_all(bytes)?;
+    fs_utils::write_file_atomic(&get_head_path(target_path)?, bytes)?;
     Ok(())
 }
 
-pub fn print_all_commits(target_path: &str) -> Result<(), Box<dyn Error>> {
+fn get_stash_path(target_path: &str) -> io::Result<String> {
+    fs_utils::build_path([&get_root_path(target_path)?, STASH_FILE_NAME])
+}
+
+// Ids of the hidden commits created to save changes before a restore
+pub fn read_stash_ids(target_path: &str) -> io::Result<Vec<u32>> {
+    let stash_path = get_stash_path(target_path)?;
+    let mut buf = Vec::new();
+    if fs_utils::is_path_exists(&stash_path) {
+        fs_utils::read_to_end(&stash_path, &mut buf)?;
+    }
+    Ok(buf
+        .chunks_exact(4)
+        .map(bytemuck::pod_read_unaligned::<u32>)
+        .collect())
+}
+
+pub fn add_stash_id(target_path: &str, id: u32) -> io::Result<()> {
+    let (file, _) = fs_utils::append_file(&get_stash_path(target_path)?, bytemuck::bytes_of(&id))?;
+    file.sync_all()
+}
+
+pub fn print_all_commits(target_path: &str, show_hidden: bool) -> Result<(), Box<dyn Error>> {
     // Get commits
-    let commits_info_file = fs_utils::read_file(&get_commits_info_path(target_path)?)?;
-    let commits = read_all_commits(&target_path)?;
+    let commits_info_file = read_commits_info_file(target_path)?;
+    let commits = read_all_commits(target_path)?;
+    let stash_ids = read_stash_ids(target_path)?;
+    let root_path = get_root_path(target_path)?;
+
+    for &commit in &commits {
+        if !show_hidden && stash_ids.contains(&commit.id) {
+            continue;
+        }
 
-    for commit in commits {
         let commit_info =
             read_commit_info(&commits_info_file, commit.info_pos, commit.info_length)?;
 
@@ -108,19 +345,60 @@ pub fn print_all_commits(target_path: &str) -> Result<(), Box<dyn Error>> {
             .unwrap_or_else(|| DateTime::from_timestamp(0, 0).unwrap());
 
         println!(
-            "Commit:\t\t{}\nId:\t\t{}\nParent commit:\t{}\nDate:\t\t{}\nFiles:\t\t{}\n--------",
+            "Commit:\t\t{}\nId:\t\t{}\nParent commit:\t{}\nDate:\t\t{}\nFiles:\t\t{}",
             commit.tag_as_str()?,
             commit.id,
             commit.parent_id,
             datetime.format("%Y-%m-%d %H:%M:%S"),
             commit_info.file_info.len(),
         );
+
+        // The initial commit is its own parent
+        if commit.parent_id != commit.id {
+            let parent = commits[commit.parent_id as usize];
+            let parent_info =
+                read_commit_info(&commits_info_file, parent.info_pos, parent.info_length)?;
+            for rename in renames::commit_renames(&root_path, &commit_info, &parent_info)? {
+                println!("Renamed:\t{} -> {}", rename.from, rename.to);
+            }
+        }
+        println!("--------");
+    }
+
+    Ok(())
+}
+
+// Prints the uncommitted changes of the world, renamed files are paired with their old path
+pub fn print_status(target_path: &str) -> Result<(), Box<dyn Error>> {
+    let changes = get_uncommitted_changes(target_path, &[])?;
+    if changes.is_empty() {
+        println!("No uncommitted changes.");
+        return Ok(());
     }
 
+    let renames = renames::uncommitted_renames(target_path, &changes)?;
+    for rename in &renames {
+        match rename.similarity {
+            Some(similarity) => println!("R {} -> {} ({similarity}%)", rename.from, rename.to),
+            None => println!("R {} -> {}", rename.from, rename.to),
+        }
+    }
+
+    let mut changes = changes;
+    changes.retain(|(path, _)| !renames.iter().any(|r| &r.from == path || &r.to == path));
+    changes.sort();
+    for (path, kind) in changes {
+        let kind = match kind {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Deleted => 'D',
+        };
+        println!("{kind} {path}");
+    }
     Ok(())
 }
 
-fn get_commit_count(target_path: &str) -> io::Result<u32> {
+pub fn get_commit_count(target_path: &str) -> io::Result<u32> {
     let commits_path = get_commits_path(target_path)?;
 
     // No commit file
@@ -128,18 +406,37 @@ fn get_commit_count(target_path: &str) -> io::Result<u32> {
         return Ok(0);
     }
 
-    let file_size = fs_utils::get_file_size(commits_path)?;
+    let file_size = fs_utils::get_file_size(&commits_path)?;
     let commit_size = std::mem::size_of::<Commit>() as u64;
+    if file_size > 0 {
+        read_commits_file(target_path)?;
+    }
 
-    Ok((file_size / commit_size) as u32)
+    Ok((file_size.saturating_sub(COMMITS_MAGIC.len() as u64) / commit_size) as u32)
 }
 
-fn get_commit_by_id(target_path: &str, id: u32) -> io::Result<Commit> {
-    let commits_path = get_commits_path(target_path)?;
+pub fn get_commit_info_by_id(target_path: &str, id: u32) -> Result<CommitInfo, Box<dyn Error>> {
+    let commit = get_commit_by_id(target_path, id)?;
+    let commits_info_file = read_commits_info_file(target_path)?;
+    read_commit_info(&commits_info_file, commit.info_pos, commit.info_length)
+}
+
+// Reads the compressed commit info of a commit as it is stored
+pub fn read_raw_commit_info(target_path: &str, commit: &Commit) -> io::Result<Vec<u8>> {
+    let mut file = read_commits_info_file(target_path)?;
+    file.seek(SeekFrom::Start(commit.info_pos))?;
+    let mut buf = vec![0u8; commit.info_length];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn get_commit_by_id(target_path: &str, id: u32) -> io::Result<Commit> {
     let commit_size = std::mem::size_of::<Commit>() as u32;
 
-    let mut file = fs_utils::read_file(&commits_path)?;
-    file.seek(SeekFrom::Start((commit_size * id) as u64))?;
+    let mut file = read_commits_file(target_path)?;
+    file.seek(SeekFrom::Start(
+        COMMITS_MAGIC.len() as u64 + commit_size as u64 * id as u64,
+    ))?;
     let mut buf = vec![0u8; commit_size as usize];
     file.read_exact(&mut buf)?;
     let commit: Commit = *from_bytes(&buf);
@@ -153,17 +450,19 @@ pub fn read_all_commits(target_path: &str) -> io::Result<Vec<Commit>> {
     let mut commits = Vec::new();
 
     // No commit file
-    if !fs_utils::is_path_exists(&commits_path) {
+    if !fs_utils::is_path_exists(&commits_path) || fs_utils::get_file_size(&commits_path)? == 0 {
         return Ok(commits);
     }
 
-    let mut file = fs_utils::read_file(&commits_path)?;
+    let mut file = read_commits_file(target_path)?;
+    file.seek(SeekFrom::Start(0))?;
     let commit_size = std::mem::size_of::<Commit>();
     let mut buf = Vec::new();
 
     file.read_to_end(&mut buf)?;
 
-    for chunk in buf.chunks(commit_size) {
+    let commits_start = COMMITS_MAGIC.len().min(buf.len());
+    for chunk in buf[commits_start..].chunks(commit_size) {
         if chunk.len() == commit_size {
             let commit: Commit = *from_bytes(chunk);
             commits.push(commit);
@@ -173,70 +472,522 @@ pub fn read_all_commits(target_path: &str) -> io::Result<Vec<Commit>> {
     Ok(commits)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+// Compares hashes of the world files against the head commit
+pub fn get_uncommitted_changes(
+    target_path: &str,
+    regions: &[[i32; 3]],
+) -> Result<Vec<(String, ChangeKind)>, Box<dyn Error>> {
+    match get_head(target_path) {
+        Ok(head) if get_commit_count(target_path)? > head => {
+            get_changes_against(target_path, head, regions)
+        }
+        _ => get_changes_from(target_path, BTreeMap::new(), regions),
+    }
+}
+
+// Compares hashes of the world files against the given commit
+pub fn get_changes_against(
+    target_path: &str,
+    commit_id: u32,
+    regions: &[[i32; 3]],
+) -> Result<Vec<(String, ChangeKind)>, Box<dyn Error>> {
+    let file_info = get_commit_info_by_id(target_path, commit_id)?.file_info;
+    get_changes_from(target_path, file_info, regions)
+}
+
+fn get_changes_from(
+    target_path: &str,
+    mut head_info: BTreeMap<String, FileInfo>,
+    regions: &[[i32; 3]],
+) -> Result<Vec<(String, ChangeKind)>, Box<dyn Error>> {
+    let mut changes = Vec::new();
+    for path in get_not_ignored_files_in_directory(target_path)? {
+        if !regions.is_empty() && !path_is_in_regions(&path, regions) {
+            continue;
+        }
+
+        match head_info.remove(&path) {
+            Some(file_info) => {
+                let world_path = fs_utils::build_path([target_path, &path])?;
+                if fs_utils::file_hash(&world_path)? != file_info.hash {
+                    changes.push((path, ChangeKind::Modified));
+                }
+            }
+            None => changes.push((path, ChangeKind::Added)),
+        }
+    }
+
+    for path in head_info.into_keys() {
+        if regions.is_empty() || path_is_in_regions(&path, regions) {
+            changes.push((path, ChangeKind::Deleted));
+        }
+    }
+
+    Ok(changes)
+}
+
 pub fn restore(
     target_path: &str,
     commit_id: u32,
     regions: Vec<[i32; 3]>,
+    discard: bool,
 ) -> Result<(), Box<dyn Error>> {
     // Get commit
     let commit = get_commit_by_id(target_path, commit_id)?;
-    let commit_info_file = fs_utils::read_file(&get_commits_info_path(target_path)?)?;
+
+    // Save uncommitted changes in a hidden commit, so a wrong restore can be undone
+    if !discard {
+        let changes = get_uncommitted_changes(target_path, &regions)?;
+        if !changes.is_empty() {
+            // Saving is only paused on a server that runs, a restore usually happens without one
+            let config = Config::load(target_path)?;
+            let rcon = match &config.rcon {
+                Some(rcon) if session_lock::is_world_in_use(target_path)? => Some(rcon),
+                _ => None,
+            };
+            let stash_id = get_commit_count(target_path)?;
+            commit_world(
+                target_path,
+                &format!("Pre-restore changes before restoring {commit_id}"),
+                regions.clone(),
+                None,
+                rcon,
+            )?;
+            add_stash_id(target_path, stash_id)?;
+            println!(
+                "Saved {} uncommitted change(s) in hidden commit {stash_id}, restore it with `minegit restore {stash_id}`.",
+                changes.len()
+            );
+        }
+    }
+
+    let commit_info_file = read_commits_info_file(target_path)?;
     let mut commit_info = read_commit_info(&commit_info_file, commit.info_pos, commit.info_length)?;
+    let config = Config::load(target_path)?;
 
-    // Delete unnecessary files
-    let root_path = get_root_path(&target_path)?;
-    let mut file_paths = get_not_ignored_files_in_directory(&target_path)?;
-    if regions.len() == 0 {
+    // Collect files that are not part of the commit
+    let root_path = get_root_path(target_path)?;
+    let mut file_paths = get_not_ignored_files_in_directory(target_path)?;
+    let mut removed_paths = Vec::new();
+    if regions.is_empty() {
         for entry in file_paths {
-            if !commit_info
-                .file_info
-                .contains_key(&str_to_fixed_bytes::<128>(&entry))
-            {
-                if fs_utils::is_path_exists(&entry) {
-                    fs_utils::remove_file(&entry)?;
-                }
+            if !commit_info.file_info.contains_key(&entry) {
+                removed_paths.push(entry);
             }
         }
     } else {
         // Clean other files files
         file_paths.retain(|file| {
-            path_is_in_regions(file, &regions)
-                && commit_info
-                    .file_info
-                    .contains_key(&str_to_fixed_bytes::<128>(&file))
+            path_is_in_regions(file, &regions) && commit_info.file_info.contains_key(file)
         });
 
-        commit_info
-            .file_info
-            .retain(|k, _| file_paths.contains(&fixed_bytes_to_str(k)));
+        commit_info.file_info.retain(|k, _| file_paths.contains(k));
 
         println!("Restored files: {:?}", file_paths);
     }
 
-    // Restore files
-    for file_info in commit_info.file_info {
-        let origin_path = fixed_bytes_to_str(&file_info.0);
-        let package_path =
-            fs_utils::build_path([&root_path, "data", &format!("{origin_path}.pkg")])?;
+    // Stage recovered files first, the world is only touched once all of them are verified
+    let transaction = Arc::new(RestoreJournal::begin(target_path)?);
+    let staged = stage_files(&root_path, &transaction, commit_info, &config.core);
+    let transaction = Arc::try_unwrap(transaction).map_err(|_| "restore is still staging")?;
+    if let Err(e) = staged {
+        transaction.abort()?;
+        return Err(e);
+    }
 
-        let mut file = fs_utils::open_to_write(&origin_path, true)?;
-        let mut package_file = fs_utils::open_to_write(&package_path, false)?;
+    transaction.apply(&removed_paths, commit_id)?;
 
-        package_file.seek(io::SeekFrom::Start(file_info.1.package_pos))?;
-        let snapshot = SnapshotHeader::deserialize(&mut package_file)?;
+    Ok(())
+}
 
-        let recovered = snapshot.recover(&mut package_file)?;
-        file.write_all(&recovered)?;
+// Recovers the files of a commit into the staging directory on the worker threads, as many
+// at a time as their estimated memory fits in the budget
+fn stage_files(
+    root_path: &str,
+    transaction: &Arc<RestoreJournal>,
+    commit_info: CommitInfo,
+    core: &CoreConfig,
+) -> Result<(), Box<dyn Error>> {
+    let window = restore_window(core);
+    let memory =
+        (core.memory_budget != 0).then(|| Arc::new(Semaphore::new(core.memory_budget as usize)));
+    let progress = Arc::new(Progress::new(commit_info.file_info.len()));
+    let root = Arc::new(root_path.to_string());
+
+    // Memory taken by every file, known before any of them is staged
+    let mut files = Vec::new();
+    for (origin_path, file_info) in commit_info.file_info {
+        let cost = match &memory {
+            Some(_) => {
+                let len = get_file_len(root_path, &origin_path, file_info.package_pos)?;
+                memory_cost(core, RESTORE_MEMORY_FACTOR, len, window)
+            }
+            None => 0,
+        };
+        files.push((origin_path, file_info, cost));
     }
 
-    write_head(target_path, commit_id)?;
+    let rt = build_runtime(core)?;
+    let staged = rt.block_on(async {
+        let mut handles = vec![];
+        for (origin_path, file_info, cost) in files {
+            let root = Arc::clone(&root);
+            let transaction = Arc::clone(transaction);
+            let progress = Arc::clone(&progress);
+            let memory = memory.clone();
+            handles.push(tokio::spawn(async move {
+                let _permit = match memory {
+                    Some(memory) => Some(
+                        memory
+                            .acquire_many_owned(cost)
+                            .await
+                            .map_err(io::Error::other)?,
+                    ),
+                    None => None,
+                };
+                let pos = file_info.package_pos;
+                transaction.stage(&origin_path, &file_info.hash, |mut out| {
+                    recover_to_at(&root, &origin_path, pos, &mut out, window, 0)
+                })?;
+                progress.advance();
+                io::Result::Ok(())
+            }));
+        }
+
+        // Every task is waited for, so nothing is written once the staged files are discarded
+        let mut staged: Result<(), Box<dyn Error>> = Ok(());
+        for handle in handles {
+            let result = match handle.await {
+                Ok(result) => result.map_err(Into::into),
+                Err(e) => Err(e.into()),
+            };
+            if staged.is_ok() {
+                staged = result;
+            }
+        }
+        staged
+    });
+    progress.finish();
+    staged
+}
+
+// Length of the version stored at `pos` in the package of `path`
+fn get_file_len(root_path: &str, path: &str, pos: u64) -> io::Result<u64> {
+    let mut package = read_package(&get_package_path(root_path, path)?)?;
+    package.seek(io::SeekFrom::Start(pos))?;
+    Ok(SnapshotHeader::deserialize(&mut package)?.file_len)
+}
+
+fn get_package_path(root_path: &str, path: &str) -> io::Result<String> {
+    fs_utils::build_path([root_path, DATA_DIRECTORY_NAME, &format!("{path}.pkg")])
+}
+
+// Recovers the snapshot at `pos` in the package of `path`, following references to the
+// packages holding the content its chain starts from
+pub fn recover_at(root_path: &str, path: &str, pos: u64, depth: u32) -> io::Result<Vec<u8>> {
+    // References point to content stored before them, a deep nesting is a corrupted package
+    if depth > MAX_REFERENCE_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Too many references while recovering {path}"),
+        ));
+    }
+
+    let mut package_file = read_package(&get_package_path(root_path, path)?)?;
+    package_file.seek(io::SeekFrom::Start(pos))?;
+    let snapshot = SnapshotHeader::deserialize(&mut package_file)?;
+    if snapshot.is_reference {
+        let (path, pos) = snapshot.read_reference(&mut package_file)?;
+        return recover_at(root_path, &path, pos, depth + 1);
+    }
+    if snapshot.depend_on == u64::MAX {
+        return snapshot.recover(&mut package_file);
+    }
+
+    snapshot.recover_with(&mut package_file, |reference, package, ops, file| {
+        let (path, pos) = reference.read_reference(package)?;
+        recover_ops_at(root_path, &path, pos, ops, file, depth + 1)
+    })
+}
+
+// Like `recover_at`, carries out `ops` on the version instead of recovering all of it
+fn recover_ops_at(
+    root_path: &str,
+    path: &str,
+    pos: u64,
+    ops: Vec<Instruction>,
+    file: &mut [u8],
+    depth: u32,
+) -> io::Result<()> {
+    if depth > MAX_REFERENCE_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Too many references while recovering {path}"),
+        ));
+    }
+
+    let mut package_file = read_package(&get_package_path(root_path, path)?)?;
+    package_file.seek(io::SeekFrom::Start(pos))?;
+    let snapshot = SnapshotHeader::deserialize(&mut package_file)?;
+    if snapshot.is_reference {
+        let (path, pos) = snapshot.read_reference(&mut package_file)?;
+        return recover_ops_at(root_path, &path, pos, ops, file, depth + 1);
+    }
+
+    snapshot.recover_ops(
+        &mut package_file,
+        ops,
+        file,
+        |reference, package, ops, file| {
+            let (path, pos) = reference.read_reference(package)?;
+            recover_ops_at(root_path, &path, pos, ops, file, depth + 1)
+        },
+    )
+}
+
+// Recovers `buf.len()` bytes at `from` of the version stored at `pos` in the package of `path`
+pub fn recover_range_at(
+    root_path: &str,
+    path: &str,
+    pos: u64,
+    from: u64,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let len = buf.len() as u64;
+    recover_ops_at(
+        root_path,
+        path,
+        pos,
+        vec![Instruction { from, to: 0, len }],
+        buf,
+        0,
+    )
+}
+
+// Like `recover_at`, writes the version to `out` recovering `window` bytes at a time
+pub fn recover_to_at<W: Write>(
+    root_path: &str,
+    path: &str,
+    pos: u64,
+    out: &mut W,
+    window: u64,
+    depth: u32,
+) -> io::Result<()> {
+    if depth > MAX_REFERENCE_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Too many references while recovering {path}"),
+        ));
+    }
+
+    let mut package_file = read_package(&get_package_path(root_path, path)?)?;
+    package_file.seek(io::SeekFrom::Start(pos))?;
+    let snapshot = SnapshotHeader::deserialize(&mut package_file)?;
+    if snapshot.is_reference {
+        let (path, pos) = snapshot.read_reference(&mut package_file)?;
+        return recover_to_at(root_path, &path, pos, out, window, depth + 1);
+    }
+
+    snapshot.recover_to(
+        &mut package_file,
+        out,
+        window,
+        |reference, package, ops, file| {
+            let (path, pos) = reference.read_reference(package)?;
+            recover_ops_at(root_path, &path, pos, ops, file, depth + 1)
+        },
+    )
+}
+
+// How a stored version is recovered
+pub struct Chain {
+    // Deltas applied on top of a full version
+    pub deltas: u32,
+    // References to other packages followed on the way
+    pub references: u32,
+    // Path of the package the chain continues in, for a file stored as a reference or as a
+    // delta of another file
+    pub referenced_path: Option<String>,
+}
+
+impl Chain {
+    // Whether `added` more deltas and references stay within the limits
+    fn can_extend(&self, chain_depth: u32, added: u32) -> bool {
+        (chain_depth == 0 || self.deltas + added < chain_depth)
+            && self.references + added < MAX_REFERENCE_DEPTH
+    }
+}
+
+pub fn get_chain(root_path: &str, path: &str, pos: u64) -> io::Result<Chain> {
+    let mut chain = Chain {
+        deltas: 0,
+        references: 0,
+        referenced_path: None,
+    };
+    let (mut path, mut pos) = (path.to_string(), pos);
+    loop {
+        if chain.references > MAX_REFERENCE_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Too many references while reading {path}"),
+            ));
+        }
+
+        let mut package = read_package(&get_package_path(root_path, &path)?)?;
+        package.seek(io::SeekFrom::Start(pos))?;
+        let mut snapshot = SnapshotHeader::deserialize(&mut package)?;
+        while snapshot.depend_on != u64::MAX {
+            package.seek(io::SeekFrom::Start(snapshot.depend_on))?;
+            snapshot = SnapshotHeader::deserialize(&mut package)?;
+            chain.deltas += 1;
+        }
+        if !snapshot.is_reference {
+            return Ok(chain);
+        }
+
+        (path, pos) = snapshot.read_reference(&mut package)?;
+        chain.references += 1;
+        chain.referenced_path.get_or_insert_with(|| path.clone());
+    }
+}
+
+// Writes every file of the commit into `dest_path`, checking them against the stored hashes
+pub fn write_commit_files(
+    target_path: &str,
+    commit_id: u32,
+    dest_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    for_each_commit_file(
+        target_path,
+        commit_id,
+        |_| true,
+        |file| {
+            let mut out = BufWriter::new(fs_utils::open_to_write(
+                &fs_utils::build_path([dest_path, file.path])?,
+                true,
+            )?);
+            file.write_to(&mut out)?;
+            out.flush()?;
+            Ok(())
+        },
+    )
+}
+
+// A file of a commit, recovered from its package while it is written
+pub struct CommitFile<'a> {
+    pub path: &'a str,
+    pub len: u64,
+    root_path: &'a str,
+    file_info: &'a FileInfo,
+    window: u64,
+}
+
+impl CommitFile<'_> {
+    // Writes the file to `out` a window of the memory budget at a time and checks it against
+    // the hash stored in the commit
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut out = fs_utils::HashingWriter::new(out);
+        recover_to_at(
+            self.root_path,
+            self.path,
+            self.file_info.package_pos,
+            &mut out,
+            self.window,
+            0,
+        )?;
+        if out.finish().0 != self.file_info.hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Recovered {} does not match the hash stored in the commit",
+                    self.path
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Passes the files of a commit accepted by `filter` to `f` in path order, without touching
+// the world
+pub fn for_each_commit_file(
+    target_path: &str,
+    commit_id: u32,
+    filter: impl Fn(&str) -> bool,
+    mut f: impl FnMut(&CommitFile) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let root_path = get_root_path(target_path)?;
+    let commit_info = get_commit_info_by_id(target_path, commit_id)?;
+    let window = restore_window(&Config::load(target_path)?.core);
+
+    // The file infos are sorted by path
+    for (origin_path, file_info) in &commit_info.file_info {
+        if !filter(origin_path) {
+            continue;
+        }
+        f(&CommitFile {
+            path: origin_path,
+            len: get_file_len(&root_path, origin_path, file_info.package_pos)?,
+            root_path: &root_path,
+            file_info,
+            window,
+        })?;
+    }
 
     Ok(())
 }
 
-fn path_is_in_regions(path: &str, regions: &Vec<[i32; 3]>) -> bool {
+// Resolves a revision: a commit id, HEAD, or HEAD~n / <id>~n for the n-th parent
+pub fn resolve_rev(target_path: &str, rev: &str) -> Result<u32, Box<dyn Error>> {
+    let (base, back) = match rev.split_once('~') {
+        Some((base, "")) => (base, 1),
+        Some((base, back)) => (
+            base,
+            back.parse::<u32>()
+                .map_err(|_| format!("Invalid revision {rev}"))?,
+        ),
+        None => (rev, 0),
+    };
+
+    let mut id = if base.eq_ignore_ascii_case("head") {
+        get_head(target_path)?
+    } else {
+        base.parse::<u32>()
+            .map_err(|_| format!("Invalid revision {rev}"))?
+    };
+    if id >= get_commit_count(target_path)? {
+        return Err(format!("Commit {id} does not exist").into());
+    }
+
+    for _ in 0..back {
+        let commit = get_commit_by_id(target_path, id)?;
+        // The initial commit is its own parent
+        if commit.parent_id == commit.id {
+            return Err(format!("Revision {rev} goes past the initial commit").into());
+        }
+        id = commit.parent_id;
+    }
+    Ok(id)
+}
+
+pub fn path_is_in_regions(path: &str, regions: &[[i32; 3]]) -> bool {
+    match parse_region_path(path) {
+        Some(region) => regions.contains(&region),
+        None => false,
+    }
+}
+
+// Returns the dimension,x,z of a region file path, e.g. DIM-1/region/r.0.-1.mca
+pub fn parse_region_path(path: &str) -> Option<[i32; 3]> {
     if !path.ends_with(".mca") || !path.contains("r.") {
-        return false;
+        return None;
     }
 
     // Extract dimension from path
@@ -248,7 +999,7 @@ fn path_is_in_regions(path: &str, regions: &Vec<[i32; 3]>) -> bool {
     {
         0
     } else {
-        return false; // Unknown dimension
+        return None; // Unknown dimension
     };
 
     // Remove ".mca" and split by '.'
@@ -256,18 +1007,45 @@ fn path_is_in_regions(path: &str, regions: &Vec<[i32; 3]>) -> bool {
     let parts: Vec<&str> = trimmed.split('.').collect();
 
     if parts.len() != 3 {
-        return false;
+        return None;
     }
 
-    let x = parts[1].parse::<i32>().ok();
-    let z = parts[2].parse::<i32>().ok();
+    let x = parts[1].parse::<i32>().ok()?;
+    let z = parts[2].parse::<i32>().ok()?;
+    Some([dim, x, z])
+}
 
-    match (x, z) {
-        (Some(x), Some(z)) => regions
-            .iter()
-            .any(|f| f[1] == x && f[2] == z && f[0] == dim),
-        _ => false,
+// Appends a commit, a new commits file starts with the format magic
+pub fn append_commit(path: &str, commit: &Commit) -> io::Result<File> {
+    let (mut file, pos) = fs_utils::append_file(path, &[])?;
+    if pos == 0 {
+        file.write_all(COMMITS_MAGIC)?;
     }
+    file.write_all(bytemuck::bytes_of(commit))?;
+    Ok(file)
+}
+
+// Opens a package to append snapshots, a new package starts with the format magic
+pub fn open_package(path: &str) -> io::Result<File> {
+    let mut package = fs_utils::open_to_write(path, false)?;
+    if package.seek(SeekFrom::End(0))? == 0 {
+        package.write_all(PACKAGE_MAGIC)?;
+    } else {
+        check_magic(&mut package, PACKAGE_MAGIC, path)?;
+        package.seek(SeekFrom::End(0))?;
+    }
+    Ok(package)
+}
+
+// Appends a compressed commit info, a new commit info file starts with the format magic
+pub fn append_commit_info(path: &str, compressed: &[u8]) -> io::Result<(File, u64)> {
+    let (mut file, mut pos) = fs_utils::append_file(path, &[])?;
+    if pos == 0 {
+        file.write_all(COMMITS_INFO_MAGIC)?;
+        pos = COMMITS_INFO_MAGIC.len() as u64;
+    }
+    file.write_all(compressed)?;
+    Ok((file, pos))
 }
 
 pub fn read_commit_info<R: Read + Seek>(
@@ -277,7 +1055,7 @@ pub fn read_commit_info<R: Read + Seek>(
 ) -> Result<CommitInfo, Box<dyn Error>> {
     reader.seek(SeekFrom::Start(pos))?;
 
-    let mut compressed_buffer = vec![0u8; len as usize];
+    let mut compressed_buffer = vec![0u8; len];
     reader.read_exact(&mut compressed_buffer)?;
 
     let uncompressed = decode_all(Cursor::new(compressed_buffer))?;
@@ -286,9 +1064,22 @@ pub fn read_commit_info<R: Read + Seek>(
     Ok(commit_info)
 }
 
+// Whether the world has files the repository does not ignore
+pub fn has_world_files(target_path: &str) -> Result<bool, Box<dyn Error>> {
+    Ok(!get_not_ignored_files_in_directory(target_path)?.is_empty())
+}
+
 fn get_not_ignored_files_in_directory(target_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
-    let root_path = get_root_path(&target_path)?;
-    let entries = fs_utils::get_all_files_in_directory(&target_path)?;
+    get_not_ignored_files_in(target_path, target_path)
+}
+
+// Lists files of `world_path` not ignored by the repository of `target_path`
+fn get_not_ignored_files_in(
+    target_path: &str,
+    world_path: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let root_path = get_root_path(target_path)?;
+    let entries = fs_utils::get_all_files_in_directory(world_path)?;
 
     let mut file_paths = Vec::new();
 
@@ -297,7 +1088,7 @@ fn get_not_ignored_files_in_directory(target_path: &str) -> Result<Vec<String>,
     // Get all files in directory
     for entry in entries {
         let full_path = entry.path();
-        let path = full_path.strip_prefix(target_path)?;
+        let path = full_path.strip_prefix(world_path)?;
 
         if filter.is_ignored(path) {
             continue;
@@ -311,162 +1102,458 @@ fn get_not_ignored_files_in_directory(target_path: &str) -> Result<Vec<String>,
     Ok(file_paths)
 }
 
-fn str_to_fixed_bytes<const N: usize>(s: &str) -> [u8; N] {
-    let as_bytes = s.as_bytes();
-    let mut bytes = [0u8; N];
-    let len = as_bytes.len().min(N);
-    bytes[..len].copy_from_slice(&as_bytes[..len]);
-    bytes
+// Where a file whose content is already stored points to
+enum Reference {
+    // Stored by an earlier commit
+    Stored(ObjectLocation),
+    // Stored by this commit for the file at the given path
+    InCommit(String),
 }
 
-fn fixed_bytes_to_str<const N: usize>(bytes: &[u8; N]) -> String {
-    let end = bytes.iter().position(|&b| b == 0).unwrap_or(N);
-    String::from_utf8_lossy(&bytes[..end]).into_owned()
-}
-
-async fn create_commit_info(
+// Stores the changed files of the world in the packages, returns the file infos of the commit
+async fn create_file_info(
     target_path: &str,
-    id: u32,
+    world_path: &str,
     parent_id: u32,
     regions: Vec<[i32; 3]>,
-) -> Result<CommitInfo, Box<dyn Error>> {
-    let root_path = get_root_path(&target_path)?;
-    let commits_info_file = fs_utils::open_to_write(&get_commits_info_path(target_path)?, false)?;
-
-    let file_paths = get_not_ignored_files_in_directory(&target_path)?;
+    config: &Config,
+    journal: Arc<Mutex<Journal>>,
+    objects: &ObjectIndex,
+) -> Result<BTreeMap<String, FileInfo>, Box<dyn Error>> {
+    let (core, diff) = (&config.core, &config.diff);
+    let root_path = get_root_path(target_path)?;
+    let file_paths = get_not_ignored_files_in(target_path, world_path)?;
 
-    let mut file_infos = HashMap::new();
+    let mut file_infos = BTreeMap::new();
 
     let mut parent_info: Option<CommitInfo> = None;
 
     if get_commit_count(target_path)? > 0 {
-        //let parent_commit = commits[parent_id as usize];
-        let parent_commit = get_commit_by_id(target_path, parent_id)?;
-        parent_info = Some(read_commit_info(
-            &commits_info_file,
-            parent_commit.info_pos,
-            parent_commit.info_length,
-        )?);
+        parent_info = Some(get_commit_info_by_id(target_path, parent_id)?);
     }
 
+    // Hash every file first, so files with the same content are known before storing any
+    let world = Arc::new(world_path.to_string());
     let mut handels = vec![];
+    for origin_path in file_paths {
+        let world = Arc::clone(&world);
+        handels.push(tokio::spawn(async move {
+            let world_path = fs_utils::build_path([world.as_str(), &origin_path])?;
+            let hash = fs_utils::file_hash(&world_path)?;
+            let len = fs_utils::get_file_size(&world_path)?;
+            io::Result::Ok((origin_path, hash, len))
+        }));
+    }
+    let mut hashed = Vec::new();
+    for handle in handels {
+        hashed.push(handle.await??);
+    }
+    // Sorted so the first path with a content is the one storing it
+    hashed.sort();
+
+    // Deleted files of the parent, new files may be renamed ones
+    let world_files: HashSet<&str> = hashed.iter().map(|(path, _, _)| path.as_str()).collect();
+    let mut deleted: BTreeMap<String, ObjectLocation> = BTreeMap::new();
+    for (path, file_info) in parent_info.iter().flat_map(|info| &info.file_info) {
+        if !world_files.contains(path.as_str()) {
+            deleted.insert(
+                path.clone(),
+                ObjectLocation {
+                    path: path.clone(),
+                    pos: file_info.package_pos,
+                },
+            );
+        }
+    }
+    let mut deleted_by_hash: HashMap<[u8; 32], ObjectLocation> = HashMap::new();
+    for (path, file_info) in parent_info.iter().flat_map(|info| &info.file_info) {
+        if let Some(location) = deleted.get(path) {
+            deleted_by_hash
+                .entry(file_info.hash)
+                .or_insert_with(|| location.clone());
+        }
+    }
+
+    let mut planned = Vec::new();
+    let mut new_files = Vec::new();
+    let mut owners: HashMap<[u8; 32], String> = HashMap::new();
+    for (origin_path, hash, len) in hashed {
+        let include_in_commit = regions.is_empty() || path_is_in_regions(&origin_path, &regions);
+
+        let parent_file_info = parent_info
+            .as_ref()
+            .and_then(|info| info.file_info.get(&origin_path));
+
+        if let Some(parent_file_info) = parent_file_info {
+            // Keep the parent version if the file is unchanged or excluded from the commit
+            if hash == parent_file_info.hash || !include_in_commit {
+                file_infos.insert(origin_path, *parent_file_info);
+                continue;
+            }
+        }
+
+        if !include_in_commit {
+            continue;
+        }
+
+        let stored = objects.get(&hash);
+        let reference = match stored {
+            // An earlier version of the same file, e.g. a reverted change
+            Some(location) if location.path == origin_path => {
+                file_infos.insert(
+                    origin_path,
+                    FileInfo {
+                        hash,
+                        package_pos: location.pos,
+                    },
+                );
+                continue;
+            }
+            // A renamed file points to its old path, so the rename shows up in the log. The old
+            // version may itself be a reference, a chain at the limit is not extended and the
+            // file is stored like one with a new content.
+            _ if parent_file_info.is_none() && deleted_by_hash.contains_key(&hash) => {
+                let location = deleted_by_hash.remove(&hash).unwrap();
+                deleted.remove(&location.path);
+                if get_chain(&root_path, &location.path, location.pos)?
+                    .can_extend(core.chain_depth, 1)
+                {
+                    Some(Reference::Stored(location))
+                } else {
+                    match owners.get(&hash) {
+                        Some(owner) => Some(Reference::InCommit(owner.clone())),
+                        None => {
+                            owners.insert(hash, origin_path.clone());
+                            None
+                        }
+                    }
+                }
+            }
+            Some(location) => Some(Reference::Stored(location.clone())),
+            None => match owners.get(&hash) {
+                Some(owner) => Some(Reference::InCommit(owner.clone())),
+                None => {
+                    owners.insert(hash, origin_path.clone());
+                    if parent_file_info.is_none() {
+                        new_files.push(origin_path.clone());
+                    }
+                    None
+                }
+            },
+        };
+        planned.push((origin_path, hash, len, reference));
+    }
+
+    // New files stored as deltas of a deleted file with the same name, if similar enough
+    let deleted_paths: Vec<&str> = deleted.keys().map(String::as_str).collect();
+    let new_paths: Vec<&str> = new_files.iter().map(String::as_str).collect();
+    let mut rename_bases: HashMap<String, ObjectLocation> =
+        renames::pair_by_name(&new_paths, &deleted_paths)
+            .into_iter()
+            .map(|(from, to)| (to.to_string(), deleted[from].clone()))
+            .collect();
 
     struct Res {
-        k: [u8; 128],
+        k: String,
         v: FileInfo,
     }
+    // Reverse rewrites change bytes other tasks may read through references, so they are
+    // only done once those tasks are finished
+    enum Stored {
+        File(Res),
+        Reverse {
+            path: String,
+            hash: [u8; 32],
+            pos: u64,
+            engine: DiffEngine,
+            cost: u32,
+        },
+    }
 
     let root = Arc::new(root_path);
     let p_inf = Arc::new(parent_info);
-    let regions = Arc::new(regions);
+    let (level, chain_depth, layout) = (core.compression, core.chain_depth, core.layout);
 
-    for origin_path in file_paths {
-        let origin_p = Arc::new(origin_path);
+    // Files are saved concurrently as long as the memory they are estimated to take fits in
+    // the budget, larger files are diffed in windows
+    let memory =
+        (core.memory_budget != 0).then(|| Arc::new(Semaphore::new(core.memory_budget as usize)));
+
+    let mut handels = vec![];
+    let mut references = vec![];
+    for (origin_path, hash, len, reference) in planned {
+        if let Some(reference) = reference {
+            references.push((origin_path, hash, len, reference));
+            continue;
+        }
 
-        let regions = Arc::clone(&regions);
         let root = Arc::clone(&root);
+        let world = Arc::clone(&world);
         let p_inf = Arc::clone(&p_inf);
+        let journal = Arc::clone(&journal);
+        let rename_base = rename_bases.remove(&origin_path);
+        let engine = diff.engine_for(&origin_path, len)?;
+        let window = diff_window(core, engine);
+        let is_large = len > window;
+        let cost = memory_cost(core, engine.memory_factor(), len, window);
+        let memory = memory.clone().map(|memory| (memory, cost));
 
         let handle = tokio::spawn(async move {
+            let _permit = match memory {
+                Some((memory, cost)) => Some(
+                    memory
+                        .acquire_many_owned(cost)
+                        .await
+                        .map_err(io::Error::other)?,
+                ),
+                None => None,
+            };
             let root_path = Arc::as_ref(&root);
-            let origin_path = Arc::as_ref(&origin_p);
             let parent_info = Arc::as_ref(&p_inf);
 
-            let path_bytes = str_to_fixed_bytes::<128>(&origin_path);
-            let hash_bytes = str_to_fixed_bytes::<256>(&fs_utils::file_hash(&origin_path).unwrap());
-
-            let include_in_commit = regions.len() == 0 || path_is_in_regions(origin_path, &regions);
-
-            // Check if packageExist
-            let output_path =
-                fs_utils::build_path([&root_path, "data", &&format!("{}.pkg", origin_path)])
-                    .unwrap();
-
-            if fs_utils::is_path_exists(&output_path) {
-                if let Some(ref parent_info) = parent_info {
-                    let parent_file_info = parent_info
-                        .file_info
-                        .get(&path_bytes)
-                        .ok_or("parent_file_info is uninitialized")
-                        .unwrap();
-
-                    // Check if file have changed
-                    if hash_bytes == parent_file_info.hash || !include_in_commit {
-                        return Res {
-                            k: path_bytes,
-                            v: FileInfo {
-                                hash: hash_bytes,
-                                package_pos: parent_file_info.package_pos,
-                            },
-                        };
-                    }
+            let world_path = fs_utils::build_path([world.as_str(), &origin_path])?;
 
-                    // Load parent snapshot
-                    let mut package = fs_utils::open_to_write(&output_path, false).unwrap();
-                    package
-                        .seek(io::SeekFrom::Start(parent_file_info.package_pos))
-                        .unwrap();
-                    let parent_snapshot = SnapshotHeader::deserialize(&mut package).unwrap();
+            let output_path = get_package_path(root_path, &origin_path)?;
 
-                    let mut origin = fs_utils::open_to_write(&origin_path, false).unwrap();
-                    let mut origin_data: Vec<u8> = Vec::new();
-                    origin.read_to_end(&mut origin_data).unwrap();
-                    let new_snap = parent_snapshot.update(&mut package, &origin_data).unwrap();
+            let parent_file_info = parent_info
+                .as_ref()
+                .and_then(|info| info.file_info.get(&origin_path));
 
-                    return Res {
-                        k: path_bytes,
-                        v: FileInfo {
-                            hash: hash_bytes,
-                            package_pos: new_snap.pos - SnapshotHeader::SERIZIZED_SIZE as u64,
-                        },
-                    };
+            // A full version is stored again once the delta chain gets too long
+            let parent = match parent_file_info {
+                Some(parent_file_info) => {
+                    let pos = parent_file_info.package_pos;
+                    Some((pos, get_chain(root_path, &origin_path, pos)?))
+                }
+                None => None,
+            }
+            .filter(|(_, chain)| chain.can_extend(chain_depth, 0));
+
+            let mut origin_data: Vec<u8> = Vec::new();
+            if !is_large {
+                fs_utils::read_to_end(&world_path, &mut origin_data)?;
+            }
+
+            // A new file continues the chain of a deleted file it was renamed from
+            let rename_base = match rename_base {
+                Some(base)
+                    if !is_large
+                        && parent.is_none()
+                        && get_chain(root_path, &base.path, base.pos)?
+                            .can_extend(chain_depth, 1) =>
+                {
+                    let data = recover_at(root_path, &base.path, base.pos, 0)?;
+                    (renames::similarity(&data, &origin_data) >= renames::MIN_SIMILARITY)
+                        .then_some((base, data))
+                }
+                _ => None,
+            };
+
+            journal.lock().unwrap().record_package(&output_path)?;
+            let mut package = open_package(&output_path)?;
+
+            let new_snap = if is_large {
+                // Read from the world and diffed against the parent window by window
+                let mut world_file = BufReader::new(fs_utils::read_file(&world_path)?);
+                match parent {
+                    Some((pos, _)) => {
+                        package.seek(io::SeekFrom::Start(pos))?;
+                        let parent_snapshot = SnapshotHeader::deserialize(&mut package)?;
+                        let read_parent = |from, buf: &mut [u8]| {
+                            recover_range_at(root_path, &origin_path, pos, from, buf)
+                        };
+                        parent_snapshot.update_windowed(
+                            &mut package,
+                            &mut world_file,
+                            window,
+                            read_parent,
+                            engine,
+                            level,
+                        )?
+                    }
+                    None => {
+                        SnapshotHeader::save_new_from(&mut package, &mut world_file, len, level)?
+                    }
+                }
+            } else if let Some((pos, chain)) = parent {
+                package.seek(io::SeekFrom::Start(pos))?;
+                let parent_snapshot = SnapshotHeader::deserialize(&mut package)?;
+                if layout == Layout::Reverse {
+                    // The previous version becomes a delta if it is the full version ending the
+                    // package, otherwise the new version starts a chain of its own
+                    let package_len = package.seek(io::SeekFrom::End(0))?;
+                    let ends_package =
+                        parent_snapshot.pos + parent_snapshot.payload_len == package_len;
+                    if chain.deltas == 0 && chain.references == 0 && ends_package {
+                        return Ok(Stored::Reverse {
+                            path: origin_path,
+                            hash,
+                            pos,
+                            engine,
+                            cost,
+                        });
+                    }
+                    SnapshotHeader::save_new(&mut package, &origin_data, level)?
+                } else if chain.references == 0 {
+                    parent_snapshot.update(&mut package, &origin_data, engine, level)?
                 } else {
-                    panic!("parent_info is uninitialized");
+                    let data = recover_at(root_path, &origin_path, pos, 0)?;
+                    parent_snapshot.update_from(&mut package, data, &origin_data, engine, level)?
                 }
-            } else if include_in_commit {
-                // Create new package file with original file
-                let mut new_package = fs_utils::open_to_write(&output_path, false).unwrap();
-                let mut data = Vec::new();
-                fs_utils::read_to_end(&origin_path, &mut data).unwrap();
-                SnapshotHeader::save_new(&mut new_package, &data).unwrap();
-
-                return Res {
-                    k: path_bytes,
-                    v: FileInfo {
-                        hash: hash_bytes,
-                        package_pos: 0,
-                    },
-                };
+            } else if let Some((base, data)) = rename_base {
+                let reference = SnapshotHeader::save_reference(
+                    &mut package,
+                    &base.path,
+                    base.pos,
+                    data.len() as u64,
+                )?;
+                reference.update_from(&mut package, data, &origin_data, engine, level)?
             } else {
-                return Res {
-                    k: path_bytes,
-                    v: FileInfo {
-                        hash: hash_bytes,
-                        package_pos: MAX,
-                    },
-                };
-            }
+                // Save the whole file, the package may already hold versions of a deleted file
+                SnapshotHeader::save_new(&mut package, &origin_data, level)?
+            };
+            package.sync_all()?;
+
+            io::Result::Ok(Stored::File(Res {
+                k: origin_path,
+                v: FileInfo {
+                    hash,
+                    package_pos: new_snap.pos - SnapshotHeader::SERIZIZED_SIZE as u64,
+                },
+            }))
         });
         handels.push(handle);
     }
 
-    for handle in handels {
-        let res = (handle.await)?;
-        if res.v.package_pos != MAX {
-            file_infos.insert(res.k, res.v);
+    let mut reversed = vec![];
+    let mut data_lock = None;
+    for stored in join_all(handels).await? {
+        match stored {
+            Stored::File(res) => {
+                file_infos.insert(res.k, res.v);
+            }
+            Stored::Reverse {
+                path,
+                hash,
+                pos,
+                engine,
+                cost,
+            } => {
+                // Readers of the packages wait until the rewrites are done
+                if data_lock.is_none() {
+                    data_lock = Some(DataLock::exclusive(target_path)?);
+                }
+                let root = Arc::clone(&root);
+                let world = Arc::clone(&world);
+                let journal = Arc::clone(&journal);
+                let memory = memory.clone();
+                reversed.push(tokio::spawn(async move {
+                    let _permit = match memory {
+                        Some(memory) => Some(
+                            memory
+                                .acquire_many_owned(cost)
+                                .await
+                                .map_err(io::Error::other)?,
+                        ),
+                        None => None,
+                    };
+                    let world_path = fs_utils::build_path([world.as_str(), &path])?;
+                    let snapshot =
+                        store_reverse(&root, &world_path, &path, pos, &journal, engine, level)?;
+                    io::Result::Ok(Res {
+                        k: path,
+                        v: FileInfo {
+                            hash,
+                            package_pos: snapshot.pos - SnapshotHeader::SERIZIZED_SIZE as u64,
+                        },
+                    })
+                }));
+            }
         }
     }
+    // A rewrite only reads the package it rewrites, so they run alongside each other
+    for res in join_all(reversed).await? {
+        file_infos.insert(res.k, res.v);
+    }
+    drop(data_lock);
+
+    // Written once the versions they point to are stored
+    for (origin_path, hash, len, reference) in references {
+        let location = match reference {
+            Reference::Stored(location) => location,
+            Reference::InCommit(owner) => ObjectLocation {
+                pos: file_infos[&owner].package_pos,
+                path: owner,
+            },
+        };
+
+        let output_path = get_package_path(&root, &origin_path)?;
+        journal.lock().unwrap().record_package(&output_path)?;
+        let mut package = open_package(&output_path)?;
+        let snapshot =
+            SnapshotHeader::save_reference(&mut package, &location.path, location.pos, len)?;
+        package.sync_all()?;
+
+        file_infos.insert(
+            origin_path,
+            FileInfo {
+                hash,
+                package_pos: snapshot.pos - SnapshotHeader::SERIZIZED_SIZE as u64,
+            },
+        );
+    }
 
-    Ok(CommitInfo {
-        id,
-        file_info: file_infos,
-    })
+    Ok(file_infos)
+}
+
+// Waits for every task, so none of them still writes once an error is returned
+async fn join_all<T>(
+    handles: Vec<tokio::task::JoinHandle<io::Result<T>>>,
+) -> Result<Vec<T>, Box<dyn Error>> {
+    let mut results = Ok(Vec::with_capacity(handles.len()));
+    for handle in handles {
+        match (handle.await, &mut results) {
+            (Ok(Ok(value)), Ok(values)) => values.push(value),
+            (Ok(Err(e)), Ok(_)) => results = Err(e.into()),
+            (Err(e), Ok(_)) => results = Err(e.into()),
+            _ => {}
+        }
+    }
+    results
+}
+
+// Stores the world file as the new full version ending its package, the full version at
+// `pos` before it becomes a reverse delta of it
+fn store_reverse(
+    root_path: &str,
+    world_path: &str,
+    path: &str,
+    pos: u64,
+    journal: &Mutex<Journal>,
+    engine: DiffEngine,
+    level: i32,
+) -> io::Result<SnapshotHeader> {
+    let mut data = Vec::new();
+    fs_utils::read_to_end(world_path, &mut data)?;
+
+    let package_path = get_package_path(root_path, path)?;
+    journal.lock().unwrap().record_rewrite(&package_path, pos)?;
+    let mut package = open_package(&package_path)?;
+    package.seek(io::SeekFrom::Start(pos))?;
+    let parent_snapshot = SnapshotHeader::deserialize(&mut package)?;
+    let snapshot = match parent_snapshot.update_reverse(&mut package, &data, engine, level)? {
+        Some(snapshot) => snapshot,
+        None => SnapshotHeader::save_new(&mut package, &data, level)?,
+    };
+    package.sync_all()?;
+    Ok(snapshot)
 }
 
 fn create_commit(
     tag: &str,
     id: u32,
     parent_id: u32,
+    timestamp: i64,
     info_pos: u64,
     info_length: usize,
 ) -> Result<Commit, Box<dyn Error>> {
@@ -478,11 +1565,121 @@ fn create_commit(
     tag_bytes[..len].copy_from_slice(&truncated[..len]);
 
     Ok(Commit {
-        id: id,
-        timestamp: Local::now().timestamp(),
+        id,
+        timestamp,
         tag: tag_bytes,
         parent_id,
         info_pos,
         info_length,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::initializer;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+
+    // Region files of the world at a version, each larger than the restore window
+    fn versioned_files(version: u8) -> Vec<(String, Vec<u8>)> {
+        (0..4)
+            .map(|i| {
+                let mut data: Vec<u8> = (0..400_000u32).map(|j| (j * 7 % 251) as u8).collect();
+                for change in 0..version as usize {
+                    data[change * 100_000 + i * 1000..][..5000].fill(version + i as u8);
+                }
+                (format!("region/r.{i}.0.mca"), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restores_files_larger_than_a_tight_memory_budget() {
+        let world = temp_dir("restore-budget");
+        for (path, data) in versioned_files(0) {
+            write_world_file(&world, &path, &data);
+        }
+        initializer::init(&world).unwrap();
+        config::set_value(&world, "core.memory_budget", "1").unwrap();
+        config::set_value(&world, "core.threads", "4").unwrap();
+        for version in 1..3 {
+            for (path, data) in versioned_files(version) {
+                write_world_file(&world, &path, &data);
+            }
+            add_commit(&world, &format!("version {version}"), Vec::new()).unwrap();
+        }
+
+        // A file costs at most the whole budget, so none of them waits forever
+        let core = Config::load(&world).unwrap().core;
+        assert_eq!(
+            memory_cost(&core, RESTORE_MEMORY_FACTOR, 400_000, u64::MAX),
+            1
+        );
+
+        for id in [1, 0, 2] {
+            restore(&world, id, Vec::new(), true).unwrap();
+            for (path, data) in versioned_files(id as u8) {
+                assert_eq!(read_world_file(&world, &path), data, "{path} at {id}");
+            }
+            assert_eq!(get_head(&world).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn broken_packages_fail_the_commit_and_restore_without_panicking() {
+        let world = temp_dir("broken-package");
+        write_world_file(&world, "region/r.0.0.mca", &[1; 5000]);
+        write_world_file(&world, "level.dat", b"level");
+        initializer::init(&world).unwrap();
+
+        // Only the magic is left of the package
+        let root_path = get_root_path(&world).unwrap();
+        let package_path = get_package_path(&root_path, "region/r.0.0.mca").unwrap();
+        fs_utils::truncate_file(&package_path, PACKAGE_MAGIC.len() as u64).unwrap();
+
+        write_world_file(&world, "region/r.0.0.mca", &[2; 5000]);
+        write_world_file(&world, "level.dat", b"changed");
+        assert!(add_commit(&world, "broken", Vec::new()).is_err());
+        assert!(crate::journal::recover(&world).unwrap());
+        assert_eq!(get_commit_count(&world).unwrap(), 1);
+
+        assert!(restore(&world, 0, Vec::new(), true).is_err());
+        assert_eq!(read_world_file(&world, "level.dat"), b"changed");
+    }
+
+    #[test]
+    fn reverse_rewrites_keep_references_into_the_package_readable() {
+        let world = temp_dir("reverse-references");
+        let mut data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        write_world_file(&world, "region/r.0.0.mca", &data);
+        initializer::init(&world).unwrap();
+        config::set_value(&world, "core.layout", "reverse").unwrap();
+
+        // The copy is a reference into the package of the original
+        write_world_file(&world, "region/r.1.0.mca", &data);
+        add_commit(&world, "copied", Vec::new()).unwrap();
+
+        // Both change, the original is rewritten while the copy is diffed against it
+        let mut versions = vec![data.clone()];
+        for version in 1..4u8 {
+            data[version as usize * 1000..][..500].fill(version);
+            let mut copy = data.clone();
+            copy[100_000..100_100].fill(version);
+            write_world_file(&world, "region/r.0.0.mca", &data);
+            write_world_file(&world, "region/r.1.0.mca", &copy);
+            add_commit(&world, &format!("version {version}"), Vec::new()).unwrap();
+            versions.push(data.clone());
+        }
+
+        for id in (0..5).rev() {
+            restore(&world, id, Vec::new(), true).unwrap();
+            let expected = &versions[id.saturating_sub(1) as usize];
+            assert_eq!(
+                &read_world_file(&world, "region/r.0.0.mca"),
+                expected,
+                "at {id}"
+            );
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use clap::ValueEnum;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+
+use crate::recover::diff_gen::DiffEngine;
+use crate::savefiles::{CONFIG_FILE_NAME, DIRECTORY_NAME};
+use crate::utils::fs_utils;
+
+// Repository configuration stored in `.minegit/config` as TOML
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub core: CoreConfig,
+    pub diff: DiffConfig,
+    pub user: UserConfig,
+    pub hooks: HooksConfig,
+    pub rcon: Option<RconConfig>,
+    pub retention: RetentionConfig,
+    #[serde(rename = "remote", skip_serializing_if = "BTreeMap::is_empty")]
+    pub remotes: BTreeMap<String, RemoteConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoreConfig {
+    // zstd level of stored file versions, 0 disables compression
+    pub compression: i32,
+    // zstd level of commit infos, 0 selects the zstd default
+    pub info_compression: i32,
+    // Maximal number of deltas before a full version is stored again, 0 is unlimited
+    pub chain_depth: u32,
+    // Number of worker threads used to save and restore files, 0 uses all CPU cores
+    pub threads: usize,
+    // Memory in MiB used to save and restore files, larger files are diffed and restored
+    // in windows to stay within it. 0 is unlimited.
+    pub memory_budget: u64,
+    // Direction of the deltas stored on commit
+    pub layout: Layout,
+}
+
+// `forward` keeps the first version of a file in full and stores later ones as deltas,
+// `reverse` stores the newest version in full and turns the previous one into a delta
+// against it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Forward,
+    Reverse,
+}
+
+impl Default for CoreConfig {
+    fn default() -> Self {
+        Self {
+            compression: 0,
+            info_compression: 0,
+            chain_depth: 64,
+            threads: 0,
+            memory_budget: 0,
+            layout: Layout::Forward,
+        }
+    }
+}
+
+// Delta engine of a file, `auto` takes the rolling hash engine for large files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffEngineChoice {
+    #[default]
+    Auto,
+    Suffix,
+    Rolling,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiffConfig {
+    pub engine: DiffEngineChoice,
+    // Size in MiB from which `auto` takes the rolling hash engine
+    pub rolling_threshold: u64,
+    // Engines of files matching glob patterns, the first matching pattern wins over `engine`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub patterns: Vec<DiffPattern>,
+}
+
+impl Default for DiffConfig {
+    fn default() -> Self {
+        Self {
+            engine: DiffEngineChoice::Auto,
+            rolling_threshold: 8,
+            patterns: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiffPattern {
+    pub pattern: String,
+    pub engine: DiffEngineChoice,
+}
+
+impl DiffConfig {
+    // Engine of the file at `path` with `len` bytes
+    pub fn engine_for(&self, path: &str, len: u64) -> Result<DiffEngine, Box<dyn Error>> {
+        let mut choice = self.engine;
+        for rule in &self.patterns {
+            if Pattern::new(&rule.pattern)?.matches_path(Path::new(path)) {
+                choice = rule.engine;
+                break;
+            }
+        }
+
+        Ok(match choice {
+            DiffEngineChoice::Suffix => DiffEngine::SuffixArray,
+            DiffEngineChoice::Rolling => DiffEngine::RollingHash,
+            DiffEngineChoice::Auto if len >= self.rolling_threshold.saturating_mul(1024 * 1024) => {
+                DiffEngine::RollingHash
+            }
+            DiffEngineChoice::Auto => DiffEngine::SuffixArray,
+        })
+    }
+}
+
+// Identity passed to hooks, commits do not record an author
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub enabled: bool,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+// Connection to the server console used to pause saving while committing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RconConfig {
+    #[serde(default = "default_rcon_host")]
+    pub host: String,
+    #[serde(default = "default_rcon_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub password: String,
+}
+
+fn default_rcon_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_rcon_port() -> u16 {
+    25575
+}
+
+// Commits kept by `prune`, a commit is kept if any rule keeps it. 0 disables a rule.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub keep_last: u32,
+    pub keep_days: u32,
+}
+
+// Another repository commits are pushed to or fetched from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteConfig {
+    pub url: String,
+}
+
+fn get_config_path(target_path: &str) -> Result<String, Box<dyn Error>> {
+    Ok(fs_utils::build_path([
+        target_path,
+        DIRECTORY_NAME,
+        CONFIG_FILE_NAME,
+    ])?)
+}
+
+fn read_table(target_path: &str) -> Result<toml::Table, Box<dyn Error>> {
+    let path = get_config_path(target_path)?;
+    if !fs_utils::is_path_exists(&path) {
+        return Ok(toml::Table::new());
+    }
+
+    let mut content = Vec::new();
+    fs_utils::read_to_end(&path, &mut content)?;
+    Ok(std::str::from_utf8(&content)?.parse()?)
+}
+
+impl Config {
+    // Loads the configuration, a missing file gives the default one
+    pub fn load(target_path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(read_table(target_path)?.try_into()?)
+    }
+
+    pub fn save(&self, target_path: &str) -> Result<(), Box<dyn Error>> {
+        let content = toml::to_string(self)?;
+        fs_utils::write_file_atomic(&get_config_path(target_path)?, content.as_bytes())?;
+        Ok(())
+    }
+}
+
+// Returns the value of a dotted key like `core.compression`, defaults included
+pub fn get_value(target_path: &str, key: &str) -> Result<String, Box<dyn Error>> {
+    let mut value = toml::Value::try_from(Config::load(target_path)?)?;
+    for part in key.split('.') {
+        value = value
+            .get(part)
+            .cloned()
+            .ok_or_else(|| format!("Unknown config key {key}"))?;
+    }
+
+    Ok(match value {
+        toml::Value::String(s) => s,
+        other => other.to_string(),
+    })
+}
+
+// Sets a dotted key, the value is parsed as a TOML value or taken as a string
+pub fn set_value(target_path: &str, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    let parsed = match format!("v = {value}").parse::<toml::Table>() {
+        Ok(mut table) => table.remove("v").unwrap(),
+        Err(_) => toml::Value::String(value.to_string()),
+    };
+
+    let mut table = read_table(target_path)?;
+    let (sections, name) = match key.rsplit_once('.') {
+        Some((sections, name)) => (sections.split('.').collect::<Vec<_>>(), name),
+        None => (Vec::new(), key),
+    };
+
+    let mut current = &mut table;
+    for section in sections {
+        current = current
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("{section} is not a section"))?;
+    }
+    current.insert(name.to_string(), parsed);
+
+    // Reject unknown keys and values of a wrong type before saving
+    let _: Config = table.clone().try_into()?;
+
+    let content = toml::to_string(&table)?;
+    fs_utils::write_file_atomic(&get_config_path(target_path)?, content.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,290 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::thread;
+
+use chrono::{DateTime, Datelike, Timelike};
+use glob::Pattern;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::committer;
+use crate::utils::fs_utils;
+
+// zstd level of exported tar archives
+const TAR_COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    // Picks the format from the extension of the output file
+    pub fn from_path(path: &str) -> Result<Self, Box<dyn Error>> {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".zip") {
+            Ok(Self::Zip)
+        } else if lower.ends_with(".tar.zst") || lower.ends_with(".tzst") {
+            Ok(Self::TarZst)
+        } else {
+            Err(format!("Unknown archive format of {path}, use .zip or .tar.zst").into())
+        }
+    }
+}
+
+// Writes every file of a commit into an archive, straight from the packages.
+// Files get the commit time as their modification time.
+pub fn export(target_path: &str, commit_id: u32, output_path: &str) -> Result<(), Box<dyn Error>> {
+    let format = ArchiveFormat::from_path(output_path)?;
+    let timestamp = committer::get_commit_by_id(target_path, commit_id)?.timestamp;
+
+    // Write next to the output first, so a failed export does not leave a broken archive
+    let temp_path = format!("{output_path}.tmp");
+    let file = BufWriter::new(File::create(&temp_path)?);
+    let result = match format {
+        ArchiveFormat::Zip => export_zip(target_path, commit_id, timestamp, file),
+        ArchiveFormat::TarZst => export_tar_zst(target_path, commit_id, timestamp, file),
+    };
+    if let Err(e) = result {
+        let _ = fs_utils::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    std::fs::rename(&temp_path, output_path)?;
+    Ok(())
+}
+
+// Writes the files of a commit into another directory, e.g. to start a test server on an
+// old version while the live world keeps running. With regions or paths given only the
+// matching files are written. Paths are path prefixes or glob patterns like `DIM-1/*`.
+pub fn checkout(
+    target_path: &str,
+    commit_id: u32,
+    dest_path: &str,
+    regions: &[[i32; 3]],
+    paths: &[String],
+    force: bool,
+) -> Result<usize, Box<dyn Error>> {
+    let target = std::fs::canonicalize(target_path)?;
+    if let Ok(dest) = std::fs::canonicalize(dest_path) {
+        if dest == target {
+            return Err("Use restore to change the world itself".into());
+        }
+        if !force && std::fs::read_dir(&dest)?.next().is_some() {
+            return Err(
+                format!("{dest_path} is not empty, use --force to overwrite files in it").into(),
+            );
+        }
+    }
+
+    let patterns = paths
+        .iter()
+        .map(|p| Pattern::new(p.trim_end_matches('/')))
+        .collect::<Result<Vec<_>, _>>()?;
+    let selected = |path: &str| {
+        (regions.is_empty() && paths.is_empty())
+            || committer::path_is_in_regions(path, regions)
+            || patterns.iter().any(|pattern| {
+                let prefix = pattern.as_str();
+                pattern.matches(path)
+                    || path
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    };
+
+    let mut count = 0;
+    committer::for_each_commit_file(target_path, commit_id, selected, |file| {
+        let mut out = BufWriter::new(fs_utils::open_to_write(
+            &fs_utils::build_path([dest_path, file.path])?,
+            true,
+        )?);
+        file.write_to(&mut out)?;
+        out.flush()?;
+        count += 1;
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+fn export_zip<W: Write + std::io::Seek>(
+    target_path: &str,
+    commit_id: u32,
+    timestamp: i64,
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut zip = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(zip_time(timestamp));
+
+    committer::for_each_commit_file(
+        target_path,
+        commit_id,
+        |_| true,
+        |file| {
+            zip.start_file(file.path, options.large_file(file.len >= u32::MAX as u64))?;
+            file.write_to(&mut zip)?;
+            Ok(())
+        },
+    )?;
+
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn export_tar_zst<W: Write>(
+    target_path: &str,
+    commit_id: u32,
+    timestamp: i64,
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let encoder = zstd::Encoder::new(writer, TAR_COMPRESSION_LEVEL)?;
+    let mut tar = tar::Builder::new(encoder);
+
+    committer::for_each_commit_file(
+        target_path,
+        commit_id,
+        |_| true,
+        |file| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(file.len);
+            header.set_mode(0o644);
+            header.set_mtime(timestamp.max(0) as u64);
+
+            // The archive can not seek back to the header, so the entry is read from a pipe
+            // the file is recovered into
+            let (reader, mut writer) = io::pipe()?;
+            thread::scope(|scope| {
+                let recovering = scope.spawn(move || file.write_to(&mut writer));
+                let appended = tar.append_data(&mut header, file.path, reader);
+                recovering.join().unwrap()?;
+                appended
+            })?;
+            Ok(())
+        },
+    )?;
+
+    tar.into_inner()?.finish()?.flush()?;
+    Ok(())
+}
+
+// Zip stores local time without a zone, dates before 1980 are not representable
+fn zip_time(timestamp: i64) -> zip::DateTime {
+    let Some(time) = DateTime::from_timestamp(timestamp, 0) else {
+        return zip::DateTime::default();
+    };
+    let time = time.with_timezone(&chrono::Local);
+    zip::DateTime::from_date_and_time(
+        time.year().clamp(1980, 2107) as u16,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initializer;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+    use std::collections::BTreeMap;
+    use std::io::Read;
+
+    const LONG_PATH: &str =
+        "data/a_path_that_does_not_fit_the_hundred_bytes_of_a_plain_tar_header/\
+                             with_a_long_file_name_too.dat";
+
+    // World with the files of commit 0 returned, commit 1 changes the region file
+    fn exported_world(name: &str) -> (String, BTreeMap<String, Vec<u8>>) {
+        let world = temp_dir(name);
+        let region: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let files = BTreeMap::from([
+            ("region/r.0.0.mca".to_string(), region.clone()),
+            ("level.dat".to_string(), b"level".to_vec()),
+            (LONG_PATH.to_string(), vec![5; 300]),
+        ]);
+        for (path, data) in &files {
+            write_world_file(&world, path, data);
+        }
+        initializer::init(&world).unwrap();
+
+        let mut changed = region;
+        changed[100..200].fill(1);
+        write_world_file(&world, "region/r.0.0.mca", &changed);
+        committer::add_commit(&world, "changed", Vec::new()).unwrap();
+        (world, files)
+    }
+
+    #[test]
+    fn exported_archives_hold_the_files_of_the_commit() {
+        let (world, files) = exported_world("export");
+        let output = temp_dir("export-output");
+
+        let zip_path = format!("{output}/world.zip");
+        export(&world, 0, &zip_path).unwrap();
+        let mut archive = zip::ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        let mut exported = BTreeMap::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            exported.insert(entry.name().unwrap().to_string(), data);
+        }
+        assert_eq!(exported, files);
+
+        let tar_path = format!("{output}/world.tar.zst");
+        export(&world, 0, &tar_path).unwrap();
+        let decoder = zstd::Decoder::new(File::open(&tar_path).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let mut exported = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_string();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            exported.insert(path, data);
+        }
+        assert_eq!(exported, files);
+
+        assert!(export(&world, 0, &format!("{output}/world.rar")).is_err());
+        assert!(!fs_utils::is_path_exists(&format!(
+            "{output}/world.rar.tmp"
+        )));
+    }
+
+    #[test]
+    fn checkout_writes_the_selected_files_of_the_commit() {
+        let (world, files) = exported_world("checkout");
+        let dest = temp_dir("checkout-dest");
+
+        assert_eq!(checkout(&world, 0, &dest, &[], &[], false).unwrap(), 3);
+        for (path, data) in &files {
+            assert_eq!(&read_world_file(&dest, path), data);
+        }
+
+        // Only an empty directory is written to without --force
+        assert!(checkout(&world, 1, &dest, &[], &[], false).is_err());
+        checkout(&world, 1, &dest, &[], &["region/".to_string()], true).unwrap();
+        assert_eq!(
+            read_world_file(&dest, "region/r.0.0.mca"),
+            read_world_file(&world, "region/r.0.0.mca")
+        );
+
+        let selected = temp_dir("checkout-selected");
+        let count = checkout(&world, 0, &selected, &[], &["*.dat".to_string()], false).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(read_world_file(&selected, "level.dat"), b"level");
+        assert_eq!(read_world_file(&selected, LONG_PATH), files[LONG_PATH]);
+        assert!(!fs_utils::is_path_exists(
+            &fs_utils::build_path([&selected, "region"]).unwrap()
+        ));
+
+        assert!(checkout(&world, 0, &world, &[], &[], true).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::committer::{self, ChangeKind};
+use crate::config::Config;
+use crate::savefiles::{DIRECTORY_NAME, HOOKS_DIRECTORY_NAME};
+use crate::utils::fs_utils;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreCommit,
+    PostCommit,
+    PreRestore,
+    PostRestore,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreCommit => "pre-commit",
+            Hook::PostCommit => "post-commit",
+            Hook::PreRestore => "pre-restore",
+            Hook::PostRestore => "post-restore",
+        }
+    }
+
+    fn is_pre(self) -> bool {
+        matches!(self, Hook::PreCommit | Hook::PreRestore)
+    }
+}
+
+// Runs `commit` between the pre-commit and post-commit hooks.
+// The changed paths are those of the world compared to the head commit.
+pub fn with_commit_hooks(
+    target_path: &str,
+    tag: &str,
+    regions: &[[i32; 3]],
+    commit: impl FnOnce() -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    if !has_any_hook(target_path, [Hook::PreCommit, Hook::PostCommit])? {
+        return commit();
+    }
+
+    let id = committer::get_commit_count(target_path)?;
+    let changes = committer::get_uncommitted_changes(target_path, regions)?;
+
+    run(target_path, Hook::PreCommit, id, tag, &changes)?;
+    commit()?;
+    run(target_path, Hook::PostCommit, id, tag, &changes)
+}
+
+// Runs `restore` between the pre-restore and post-restore hooks.
+// The changed paths are what the restore does to the world, e.g. files missing in the
+// restored commit are reported as deleted.
+pub fn with_restore_hooks(
+    target_path: &str,
+    commit_id: u32,
+    regions: &[[i32; 3]],
+    restore: impl FnOnce() -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    if !has_any_hook(target_path, [Hook::PreRestore, Hook::PostRestore])? {
+        return restore();
+    }
+
+    let commit = committer::get_commit_by_id(target_path, commit_id)?;
+    let tag = commit.tag_as_str()?.to_string();
+    let changes: Vec<(String, ChangeKind)> =
+        committer::get_changes_against(target_path, commit_id, regions)?
+            .into_iter()
+            .map(|(path, kind)| {
+                let kind = match kind {
+                    ChangeKind::Added => ChangeKind::Deleted,
+                    ChangeKind::Deleted => ChangeKind::Added,
+                    ChangeKind::Modified => ChangeKind::Modified,
+                };
+                (path, kind)
+            })
+            .collect();
+
+    run(target_path, Hook::PreRestore, commit_id, &tag, &changes)?;
+    restore()?;
+    run(target_path, Hook::PostRestore, commit_id, &tag, &changes)
+}
+
+// Runs a hook from `.minegit/hooks` if it exists and hooks are enabled.
+//
+// The hook is started in the world directory and gets the commit in MINEGIT_COMMIT_ID and
+// MINEGIT_MESSAGE, and one `A|M|D <path>` line per changed path on stdin.
+// A failing pre hook aborts the operation, a failing post hook only prints a warning.
+fn run(
+    target_path: &str,
+    hook: Hook,
+    commit_id: u32,
+    message: &str,
+    changes: &[(String, ChangeKind)],
+) -> Result<(), Box<dyn Error>> {
+    let Some(path) = find_hook(target_path, hook)? else {
+        return Ok(());
+    };
+
+    // Like git, hooks without the executable bit are skipped
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if std::fs::metadata(&path)?.permissions().mode() & 0o111 == 0 {
+            eprintln!(
+                "Warning: the {} hook is not executable and was skipped.",
+                hook.name()
+            );
+            return Ok(());
+        }
+    }
+
+    let config = Config::load(target_path)?;
+
+    let mut child = Command::new(&path)
+        .current_dir(target_path)
+        .env("MINEGIT_HOOK", hook.name())
+        .env("MINEGIT_COMMIT_ID", commit_id.to_string())
+        .env("MINEGIT_MESSAGE", message)
+        .env("MINEGIT_CHANGED_COUNT", changes.len().to_string())
+        .env("MINEGIT_USER_NAME", &config.user.name)
+        .env("MINEGIT_USER_EMAIL", &config.user.email)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run the {} hook: {e}", hook.name()))?;
+
+    let mut input = String::new();
+    for (path, kind) in changes {
+        let kind = match kind {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Deleted => 'D',
+        };
+        input.push_str(&format!("{kind} {path}\n"));
+    }
+    // The hook does not have to read its input
+    if let Some(mut stdin) = child.stdin.take() {
+        match stdin.write_all(input.as_bytes()) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    let status = child.wait()?;
+    if status.success() {
+        return Ok(());
+    }
+    if hook.is_pre() {
+        return Err(format!("The {} hook failed ({status}), aborting.", hook.name()).into());
+    }
+    eprintln!("Warning: the {} hook failed ({status}).", hook.name());
+    Ok(())
+}
+
+fn has_any_hook<const N: usize>(
+    target_path: &str,
+    hooks: [Hook; N],
+) -> Result<bool, Box<dyn Error>> {
+    for hook in hooks {
+        if find_hook(target_path, hook)?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn find_hook(target_path: &str, hook: Hook) -> Result<Option<String>, Box<dyn Error>> {
+    let path = fs_utils::build_path([
+        target_path,
+        DIRECTORY_NAME,
+        HOOKS_DIRECTORY_NAME,
+        hook.name(),
+    ])?;
+    if !Path::new(&path).is_file() || !Config::load(target_path)?.hooks.enabled {
+        return Ok(None);
+    }
+    Ok(Some(path))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::initializer;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write_hook(world: &str, hook: Hook, script: &str) {
+        let path = format!("{DIRECTORY_NAME}/{HOOKS_DIRECTORY_NAME}/{}", hook.name());
+        write_world_file(world, &path, script.as_bytes());
+        let path = fs_utils::build_path([world, &path]).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    fn hooked_world(name: &str) -> String {
+        let world = temp_dir(name);
+        write_world_file(&world, "level.dat", b"level");
+        initializer::init(&world).unwrap();
+        config::set_value(&world, "hooks.enabled", "true").unwrap();
+        world
+    }
+
+    #[test]
+    fn failing_pre_commit_hook_aborts_the_commit() {
+        let world = hooked_world("hooks-abort");
+        write_hook(&world, Hook::PreCommit, "#!/bin/sh\nexit 3\n");
+        write_world_file(&world, "level.dat", b"changed");
+
+        let mut committed = false;
+        let result = with_commit_hooks(&world, "blocked", &[], || {
+            committed = true;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert!(!committed);
+
+        // A failing post hook only warns
+        write_hook(&world, Hook::PreCommit, "#!/bin/sh\nexit 0\n");
+        write_hook(&world, Hook::PostCommit, "#!/bin/sh\nexit 3\n");
+        with_commit_hooks(&world, "allowed", &[], || {
+            committed = true;
+            Ok(())
+        })
+        .unwrap();
+        assert!(committed);
+    }
+
+    #[test]
+    fn hooks_get_the_commit_and_changed_paths() {
+        let world = hooked_world("hooks-input");
+        let output = temp_dir("hooks-input-output");
+        write_hook(
+            &world,
+            Hook::PreCommit,
+            &format!(
+                "#!/bin/sh\necho \"$MINEGIT_HOOK $MINEGIT_COMMIT_ID $MINEGIT_MESSAGE \
+                 $MINEGIT_CHANGED_COUNT\" > {output}/input\ncat >> {output}/input\n"
+            ),
+        );
+        write_world_file(&world, "level.dat", b"changed");
+        write_world_file(&world, "new.dat", b"new");
+
+        with_commit_hooks(&world, "tagged", &[], || Ok(())).unwrap();
+        let input = String::from_utf8(read_world_file(&output, "input")).unwrap();
+        let mut lines: Vec<&str> = input.lines().collect();
+        lines[1..].sort();
+        assert_eq!(lines, ["pre-commit 1 tagged 2", "A new.dat", "M level.dat"]);
+
+        // Disabled hooks are not run
+        config::set_value(&world, "hooks.enabled", "false").unwrap();
+        write_hook(&world, Hook::PreCommit, "#!/bin/sh\nexit 3\n");
+        with_commit_hooks(&world, "unhooked", &[], || Ok(())).unwrap();
+    }
+}
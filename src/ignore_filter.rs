@@ -2,7 +2,7 @@ use std::{io::Read, path::Path};
 
 use glob::Pattern;
 
-use crate::savefiles::IGNORE_FILE_NAME;
+use crate::savefiles::{IGNORE_FILE_NAME, SESSION_LOCK_FILE_NAME};
 use crate::utils::fs_utils;
 
 pub struct IgnoreFilter {
@@ -21,12 +21,13 @@ impl IgnoreFilter {
         file.read_to_string(&mut content).unwrap();
         let lines: Vec<&str> = content.lines().collect();
 
-        let mut patterns_vec = Vec::new();
+        // The game lock is never versioned unless negated in the ignore file
+        let mut patterns_vec = vec![SESSION_LOCK_FILE_NAME.to_string()];
         let mut negated_patterns_vec = Vec::new();
 
         for pattern in lines {
-            if pattern.starts_with('!') {
-                negated_patterns_vec.push(pattern[1..].to_string());
+            if let Some(pattern) = pattern.strip_prefix('!') {
+                negated_patterns_vec.push(pattern.to_string());
             } else {
                 patterns_vec.push(pattern.to_string());
             }
@@ -0,0 +1,359 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+
+use crate::committer;
+use crate::savefiles::{DIRECTORY_NAME, IMPORT_DIRECTORY_NAME};
+use crate::utils::fs_utils;
+
+// Formats of dates in backup names, tried at every position where a number starts
+const DATE_TIME_FORMATS: [&str; 7] = [
+    "%Y-%m-%d_%H-%M-%S",
+    "%Y-%m-%d %H-%M-%S",
+    "%Y-%m-%d_%H.%M.%S",
+    "%Y-%m-%d %H.%M.%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y%m%d_%H%M%S",
+];
+const DATE_FORMATS: [&str; 4] = ["%Y-%m-%d", "%Y_%m_%d", "%Y.%m.%d", "%Y%m%d"];
+
+// File every world has in its root, used to find the world inside a backup
+const LEVEL_FILE_NAME: &str = "level.dat";
+
+enum Source {
+    Directory,
+    Zip,
+    Tar,
+    TarZst,
+}
+
+struct Backup {
+    path: PathBuf,
+    source: Source,
+    timestamp: i64,
+}
+
+// Turns backups (world directories, .zip, .tar or .tar.zst archives) into commits in
+// chronological order. Timestamps are taken from the backup names with `date_from_name`,
+// otherwise from the newest modification time of the files in the backup.
+//
+// The world and the head are not touched, like after a fetch. The backups are added after the
+// head, or after the newest commit without one.
+pub fn import(
+    target_path: &str,
+    sources: &[String],
+    date_from_name: bool,
+) -> Result<Vec<u32>, Box<dyn Error>> {
+    let mut backups = Vec::new();
+    for source in sources {
+        let path = PathBuf::from(source);
+        let source = get_source(&path)?;
+        let timestamp = if date_from_name {
+            date_from_file_name(&path)?
+        } else {
+            newest_modification_time(&path, &source)?
+        };
+        backups.push(Backup {
+            path,
+            source,
+            timestamp,
+        });
+    }
+    backups.sort_by(|a, b| (a.timestamp, &a.path).cmp(&(b.timestamp, &b.path)));
+
+    let work_path = fs_utils::build_path([target_path, DIRECTORY_NAME, IMPORT_DIRECTORY_NAME])?;
+    let count = committer::get_commit_count(target_path)?;
+    let mut parent = match committer::get_head(target_path) {
+        Ok(head) => head,
+        Err(_) => count.saturating_sub(1),
+    };
+    let mut ids = Vec::new();
+    for backup in backups {
+        // Left over from an interrupted import or the previous backup
+        if fs_utils::is_path_exists(&work_path) {
+            fs_utils::remove_dir_all(&work_path)?;
+        }
+
+        let extracted = match backup.source {
+            Source::Directory => backup.path.clone(),
+            Source::Zip => {
+                zip::ZipArchive::new(BufReader::new(File::open(&backup.path)?))?
+                    .extract(&work_path)?;
+                PathBuf::from(&work_path)
+            }
+            Source::Tar => {
+                tar::Archive::new(BufReader::new(File::open(&backup.path)?)).unpack(&work_path)?;
+                PathBuf::from(&work_path)
+            }
+            Source::TarZst => {
+                let decoder = zstd::Decoder::new(File::open(&backup.path)?)?;
+                tar::Archive::new(decoder).unpack(&work_path)?;
+                PathBuf::from(&work_path)
+            }
+        };
+        let world_path = find_world_root(&extracted)?;
+        let world_path = world_path
+            .to_str()
+            .ok_or("Backup path is not valid UTF-8")?;
+
+        let name = backup
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(world_path);
+        let id = committer::get_commit_count(target_path)?;
+        if id > 0 {
+            let parent_commit = committer::get_commit_by_id(target_path, parent)?;
+            if backup.timestamp < parent_commit.timestamp {
+                eprintln!(
+                    "Warning: {name} is older than commit {parent} and is added after it, commits are not reordered."
+                );
+            }
+        }
+        committer::add_commit_at(
+            target_path,
+            world_path,
+            &format!("Import {name}"),
+            Vec::new(),
+            backup.timestamp,
+            None,
+            Some(parent),
+        )?;
+        println!(
+            "Imported {name} as commit {id} ({})",
+            Local
+                .timestamp_opt(backup.timestamp, 0)
+                .unwrap()
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+        ids.push(id);
+        parent = id;
+    }
+
+    if fs_utils::is_path_exists(&work_path) {
+        fs_utils::remove_dir_all(&work_path)?;
+    }
+    Ok(ids)
+}
+
+fn get_source(path: &Path) -> Result<Source, Box<dyn Error>> {
+    if path.is_dir() {
+        return Ok(Source::Directory);
+    }
+    if !path.is_file() {
+        return Err(format!("{} does not exist", path.display()).into());
+    }
+
+    let name = path.to_string_lossy().to_lowercase();
+    if name.ends_with(".zip") {
+        Ok(Source::Zip)
+    } else if name.ends_with(".tar") {
+        Ok(Source::Tar)
+    } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+        Ok(Source::TarZst)
+    } else {
+        Err(format!(
+            "Unknown backup format of {}, use a directory, .zip, .tar or .tar.zst",
+            path.display()
+        )
+        .into())
+    }
+}
+
+// Finds a date like 2021-03-04, 20210304 or 2021-03-04_12-30-00 in the name, in local time
+fn date_from_file_name(path: &Path) -> Result<i64, Box<dyn Error>> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    let bytes = name.as_bytes();
+    for start in 0..bytes.len() {
+        if !bytes[start].is_ascii_digit() || (start > 0 && bytes[start - 1].is_ascii_digit()) {
+            continue;
+        }
+        let rest = &name[start..];
+
+        let date_time = DATE_TIME_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_and_remainder(rest, format).ok())
+            .map(|(date_time, _)| date_time)
+            .or_else(|| {
+                DATE_FORMATS.iter().find_map(|format| {
+                    let (date, _) = NaiveDate::parse_and_remainder(rest, format).ok()?;
+                    date.and_hms_opt(0, 0, 0)
+                })
+            });
+
+        if let Some(date_time) = date_time {
+            return Local
+                .from_local_datetime(&date_time)
+                .earliest()
+                .map(|time| time.timestamp())
+                .ok_or_else(|| format!("Invalid local time in {name}").into());
+        }
+    }
+
+    Err(format!("No date found in the name of {}", path.display()).into())
+}
+
+// Newest modification time of a file in the backup, the time it was taken
+fn newest_modification_time(path: &Path, source: &Source) -> Result<i64, Box<dyn Error>> {
+    let mut newest: Option<i64> = None;
+    match source {
+        Source::Directory => {
+            for entry in fs_utils::get_all_files_in_directory(path.to_str().ok_or("Invalid path")?)?
+            {
+                let modified = entry.metadata()?.modified()?;
+                newest = newest.max(Some(system_time_to_timestamp(modified)));
+            }
+        }
+        Source::Zip => {
+            let mut archive = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+            for i in 0..archive.len() {
+                let Some(modified) = archive.by_index_raw(i)?.last_modified() else {
+                    continue;
+                };
+                let Some(date_time) = NaiveDate::from_ymd_opt(
+                    modified.year() as i32,
+                    modified.month() as u32,
+                    modified.day() as u32,
+                )
+                .and_then(|date| {
+                    date.and_hms_opt(
+                        modified.hour() as u32,
+                        modified.minute() as u32,
+                        modified.second() as u32,
+                    )
+                }) else {
+                    continue;
+                };
+                if let Some(time) = Local.from_local_datetime(&date_time).earliest() {
+                    newest = newest.max(Some(time.timestamp()));
+                }
+            }
+        }
+        Source::Tar => {
+            let mut archive = tar::Archive::new(BufReader::new(File::open(path)?));
+            for entry in archive.entries()? {
+                newest = newest.max(Some(entry?.header().mtime()? as i64));
+            }
+        }
+        Source::TarZst => {
+            let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path)?)?);
+            for entry in archive.entries()? {
+                newest = newest.max(Some(entry?.header().mtime()? as i64));
+            }
+        }
+    }
+
+    // An empty backup, fall back to the time of the backup itself
+    match newest {
+        Some(newest) => Ok(newest),
+        None => Ok(system_time_to_timestamp(fs::metadata(path)?.modified()?)),
+    }
+}
+
+fn system_time_to_timestamp(time: SystemTime) -> i64 {
+    chrono::DateTime::<chrono::Utc>::from(time).timestamp()
+}
+
+// Backups often contain the world in a subdirectory, e.g. `world/level.dat`.
+// Returns the shallowest directory with a level.dat, or the backup root without one.
+fn find_world_root(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let mut level = vec![path.to_path_buf()];
+    while !level.is_empty() {
+        let mut next = Vec::new();
+        for dir in level {
+            if dir.join(LEVEL_FILE_NAME).is_file() {
+                return Ok(dir);
+            }
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() && entry.file_name() != DIRECTORY_NAME {
+                    next.push(entry.path());
+                }
+            }
+        }
+        next.sort();
+        level = next;
+    }
+    Ok(path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initializer;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+
+    // Tar archive holding a world with the level file in the directory `world`
+    fn world_tar(level: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(level.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "world/level.dat", level)
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn imports_a_folder_of_backups_in_chronological_order() {
+        let world = temp_dir("import-world");
+        write_world_file(&world, LEVEL_FILE_NAME, b"current");
+        initializer::init(&world).unwrap();
+
+        let backups = temp_dir("import-backups");
+        write_world_file(&backups, "world_2021-03-05/level.dat", b"second");
+        write_world_file(&backups, "world_2021-03-05/region/r.0.0.mca", &[5; 3000]);
+        write_world_file(
+            &backups,
+            "world_2021-03-04_12-30-00.tar",
+            &world_tar(b"first"),
+        );
+        let zst = zstd::encode_all(world_tar(b"third").as_slice(), 3).unwrap();
+        write_world_file(&backups, "world_2021-03-06.tar.zst", &zst);
+        let sources: Vec<String> = fs::read_dir(&backups)
+            .unwrap()
+            .map(|entry| entry.unwrap().path().to_str().unwrap().to_string())
+            .collect();
+
+        assert_eq!(import(&world, &sources, true).unwrap(), [1, 2, 3]);
+        let commits = committer::read_all_commits(&world).unwrap();
+        assert_eq!(commits.len(), 4);
+        let tags: Vec<_> = commits[1..]
+            .iter()
+            .map(|c| c.tag_as_str().unwrap())
+            .collect();
+        assert_eq!(
+            tags,
+            [
+                "Import world_2021-03-04_12-30-00.tar",
+                "Import world_2021-03-05",
+                "Import world_2021-03-06.tar.zst",
+            ]
+        );
+        for pair in commits[1..].windows(2) {
+            assert_eq!(pair[1].parent_id, pair[0].id);
+            assert!(pair[1].timestamp > pair[0].timestamp);
+        }
+
+        // The world is not touched
+        assert_eq!(committer::get_head(&world).unwrap(), 0);
+        assert_eq!(read_world_file(&world, LEVEL_FILE_NAME), b"current");
+        for (id, level) in [(1, &b"first"[..]), (2, b"second"), (3, b"third")] {
+            committer::restore(&world, id, Vec::new(), true).unwrap();
+            assert_eq!(read_world_file(&world, LEVEL_FILE_NAME), level);
+        }
+        assert!(!fs_utils::is_path_exists(
+            &fs_utils::build_path([world.as_str(), DIRECTORY_NAME, IMPORT_DIRECTORY_NAME]).unwrap()
+        ));
+    }
+}
@@ -2,12 +2,24 @@ use std::error::Error;
 
 use crate::{
     committer::add_commit,
-    savefiles::{DIRECTORY_NAME, IGNORE_FILE_NAME},
+    config::Config,
+    migrate,
+    savefiles::{
+        DIRECTORY_NAME, FORMAT_VERSION, HOOKS_DIRECTORY_NAME, IGNORE_FILE_NAME,
+        SESSION_LOCK_FILE_NAME,
+    },
     utils::fs_utils::{self},
 };
 
 pub fn init(target_path: &str) -> Result<(), Box<dyn Error>> {
-    let dir_path = fs_utils::build_path([&target_path, DIRECTORY_NAME])?;
+    init_empty(target_path)?;
+    add_commit(target_path, "Initial Commit.", Vec::new())?;
+    Ok(())
+}
+
+// Creates a repository without commits, e.g. to receive commits from another one
+pub fn init_empty(target_path: &str) -> Result<(), Box<dyn Error>> {
+    let dir_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
 
     // Check if repo is exists
     if fs_utils::is_path_exists(&dir_path) {
@@ -16,14 +28,23 @@ pub fn init(target_path: &str) -> Result<(), Box<dyn Error>> {
 
     // Create a directory
     fs_utils::make_dir(&dir_path)?;
+    migrate::write_format_version(&dir_path, FORMAT_VERSION)?;
     // Create ignore file
-    let patterns = [".git/*", "target/*", ".minegit/*", "src/*"];
+    let patterns = [
+        ".git/*",
+        "target/*",
+        ".minegit/*",
+        "src/*",
+        SESSION_LOCK_FILE_NAME,
+    ];
 
     fs_utils::write_file(
         &format!("{dir_path}/{IGNORE_FILE_NAME}"),
         patterns.join("\n").as_bytes(),
     )?;
 
-    add_commit(&target_path, "Initial Commit.", Vec::new())?;
+    Config::default().save(target_path)?;
+    // Create an empty hooks directory
+    fs_utils::make_dir(&format!("{dir_path}/{HOOKS_DIRECTORY_NAME}"))?;
     Ok(())
 }
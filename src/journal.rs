@@ -0,0 +1,688 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::committer;
+use crate::lock::DataLock;
+use crate::savefiles::{
+    BACKUP_DIRECTORY_NAME, COMMITS_FILE_NAME, COMMITS_INFO_FILE_NAME, DATA_DIRECTORY_NAME,
+    DIRECTORY_NAME, HEAD_FILE_NAME, JOURNAL_BACKUP_DIRECTORY_NAME, JOURNAL_FILE_NAME,
+    OBJECTS_INDEX_FILE_NAME, PRUNE_DIRECTORY_NAME, RESTORE_JOURNAL_FILE_NAME,
+    STAGING_DIRECTORY_NAME, STASH_FILE_NAME,
+};
+use crate::utils::fs_utils;
+
+// Write-ahead journal of a single commit.
+//
+// Before any repository file is modified its previous state is recorded and flushed to
+// `.minegit/journal`. The journal is removed once every write is durable, which is the
+// point at which the commit becomes visible. If the journal is still present on the next
+// run, the interrupted commit is rolled back using the recorded states.
+//
+// Records are text lines: `commits <len>`, `info <len>`, `head <id|->`,
+// `pkg <len|-> <path>` and `rewrite <pos> <backup> <path>`, where `-` means the file did not
+// exist and `<path>` is relative to the `.minegit` directory. A package written over from
+// `<pos>` on, see `record_rewrite`, is restored from the copy of its end kept in
+// `.minegit/journal_backup/<backup>`. A trailing line without a newline is an incomplete
+// record and is ignored, since the file it describes was not touched yet.
+pub struct Journal {
+    root_path: String,
+    file: File,
+    backups: u32,
+}
+
+fn get_journal_path(root_path: &str) -> io::Result<String> {
+    fs_utils::build_path([root_path, JOURNAL_FILE_NAME])
+}
+
+fn get_backup_path(root_path: &str) -> io::Result<String> {
+    fs_utils::build_path([root_path, JOURNAL_BACKUP_DIRECTORY_NAME])
+}
+
+fn file_len(path: &str) -> Option<u64> {
+    fs_utils::get_file_size(path).ok()
+}
+
+fn format_len(len: Option<u64>) -> String {
+    len.map_or("-".to_string(), |l| l.to_string())
+}
+
+impl Journal {
+    // Starts a journal, recording the state of the commit list, commit infos and head
+    pub fn begin(target_path: &str) -> io::Result<Self> {
+        let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+        let journal_path = get_journal_path(&root_path)?;
+
+        if fs_utils::is_path_exists(&journal_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Another commit is in progress or was interrupted",
+            ));
+        }
+
+        let commits_len = file_len(&fs_utils::build_path([&root_path, COMMITS_FILE_NAME])?);
+        let info_len = file_len(&fs_utils::build_path([&root_path, COMMITS_INFO_FILE_NAME])?);
+        let head = committer::get_head(target_path).ok().map(u64::from);
+
+        // Left behind if the process died right after the previous journal was removed
+        let backup_path = get_backup_path(&root_path)?;
+        if fs_utils::is_path_exists(&backup_path) {
+            fs_utils::remove_dir_all(&backup_path)?;
+        }
+
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&journal_path)?;
+        let mut journal = Self {
+            root_path,
+            file,
+            backups: 0,
+        };
+        journal.write_record(&format!(
+            "commits {}\ninfo {}\nhead {}\n",
+            commits_len.unwrap_or(0),
+            info_len.unwrap_or(0),
+            format_len(head),
+        ))?;
+        Ok(journal)
+    }
+
+    // Records the current length of a package before it is appended to or created
+    pub fn record_package(&mut self, package_path: &str) -> io::Result<()> {
+        let relative = self.relative_path(package_path)?;
+        let len = file_len(package_path);
+        self.write_record(&format!("pkg {} {relative}\n", format_len(len)))
+    }
+
+    // Keeps a copy of a package from `pos` to its end before it is written over, packages are
+    // otherwise only appended to
+    pub fn record_rewrite(&mut self, package_path: &str, pos: u64) -> io::Result<()> {
+        let relative = self.relative_path(package_path)?;
+        let backup_dir = get_backup_path(&self.root_path)?;
+        if !fs_utils::is_path_exists(&backup_dir) {
+            fs_utils::make_dir(&backup_dir)?;
+        }
+        let backup = self.backups;
+        self.backups += 1;
+
+        let mut package = fs_utils::read_file(package_path)?;
+        package.seek(SeekFrom::Start(pos))?;
+        let mut backup_file =
+            File::create(fs_utils::build_path([&backup_dir, &backup.to_string()])?)?;
+        io::copy(&mut package, &mut backup_file)?;
+        backup_file.sync_all()?;
+
+        self.write_record(&format!("rewrite {pos} {backup} {relative}\n"))
+    }
+
+    fn relative_path(&self, package_path: &str) -> io::Result<String> {
+        Ok(Path::new(package_path)
+            .strip_prefix(&self.root_path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid package path"))?
+            .to_string())
+    }
+
+    fn write_record(&mut self, record: &str) -> io::Result<()> {
+        self.file.write_all(record.as_bytes())?;
+        self.file.sync_all()
+    }
+
+    // Makes the commit visible by removing the journal.
+    // All journaled files must already be synced to disk.
+    pub fn finish(self) -> io::Result<()> {
+        let journal_path = get_journal_path(&self.root_path)?;
+        drop(self.file);
+        fs_utils::remove_file(&journal_path)?;
+        remove_backups(&self.root_path)
+    }
+}
+
+// Rolls back an interrupted commit or restore, must be called while holding the repository lock
+pub fn recover_all(target_path: &str) -> io::Result<()> {
+    if recover(target_path)? {
+        eprintln!("Rolled back an interrupted commit.");
+    }
+    if recover_restore(target_path)? {
+        eprintln!("Rolled back an interrupted restore.");
+    }
+    if recover_prune(target_path)? {
+        eprintln!("Finished an interrupted prune.");
+    }
+    Ok(())
+}
+
+// Rolls back an interrupted commit if a journal is left in the repository.
+// Returns true if a rollback was performed.
+pub fn recover(target_path: &str) -> io::Result<bool> {
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+    let journal_path = get_journal_path(&root_path)?;
+
+    if !fs_utils::is_path_exists(&journal_path) {
+        return Ok(false);
+    }
+    // Rolling back rewrites the end of packages
+    let _data_lock = DataLock::exclusive(target_path)?;
+
+    let mut content = String::new();
+    fs_utils::read_file(&journal_path)?.read_to_string(&mut content)?;
+
+    // Only complete records are applied
+    let complete = match content.rfind('\n') {
+        Some(end) => &content[..end],
+        None => "",
+    };
+
+    for record in complete.lines() {
+        let (kind, rest) = record.split_once(' ').unwrap_or((record, ""));
+        match kind {
+            "commits" => restore_len(
+                &fs_utils::build_path([&root_path, COMMITS_FILE_NAME])?,
+                rest,
+            )?,
+            "info" => restore_len(
+                &fs_utils::build_path([&root_path, COMMITS_INFO_FILE_NAME])?,
+                rest,
+            )?,
+            "head" => {
+                let head_path = fs_utils::build_path([&root_path, HEAD_FILE_NAME])?;
+                match rest.parse::<u32>() {
+                    Ok(id) => committer::write_head(target_path, id)
+                        .map_err(|e| io::Error::other(e.to_string()))?,
+                    Err(_) if fs_utils::is_path_exists(&head_path) => {
+                        fs_utils::remove_file(&head_path)?
+                    }
+                    Err(_) => {}
+                }
+            }
+            "pkg" => {
+                let (len, path) = rest.split_once(' ').ok_or_else(invalid_record)?;
+                restore_len(&fs_utils::build_path([&root_path, path])?, len)?;
+            }
+            "rewrite" => {
+                let mut fields = rest.splitn(3, ' ');
+                let (Some(pos), Some(backup), Some(path)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(invalid_record());
+                };
+                let pos = pos.parse::<u64>().map_err(|_| invalid_record())?;
+                restore_end(
+                    &fs_utils::build_path([&root_path, path])?,
+                    pos,
+                    &fs_utils::build_path([&get_backup_path(&root_path)?, backup])?,
+                )?;
+            }
+            _ => return Err(invalid_record()),
+        }
+    }
+
+    fs_utils::remove_file(&journal_path)?;
+    remove_backups(&root_path)?;
+    Ok(true)
+}
+
+fn remove_backups(root_path: &str) -> io::Result<()> {
+    let backup_path = get_backup_path(root_path)?;
+    if fs_utils::is_path_exists(&backup_path) {
+        fs_utils::remove_dir_all(&backup_path)?;
+    }
+    Ok(())
+}
+
+fn invalid_record() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Corrupted journal record")
+}
+
+// Truncates the file to the recorded length or removes it if it did not exist
+fn restore_len(path: &str, len: &str) -> io::Result<()> {
+    if !fs_utils::is_path_exists(path) {
+        return Ok(());
+    }
+    if len == "-" {
+        return fs_utils::remove_file(path);
+    }
+    let len = len.parse::<u64>().map_err(|_| invalid_record())?;
+    fs_utils::truncate_file(path, len)
+}
+
+// Puts back the end of a package from `pos` on, as copied to `backup`
+fn restore_end(path: &str, pos: u64, backup: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(pos)?;
+    file.seek(SeekFrom::Start(pos))?;
+    io::copy(&mut fs_utils::read_file(backup)?, &mut file)?;
+    file.sync_all()
+}
+
+// Journal of a restore.
+//
+// Recovered files are first written to `.minegit/staging` and verified against the
+// hashes stored in the commit. Only then the plan is written to `.minegit/restore_journal`
+// and the world files are swapped: originals are moved to `.minegit/backup` and staged
+// files are moved into their place. If the swap fails, or the process dies while swapping,
+// the backups are moved back so the world is left as it was before the restore.
+//
+// Plan records are text lines: `head <id|->`, `replace <path>`, `create <path>` and
+// `remove <path>`, where `<path>` is relative to the world directory.
+pub struct RestoreJournal {
+    target_path: String,
+    root_path: String,
+    // Files are staged from several threads
+    staged: Mutex<Vec<String>>,
+}
+
+impl RestoreJournal {
+    pub fn begin(target_path: &str) -> io::Result<Self> {
+        let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+        let staging_path = fs_utils::build_path([&root_path, STAGING_DIRECTORY_NAME])?;
+
+        if fs_utils::is_path_exists(&staging_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Another restore is in progress or was interrupted",
+            ));
+        }
+        fs_utils::make_dir(&staging_path)?;
+
+        Ok(Self {
+            target_path: target_path.to_string(),
+            root_path,
+            staged: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Writes the recovered file streamed by `write` to the staging directory and checks its
+    // hash
+    pub fn stage<F: FnOnce(&mut dyn Write) -> io::Result<()>>(
+        &self,
+        path: &str,
+        hash: &[u8; 32],
+        write: F,
+    ) -> io::Result<()> {
+        let staged_path = fs_utils::build_path([&self.root_path, STAGING_DIRECTORY_NAME, path])?;
+        let file = fs_utils::open_to_write(&staged_path, true)?;
+        let mut out = fs_utils::HashingWriter::new(BufWriter::new(file));
+        write(&mut out)?;
+
+        let (staged_hash, out) = out.finish();
+        if staged_hash != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Recovered {path} does not match the hash stored in the commit"),
+            ));
+        }
+        out.into_inner()?.sync_all()?;
+
+        self.staged.lock().unwrap().push(path.to_string());
+        Ok(())
+    }
+
+    // Discards the staged files, the world was not touched yet
+    pub fn abort(self) -> io::Result<()> {
+        fs_utils::remove_dir_all(&fs_utils::build_path([
+            &self.root_path,
+            STAGING_DIRECTORY_NAME,
+        ])?)
+    }
+
+    // Swaps the staged files into the world, removes `removed` files and moves head.
+    // On failure the world and head are rolled back before the error is returned.
+    pub fn apply(self, removed: &[String], head: u32) -> Result<(), Box<dyn Error>> {
+        let mut plan = format!(
+            "head {}\n",
+            format_len(committer::get_head(&self.target_path).ok().map(u64::from))
+        );
+        let mut staged = std::mem::take(&mut *self.staged.lock().unwrap());
+        staged.sort();
+        for path in &staged {
+            let world_path = fs_utils::build_path([&self.target_path, path])?;
+            let kind = if fs_utils::is_path_exists(&world_path) {
+                "replace"
+            } else {
+                "create"
+            };
+            plan.push_str(&format!("{kind} {path}\n"));
+        }
+        for path in removed {
+            plan.push_str(&format!("remove {path}\n"));
+        }
+
+        let journal_path = fs_utils::build_path([&self.root_path, RESTORE_JOURNAL_FILE_NAME])?;
+        fs_utils::write_file_atomic(&journal_path, plan.as_bytes())?;
+
+        if let Err(e) = self.swap(&plan, head) {
+            recover_restore(&self.target_path)?;
+            return Err(e);
+        }
+
+        // The restore is complete, drop the backups
+        fs_utils::remove_file(&journal_path)?;
+        for dir in [STAGING_DIRECTORY_NAME, BACKUP_DIRECTORY_NAME] {
+            let path = fs_utils::build_path([&self.root_path, dir])?;
+            if fs_utils::is_path_exists(&path) {
+                fs_utils::remove_dir_all(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn swap(&self, plan: &str, head: u32) -> Result<(), Box<dyn Error>> {
+        for record in plan.lines() {
+            let (kind, path) = record.split_once(' ').ok_or_else(invalid_record)?;
+            let world_path = fs_utils::build_path([&self.target_path, path])?;
+            let backup_path = fs_utils::build_path([&self.root_path, BACKUP_DIRECTORY_NAME, path])?;
+            let staged_path =
+                fs_utils::build_path([&self.root_path, STAGING_DIRECTORY_NAME, path])?;
+
+            match kind {
+                "replace" => {
+                    fs_utils::move_file(&world_path, &backup_path)?;
+                    fs_utils::move_file(&staged_path, &world_path)?;
+                }
+                "create" => fs_utils::move_file(&staged_path, &world_path)?,
+                "remove" => fs_utils::move_file(&world_path, &backup_path)?,
+                _ => {}
+            }
+        }
+
+        committer::write_head(&self.target_path, head)
+    }
+}
+
+// Moves the world back to its state before an interrupted restore.
+// Returns true if a rollback was performed.
+pub fn recover_restore(target_path: &str) -> io::Result<bool> {
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+    let journal_path = fs_utils::build_path([&root_path, RESTORE_JOURNAL_FILE_NAME])?;
+    let staging_path = fs_utils::build_path([&root_path, STAGING_DIRECTORY_NAME])?;
+    let backup_path = fs_utils::build_path([&root_path, BACKUP_DIRECTORY_NAME])?;
+
+    // Interrupted while staging, the world was not touched
+    if !fs_utils::is_path_exists(&journal_path) {
+        if fs_utils::is_path_exists(&staging_path) {
+            fs_utils::remove_dir_all(&staging_path)?;
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
+    let mut plan = String::new();
+    fs_utils::read_file(&journal_path)?.read_to_string(&mut plan)?;
+
+    for record in plan.lines() {
+        let (kind, rest) = record.split_once(' ').ok_or_else(invalid_record)?;
+        let world_path = fs_utils::build_path([target_path, rest])?;
+        let backup_file = fs_utils::build_path([&backup_path, rest])?;
+        let staged_file = fs_utils::build_path([&staging_path, rest])?;
+
+        match kind {
+            "head" => match rest.parse::<u32>() {
+                Ok(id) => committer::write_head(target_path, id)
+                    .map_err(|e| io::Error::other(e.to_string()))?,
+                Err(_) => {
+                    let head_path = fs_utils::build_path([&root_path, HEAD_FILE_NAME])?;
+                    if fs_utils::is_path_exists(&head_path) {
+                        fs_utils::remove_file(&head_path)?;
+                    }
+                }
+            },
+            "replace" | "remove" => {
+                if fs_utils::is_path_exists(&backup_file) {
+                    if fs_utils::is_path_exists(&world_path) {
+                        fs_utils::remove_file(&world_path)?;
+                    }
+                    fs_utils::move_file(&backup_file, &world_path)?;
+                }
+            }
+            "create" => {
+                // The file did not exist before, so it is present only if it was swapped in
+                if !fs_utils::is_path_exists(&staged_file) && fs_utils::is_path_exists(&world_path)
+                {
+                    fs_utils::remove_file(&world_path)?;
+                }
+            }
+            _ => return Err(invalid_record()),
+        }
+    }
+
+    for dir in [&staging_path, &backup_path] {
+        if fs_utils::is_path_exists(dir) {
+            fs_utils::remove_dir_all(dir)?;
+        }
+    }
+    fs_utils::remove_file(&journal_path)?;
+    Ok(true)
+}
+
+// Swap of the repository rebuilt by a prune.
+//
+// The prune builds the new repository in `.minegit/prune/.minegit`. Once it is complete a
+// `ready` file listing the rebuilt files is written to `.minegit/prune`, and from then on an
+// interrupted swap is rolled forward: every old file is moved to `.minegit/prune/old` right
+// before the rebuilt one is moved into its place. Without the ready file the rebuilt
+// repository may be incomplete, so it is removed and the repository is left as it was.
+const PRUNE_READY_FILE_NAME: &str = "ready";
+const PRUNED_FILE_NAMES: [&str; 6] = [
+    COMMITS_FILE_NAME,
+    COMMITS_INFO_FILE_NAME,
+    HEAD_FILE_NAME,
+    STASH_FILE_NAME,
+    OBJECTS_INDEX_FILE_NAME,
+    DATA_DIRECTORY_NAME,
+];
+
+// Marks the rebuilt repository complete and swaps it in. Its files must already be synced
+// to disk.
+pub fn swap_in_pruned(target_path: &str) -> io::Result<()> {
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+    let work_path = fs_utils::build_path([&root_path, PRUNE_DIRECTORY_NAME])?;
+
+    let mut rebuilt = String::new();
+    for name in PRUNED_FILE_NAMES {
+        if fs_utils::is_path_exists(&fs_utils::build_path([&work_path, DIRECTORY_NAME, name])?) {
+            rebuilt.push_str(name);
+            rebuilt.push('\n');
+        }
+    }
+    let ready_path = fs_utils::build_path([&work_path, PRUNE_READY_FILE_NAME])?;
+    let _data_lock = DataLock::exclusive(target_path)?;
+    fs_utils::write_file(&ready_path, rebuilt.as_bytes())?.sync_all()?;
+    finish_prune(&root_path, &work_path)
+}
+
+fn finish_prune(root_path: &str, work_path: &str) -> io::Result<()> {
+    let mut rebuilt = String::new();
+    fs_utils::read_file(&fs_utils::build_path([work_path, PRUNE_READY_FILE_NAME])?)?
+        .read_to_string(&mut rebuilt)?;
+    let rebuilt: Vec<&str> = rebuilt.lines().collect();
+
+    for name in PRUNED_FILE_NAMES {
+        let path = fs_utils::build_path([root_path, name])?;
+        let new_path = fs_utils::build_path([work_path, DIRECTORY_NAME, name])?;
+        // A present file is the old one, unless the rebuilt one was already moved in
+        let is_old = fs_utils::is_path_exists(&new_path) || !rebuilt.contains(&name);
+        if is_old && fs_utils::is_path_exists(&path) {
+            fs_utils::move_file(&path, &fs_utils::build_path([work_path, "old", name])?)?;
+        }
+        if fs_utils::is_path_exists(&new_path) {
+            fs_utils::move_file(&new_path, &path)?;
+        }
+    }
+    fs_utils::remove_dir_all(work_path)
+}
+
+// Finishes an interrupted prune whose rebuilt repository was complete, or removes it.
+// Returns true if the prune was finished.
+pub fn recover_prune(target_path: &str) -> io::Result<bool> {
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+    let work_path = fs_utils::build_path([&root_path, PRUNE_DIRECTORY_NAME])?;
+    if !fs_utils::is_path_exists(&work_path) {
+        return Ok(false);
+    }
+
+    if fs_utils::is_path_exists(&fs_utils::build_path([&work_path, PRUNE_READY_FILE_NAME])?) {
+        let _data_lock = DataLock::exclusive(target_path)?;
+        finish_prune(&root_path, &work_path)?;
+        return Ok(true);
+    }
+    fs_utils::remove_dir_all(&work_path)?;
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initializer;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+
+    // Repository files a commit of the test world writes to, `None` if missing
+    fn repo_state(world: &str) -> Vec<Option<Vec<u8>>> {
+        [
+            COMMITS_FILE_NAME,
+            COMMITS_INFO_FILE_NAME,
+            HEAD_FILE_NAME,
+            "data/region/r.0.0.mca.pkg",
+            "data/level.dat.pkg",
+        ]
+        .iter()
+        .map(|name| {
+            std::fs::read(fs_utils::build_path([world, DIRECTORY_NAME, name]).unwrap()).ok()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn rolls_back_a_commit_interrupted_before_its_journal_was_removed() {
+        let world = temp_dir("journal-commit");
+        let mut region: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        initializer::init(&world).unwrap();
+        let before = repo_state(&world);
+
+        // Journal of a commit that wrote everything and died before removing it
+        let root_path = fs_utils::build_path([&world, DIRECTORY_NAME]).unwrap();
+        let mut journal = Journal::begin(&world).unwrap();
+        for path in ["region/r.0.0.mca.pkg", "level.dat.pkg"] {
+            journal
+                .record_package(
+                    &fs_utils::build_path([&root_path, DATA_DIRECTORY_NAME, path]).unwrap(),
+                )
+                .unwrap();
+        }
+        drop(journal);
+        let journal_path = get_journal_path(&root_path).unwrap();
+        let parked_path = format!("{journal_path}.parked");
+        std::fs::rename(&journal_path, &parked_path).unwrap();
+
+        region[100..200].fill(1);
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        write_world_file(&world, "level.dat", b"level");
+        committer::add_commit(&world, "interrupted", Vec::new()).unwrap();
+        std::fs::rename(&parked_path, &journal_path).unwrap();
+        assert_ne!(repo_state(&world), before);
+
+        assert!(recover(&world).unwrap());
+        assert_eq!(repo_state(&world), before);
+        assert!(!fs_utils::is_path_exists(&journal_path));
+        assert!(!recover(&world).unwrap());
+
+        // The repository takes new commits again
+        committer::add_commit(&world, "retried", Vec::new()).unwrap();
+        assert_eq!(committer::get_commit_count(&world).unwrap(), 2);
+    }
+
+    // Stages `data` as the restored `path`
+    fn stage(transaction: &RestoreJournal, path: &str, data: &[u8]) -> io::Result<()> {
+        let mut hasher = fs_utils::HashingWriter::new(io::sink());
+        hasher.write_all(data)?;
+        let (hash, _) = hasher.finish();
+        transaction.stage(path, &hash, |out| out.write_all(data))
+    }
+
+    #[test]
+    fn rolls_back_a_restore_that_failed_while_swapping() {
+        let world = temp_dir("journal-restore");
+        write_world_file(&world, "region/r.0.0.mca", &[1; 5000]);
+        write_world_file(&world, "level.dat", b"level");
+        initializer::init(&world).unwrap();
+        let head = committer::get_head(&world).unwrap();
+
+        let transaction = RestoreJournal::begin(&world).unwrap();
+        stage(&transaction, "region/r.0.0.mca", &[2; 6000]).unwrap();
+        stage(&transaction, "new.dat", b"new").unwrap();
+        // Removing a file that does not exist fails after the others were swapped
+        let removed = ["level.dat".to_string(), "missing.dat".to_string()];
+        assert!(transaction.apply(&removed, head + 1).is_err());
+
+        assert_eq!(read_world_file(&world, "region/r.0.0.mca"), [1; 5000]);
+        assert_eq!(read_world_file(&world, "level.dat"), b"level");
+        assert!(!fs_utils::is_path_exists(
+            &fs_utils::build_path([&world, "new.dat"]).unwrap()
+        ));
+        assert_eq!(committer::get_head(&world).unwrap(), head);
+        for name in [
+            RESTORE_JOURNAL_FILE_NAME,
+            STAGING_DIRECTORY_NAME,
+            BACKUP_DIRECTORY_NAME,
+        ] {
+            let path = fs_utils::build_path([&world, DIRECTORY_NAME, name]).unwrap();
+            assert!(!fs_utils::is_path_exists(&path));
+        }
+    }
+
+    #[test]
+    fn discards_files_staged_by_an_interrupted_restore() {
+        let world = temp_dir("journal-staging");
+        write_world_file(&world, "level.dat", b"level");
+        initializer::init(&world).unwrap();
+
+        let transaction = RestoreJournal::begin(&world).unwrap();
+        stage(&transaction, "level.dat", b"other").unwrap();
+        // A file not matching its hash is never swapped in
+        let result = transaction.stage("level.dat", &[0; 32], |out| out.write_all(b"bad"));
+        assert!(result.is_err());
+        drop(transaction);
+
+        assert!(recover_restore(&world).unwrap());
+        assert_eq!(read_world_file(&world, "level.dat"), b"level");
+        assert!(RestoreJournal::begin(&world).is_ok());
+    }
+
+    #[test]
+    fn puts_back_rewritten_package_ends_and_ignores_incomplete_records() {
+        let world = temp_dir("journal-rewrite");
+        write_world_file(&world, "region/r.0.0.mca", &[3; 5000]);
+        initializer::init(&world).unwrap();
+        let before = repo_state(&world);
+
+        let root_path = fs_utils::build_path([&world, DIRECTORY_NAME]).unwrap();
+        let package_path =
+            fs_utils::build_path([&root_path, DATA_DIRECTORY_NAME, "region/r.0.0.mca.pkg"])
+                .unwrap();
+        let len = std::fs::metadata(&package_path).unwrap().len();
+        let mut journal = Journal::begin(&world).unwrap();
+        journal.record_rewrite(&package_path, len - 10).unwrap();
+        // Died while writing the next record, its file was not touched yet
+        journal
+            .file
+            .write_all(b"pkg 0 data/region/r.0.0.mca.pkg")
+            .unwrap();
+        drop(journal);
+
+        let mut package = OpenOptions::new().write(true).open(&package_path).unwrap();
+        package.seek(SeekFrom::Start(len - 10)).unwrap();
+        package.write_all(&[0xff; 40]).unwrap();
+        drop(package);
+
+        assert!(recover(&world).unwrap());
+        assert_eq!(repo_state(&world), before);
+        assert!(!fs_utils::is_path_exists(
+            &get_backup_path(&root_path).unwrap()
+        ));
+    }
+}
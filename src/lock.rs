@@ -0,0 +1,192 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+use sysinfo::{Pid, ProcessesToUpdate, System};
+
+use crate::savefiles::{DATA_LOCK_FILE_NAME, DIRECTORY_NAME, LOCK_FILE_NAME};
+use crate::utils::fs_utils;
+
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+// Exclusive lock of a repository held by a command reading or changing it.
+//
+// The lock is a `.minegit/lock` file containing the PID of its owner. It is removed when
+// the lock is dropped. A lock left by a process that is no longer running is stale and
+// is taken over.
+pub struct RepoLock {
+    path: String,
+}
+
+impl RepoLock {
+    // Acquires the lock, if `wait` is set blocks until the other process releases it
+    pub fn acquire(target_path: &str, wait: bool) -> Result<Self, Box<dyn Error>> {
+        let path = fs_utils::build_path([target_path, DIRECTORY_NAME, LOCK_FILE_NAME])?;
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(std::process::id().to_string().as_bytes())?;
+                    file.sync_all()?;
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let owner = read_owner(&path);
+            match owner {
+                Some(pid) if is_process_alive(pid) => {
+                    if !wait {
+                        return Err(format!(
+                            "Repository is locked by process {pid}, use --wait to wait for it"
+                        )
+                        .into());
+                    }
+                    thread::sleep(WAIT_INTERVAL);
+                }
+                // The owner may not have written its PID yet
+                None if is_recently_modified(&path) => thread::sleep(WAIT_INTERVAL),
+                // Remove the stale lock unless another process took it over meanwhile
+                _ => {
+                    if fs_utils::is_path_exists(&path) && read_owner(&path) == owner {
+                        eprintln!("Removing stale lock of process {}.", format_owner(owner));
+                        let _ = fs_utils::remove_file(&path);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        let _ = fs_utils::remove_file(&self.path);
+    }
+}
+
+// Lock of the data already stored in a repository.
+//
+// Reading commands share it instead of taking the `RepoLock`, so they are not refused
+// while a commit appends to the repository. Changes of stored data in place, the reverse
+// delta rewrites, rollbacks and the swap of a pruned repository, hold it exclusively. The
+// lock is an OS file lock on `.minegit/data.lock`, released when the process exits.
+pub struct DataLock {
+    _file: File,
+}
+
+impl DataLock {
+    // Blocks while the data is changed in place
+    pub fn shared(target_path: &str) -> io::Result<Self> {
+        let file = Self::open(target_path)?;
+        file.lock_shared()?;
+        Ok(Self { _file: file })
+    }
+
+    // Blocks until no other process reads the data
+    pub fn exclusive(target_path: &str) -> io::Result<Self> {
+        let file = Self::open(target_path)?;
+        file.lock()?;
+        Ok(Self { _file: file })
+    }
+
+    fn open(target_path: &str) -> io::Result<File> {
+        let path = fs_utils::build_path([target_path, DIRECTORY_NAME, DATA_LOCK_FILE_NAME])?;
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+    }
+}
+
+fn read_owner(path: &str) -> Option<u32> {
+    let mut content = String::new();
+    fs_utils::read_file(path)
+        .ok()?
+        .read_to_string(&mut content)
+        .ok()?;
+    content.trim().parse().ok()
+}
+
+fn format_owner(owner: Option<u32>) -> String {
+    owner.map_or("<unknown>".to_string(), |pid| pid.to_string())
+}
+
+fn is_recently_modified(path: &str) -> bool {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed < Duration::from_secs(5))
+}
+
+fn is_process_alive(pid: u32) -> bool {
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+    system.process(pid).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+
+    const LOCK_PATH: &str = ".minegit/lock";
+
+    #[test]
+    fn takes_over_a_lock_left_by_a_dead_process() {
+        let world = temp_dir("lock-stale");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        write_world_file(&world, LOCK_PATH, child.id().to_string().as_bytes());
+
+        let lock = RepoLock::acquire(&world, false).unwrap();
+        assert_eq!(
+            read_world_file(&world, LOCK_PATH),
+            std::process::id().to_string().as_bytes()
+        );
+        drop(lock);
+        assert!(!fs_utils::is_path_exists(&format!("{world}/{LOCK_PATH}")));
+    }
+
+    #[test]
+    fn refuses_a_lock_held_by_a_running_process() {
+        let world = temp_dir("lock-held");
+        fs_utils::make_dir(&format!("{world}/{DIRECTORY_NAME}")).unwrap();
+
+        let lock = RepoLock::acquire(&world, false).unwrap();
+        assert!(RepoLock::acquire(&world, false).is_err());
+        drop(lock);
+        assert!(RepoLock::acquire(&world, false).is_ok());
+    }
+
+    #[test]
+    fn data_lock_is_shared_by_readers_only() {
+        let world = temp_dir("lock-data");
+        fs_utils::make_dir(&format!("{world}/{DIRECTORY_NAME}")).unwrap();
+        let path = format!("{world}/{DIRECTORY_NAME}/{DATA_LOCK_FILE_NAME}");
+        let is_free = || {
+            let file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.try_lock().is_ok()
+        };
+
+        let readers = [
+            DataLock::shared(&world).unwrap(),
+            DataLock::shared(&world).unwrap(),
+        ];
+        assert!(!is_free());
+        drop(readers);
+        assert!(is_free());
+
+        let writer = DataLock::exclusive(&world).unwrap();
+        let file = OpenOptions::new().read(true).open(&path).unwrap();
+        assert!(file.try_lock_shared().is_err());
+        drop(writer);
+        assert!(file.try_lock_shared().is_ok());
+    }
+}
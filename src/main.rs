@@ -1,17 +1,36 @@
 use std::env;
 
 mod args;
+mod bundle;
 mod cli;
 mod committer;
+mod config;
+mod exporter;
+mod hooks;
 mod ignore_filter;
+mod importer;
 mod initializer;
+mod journal;
+mod lock;
+mod migrate;
+mod objects;
+mod protocol;
+mod rcon;
+mod remote;
+mod renames;
+mod retention;
 mod savefiles;
+mod session_lock;
+mod sync;
 mod utils;
+mod watcher;
 
 mod recover {
     pub mod diff;
     pub mod diff_gen;
+    #[allow(clippy::module_inception)]
     pub mod recover;
+    pub mod rolling_diff;
     pub mod snapshot;
     pub mod test;
 }
@@ -19,4 +38,4 @@ mod recover {
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
     cli::run();
-}
\ No newline at end of file
+}
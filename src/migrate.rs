@@ -0,0 +1,536 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use bitcode::Decode;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use zstd::{decode_all, encode_all};
+
+use crate::lock::DataLock;
+use crate::recover::snapshot::SnapshotHeader;
+use crate::savefiles::{
+    Commit, CommitInfo, FileInfo, COMMITS_FILE_NAME, COMMITS_INFO_FILE_NAME, COMMITS_INFO_MAGIC,
+    COMMITS_MAGIC, DATA_DIRECTORY_NAME, DIRECTORY_NAME, FORMAT_FILE_NAME, FORMAT_VERSION,
+    MIGRATE_DIRECTORY_NAME, OBJECTS_INDEX_FILE_NAME, PACKAGE_MAGIC,
+};
+use crate::utils::fs_utils;
+
+// Upgrades of repositories created by older versions of MineGit.
+//
+// Format versions:
+// 0. Repositories created before the format file, they have no magics. Commit infos key
+//    files by their path in a 128 byte array, cutting longer paths off, and store the hex
+//    SHA-256 of a file in a 256 byte array. Packages are named by the full path, which
+//    the upgrade takes the cut off paths from.
+// 1. Commit infos store paths as strings and raw digests. The commits file, the commit
+//    info file and packages start with a magic, which moves every snapshot of a package.
+//    Snapshots mark the encoding of their diff commands in their flags, so the ones of
+//    version 0 are read as they are.
+//
+// An upgrade step builds the files it changes in `.minegit/migrate`, like a prune, and moves
+// them over the old ones once complete, which is marked by a `ready` file. An interrupted
+// step is therefore either restarted or finished by the next upgrade.
+
+// Fixed instead of the configured level, so replicas of a repository upgrade to the same
+// commits and can still be synced
+const COMPRESSION: i32 = 3;
+const READY_FILE_NAME: &str = "ready";
+
+#[derive(Decode)]
+#[cfg_attr(test, derive(bitcode::Encode))]
+struct LegacyCommitInfo {
+    id: u32,
+    file_info: HashMap<[u8; 128], LegacyFileInfo>,
+}
+
+#[derive(Decode)]
+#[cfg_attr(test, derive(bitcode::Encode))]
+struct LegacyFileInfo {
+    hash: [u8; 256],
+    package_pos: u64,
+}
+
+// Format version of the repository
+pub fn read_format_version(target_path: &str) -> Result<u32, Box<dyn Error>> {
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+    let format_path = fs_utils::build_path([&root_path, FORMAT_FILE_NAME])?;
+    if fs_utils::is_path_exists(&format_path) {
+        let mut content = String::new();
+        fs_utils::read_file(&format_path)?.read_to_string(&mut content)?;
+        return content
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid repository format version {}", content.trim()).into());
+    }
+
+    Ok(0)
+}
+
+pub fn write_format_version(root_path: &str, version: u32) -> io::Result<()> {
+    let path = fs_utils::build_path([root_path, FORMAT_FILE_NAME])?;
+    fs_utils::write_file(&path, format!("{version}\n").as_bytes())?.sync_all()
+}
+
+// Fails unless the repository has the format of this version, commands never read or
+// write a repository of another format
+pub fn check_format(target_path: &str) -> Result<(), Box<dyn Error>> {
+    let ready_path = fs_utils::build_path([
+        target_path,
+        DIRECTORY_NAME,
+        MIGRATE_DIRECTORY_NAME,
+        READY_FILE_NAME,
+    ])?;
+    if fs_utils::is_path_exists(&ready_path) {
+        return Err(
+            "An upgrade of the repository was interrupted, run `minegit upgrade` to finish it"
+                .into(),
+        );
+    }
+
+    let version = read_format_version(target_path)?;
+    if version < FORMAT_VERSION {
+        return Err(format!(
+            "The repository has format version {version}, run `minegit upgrade` to upgrade it to version {FORMAT_VERSION}"
+        )
+        .into());
+    }
+    if version > FORMAT_VERSION {
+        return Err(format!(
+            "The repository has format version {version}, this MineGit supports up to version {FORMAT_VERSION}"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Upgrades the repository to the current format one version at a time, the caller holds
+// the repository lock. Returns the version it had before.
+pub fn upgrade(target_path: &str) -> Result<u32, Box<dyn Error>> {
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+    let work_path = fs_utils::build_path([&root_path, MIGRATE_DIRECTORY_NAME])?;
+    let ready_path = fs_utils::build_path([&work_path, READY_FILE_NAME])?;
+    let _data_lock = DataLock::exclusive(target_path)?;
+
+    // Finish a step that was interrupted while its files were moved
+    if fs_utils::is_path_exists(&ready_path) {
+        swap_in(&root_path, &work_path)?;
+    }
+
+    let initial = read_format_version(target_path)?;
+    if initial > FORMAT_VERSION {
+        return Err(format!(
+            "The repository has format version {initial}, this MineGit supports up to version {FORMAT_VERSION}"
+        )
+        .into());
+    }
+
+    for version in initial..FORMAT_VERSION {
+        // Left over from a step interrupted before it was complete
+        if fs_utils::is_path_exists(&work_path) {
+            fs_utils::remove_dir_all(&work_path)?;
+        }
+        fs::create_dir_all(&work_path)?;
+
+        match version {
+            0 => upgrade_from_0(target_path, &work_path)?,
+            _ => unreachable!(),
+        }
+        write_format_version(&work_path, version + 1)?;
+        fs_utils::write_file(&ready_path, &[])?.sync_all()?;
+        swap_in(&root_path, &work_path)?;
+    }
+    Ok(initial)
+}
+
+// Moves the files built by a step over those of the repository. Files moved before an
+// interruption are already in place.
+fn swap_in(root_path: &str, work_path: &str) -> Result<(), Box<dyn Error>> {
+    for entry in fs_utils::get_all_files_in_directory(work_path)? {
+        let path = entry.path();
+        let relative = path.strip_prefix(work_path)?;
+        if relative == Path::new(READY_FILE_NAME) {
+            continue;
+        }
+        fs_utils::move_file(
+            path.to_str().ok_or("Invalid path")?,
+            &fs_utils::build_path([Path::new(root_path), relative])?,
+        )?;
+    }
+
+    // Rebuilt from the new commit infos on the next use
+    let index_path = fs_utils::build_path([root_path, OBJECTS_INDEX_FILE_NAME])?;
+    if fs_utils::is_path_exists(&index_path) {
+        fs_utils::remove_file(&index_path)?;
+    }
+    fs_utils::remove_dir_all(work_path)?;
+    Ok(())
+}
+
+// Converts the commit infos to string paths and raw digests and adds the magics. Every
+// snapshot of a package moves by the length of the magic, so the positions in commit infos,
+// snapshot headers and references are moved as well.
+fn upgrade_from_0(target_path: &str, work_path: &str) -> Result<(), Box<dyn Error>> {
+    let shift = |pos: u64| match pos {
+        u64::MAX => pos,
+        pos => pos + PACKAGE_MAGIC.len() as u64,
+    };
+
+    let info_path = fs_utils::build_path([target_path, DIRECTORY_NAME, COMMITS_INFO_FILE_NAME])?;
+    let package_paths = read_package_paths(target_path)?;
+    let mut commits = read_commits_without_magic(target_path)?;
+    let mut infos = Vec::with_capacity(commits.len());
+    if !commits.is_empty() {
+        let mut info_file = fs_utils::read_file(&info_path)?;
+        for commit in &commits {
+            let mut info = convert(read_legacy_info(&mut info_file, commit)?, &package_paths)?;
+            for file_info in info.file_info.values_mut() {
+                file_info.package_pos = shift(file_info.package_pos);
+            }
+            infos.push(info);
+        }
+    }
+    write_commit_infos(work_path, &mut commits, &infos)?;
+
+    let commits_path = fs_utils::build_path([work_path, COMMITS_FILE_NAME])?;
+    let mut commits_file = fs_utils::write_file(&commits_path, COMMITS_MAGIC)?;
+    commits_file.write_all(bytemuck::cast_slice(&commits))?;
+    commits_file.sync_all()?;
+
+    let data_path = fs_utils::build_path([target_path, DIRECTORY_NAME, DATA_DIRECTORY_NAME])?;
+    if !fs_utils::is_path_exists(&data_path) {
+        return Ok(());
+    }
+    for entry in fs_utils::get_all_files_in_directory(&data_path)? {
+        let path = entry.path();
+        let new_path = fs_utils::build_path([
+            Path::new(work_path),
+            Path::new(DATA_DIRECTORY_NAME),
+            path.strip_prefix(&data_path)?,
+        ])?;
+        if let Some(parent) = Path::new(&new_path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let len = entry.metadata()?.len();
+        let mut package = BufReader::new(File::open(&path)?);
+        let mut new_package = BufWriter::new(File::create(&new_path)?);
+        new_package.write_all(PACKAGE_MAGIC)?;
+        while package.stream_position()? < len {
+            let mut header = SnapshotHeader::deserialize(&mut package)?;
+            header.depend_on = shift(header.depend_on);
+            header.serialize(&mut new_package)?;
+
+            // A reference starts with the position of the snapshot it points to
+            let mut payload_len = header.payload_len;
+            if header.is_reference {
+                let pos = package.read_u64::<BigEndian>()?;
+                new_package.write_u64::<BigEndian>(shift(pos))?;
+                payload_len = payload_len.saturating_sub(8);
+            }
+            let copied = io::copy(&mut (&mut package).take(payload_len), &mut new_package)?;
+            if copied != payload_len {
+                return Err(format!("The package {} is truncated", path.display()).into());
+            }
+        }
+        new_package.into_inner()?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Commits of a repository whose commits file has no magic yet
+fn read_commits_without_magic(target_path: &str) -> io::Result<Vec<Commit>> {
+    let path = fs_utils::build_path([target_path, DIRECTORY_NAME, COMMITS_FILE_NAME])?;
+    if !fs_utils::is_path_exists(&path) {
+        return Ok(Vec::new());
+    }
+    let mut data = Vec::new();
+    fs_utils::read_to_end(&path, &mut data)?;
+    Ok(data
+        .chunks_exact(std::mem::size_of::<Commit>())
+        .map(bytemuck::pod_read_unaligned)
+        .collect())
+}
+
+// Writes the commit infos into a new commit info file and points the commits to them
+fn write_commit_infos(
+    work_path: &str,
+    commits: &mut [Commit],
+    infos: &[CommitInfo],
+) -> Result<(), Box<dyn Error>> {
+    let info_path = fs_utils::build_path([work_path, COMMITS_INFO_FILE_NAME])?;
+    let mut info_file = fs_utils::write_file(&info_path, COMMITS_INFO_MAGIC)?;
+    for (commit, info) in commits.iter_mut().zip(infos) {
+        let compressed = encode_all(Cursor::new(fs_utils::encode_to_bytes(info)), COMPRESSION)?;
+        commit.info_pos = info_file.stream_position()?;
+        commit.info_length = compressed.len();
+        info_file.write_all(&compressed)?;
+    }
+    info_file.sync_all()?;
+    Ok(())
+}
+
+fn read_legacy_info(file: &mut File, commit: &Commit) -> Result<LegacyCommitInfo, Box<dyn Error>> {
+    file.seek(SeekFrom::Start(commit.info_pos))?;
+    let mut compressed = vec![0u8; commit.info_length];
+    file.read_exact(&mut compressed)?;
+    Ok(bitcode::decode(&decode_all(Cursor::new(compressed))?)?)
+}
+
+// Paths of the files with a package, keyed by their first 128 bytes as legacy commit infos
+// store them
+fn read_package_paths(target_path: &str) -> Result<HashMap<Vec<u8>, Vec<String>>, Box<dyn Error>> {
+    let mut package_paths: HashMap<Vec<u8>, Vec<String>> = HashMap::new();
+    let data_path = fs_utils::build_path([target_path, DIRECTORY_NAME, DATA_DIRECTORY_NAME])?;
+    if !fs_utils::is_path_exists(&data_path) {
+        return Ok(package_paths);
+    }
+    for entry in fs_utils::get_all_files_in_directory(&data_path)? {
+        let path = entry.path();
+        let relative = path
+            .strip_prefix(&data_path)?
+            .to_str()
+            .ok_or("Invalid path")?;
+        if let Some(path) = relative.strip_suffix(".pkg") {
+            let key = path.as_bytes()[..path.len().min(128)].to_vec();
+            package_paths.entry(key).or_default().push(path.to_string());
+        }
+    }
+    Ok(package_paths)
+}
+
+// Version 1 cut paths off at 128 bytes, the full path is that of the only package starting
+// with them
+fn legacy_path(
+    id: u32,
+    key: &[u8; 128],
+    package_paths: &HashMap<Vec<u8>, Vec<String>>,
+) -> Result<String, Box<dyn Error>> {
+    if key.contains(&0) {
+        return Ok(fixed_bytes_to_str(key));
+    }
+    match package_paths.get(key.as_slice()).map(Vec::as_slice) {
+        Some([path]) => Ok(path.clone()),
+        Some(paths) => Err(format!(
+            "Commit {id} has a path cut off at 128 bytes that is the start of several files: {}. Rename all but one of their packages in .minegit/data to upgrade",
+            paths.join(", ")
+        )
+        .into()),
+        None => Err(format!(
+            "Commit {id} has a path cut off at 128 bytes without a package: {}",
+            fixed_bytes_to_str(key)
+        )
+        .into()),
+    }
+}
+
+fn convert(
+    legacy: LegacyCommitInfo,
+    package_paths: &HashMap<Vec<u8>, Vec<String>>,
+) -> Result<CommitInfo, Box<dyn Error>> {
+    let mut file_info = BTreeMap::new();
+    for (path, info) in legacy.file_info {
+        let path = legacy_path(legacy.id, &path, package_paths)?;
+        let hash = digest_from_hex(&fixed_bytes_to_str(&info.hash))
+            .ok_or_else(|| format!("Commit {} has an invalid hash for {path}", legacy.id))?;
+        file_info.insert(
+            path,
+            FileInfo {
+                hash,
+                package_pos: info.package_pos,
+            },
+        );
+    }
+    Ok(CommitInfo {
+        id: legacy.id,
+        file_info,
+    })
+}
+
+fn fixed_bytes_to_str<const N: usize>(bytes: &[u8; N]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(N);
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn digest_from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::committer;
+    use crate::savefiles::IGNORE_FILE_NAME;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+    use bytemuck::Zeroable;
+    use sha2::{Digest, Sha256};
+
+    // Header of a snapshot as version 0 writes it, without flags for the encoding
+    fn legacy_header(package: &mut Vec<u8>, depend_on: u64, payload_len: u64, file_len: u64) {
+        package.write_u64::<BigEndian>(depend_on).unwrap();
+        package.write_u64::<BigEndian>(payload_len).unwrap();
+        package.write_u64::<BigEndian>(file_len).unwrap();
+        package.write_u8(0).unwrap();
+    }
+
+    // Package of a file stored in full in the first commit and as a delta in the second,
+    // with the fixed-size diff commands of version 0
+    fn legacy_package(v1: &[u8], v2: &[u8]) -> Vec<u8> {
+        let mut package = Vec::new();
+        legacy_header(&mut package, u64::MAX, 4 + v1.len() as u64, v1.len() as u64);
+        package
+            .write_u32::<BigEndian>(1 << 31 | v1.len() as u32)
+            .unwrap();
+        package.extend_from_slice(v1);
+
+        // Copies the first half of the first version, which the second starts with
+        let copied = v1.len() / 2;
+        assert_eq!(v1[..copied], v2[..copied]);
+        let inserted = &v2[copied..];
+        legacy_header(&mut package, 0, 12 + inserted.len() as u64, v2.len() as u64);
+        package.write_u32::<BigEndian>(copied as u32).unwrap();
+        package.write_u32::<BigEndian>(0).unwrap();
+        package
+            .write_u32::<BigEndian>(1 << 31 | inserted.len() as u32)
+            .unwrap();
+        package.extend_from_slice(inserted);
+        package
+    }
+
+    fn legacy_info(id: u32, files: &[(&str, &[u8], u64)]) -> LegacyCommitInfo {
+        let mut file_info = HashMap::new();
+        for &(path, data, package_pos) in files {
+            let mut key = [0u8; 128];
+            let len = path.len().min(128);
+            key[..len].copy_from_slice(&path.as_bytes()[..len]);
+            let digest: [u8; 32] = Sha256::digest(data).into();
+            let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+            let mut hash = [0u8; 256];
+            hash[..64].copy_from_slice(hex.as_bytes());
+            file_info.insert(key, LegacyFileInfo { hash, package_pos });
+        }
+        LegacyCommitInfo { id, file_info }
+    }
+
+    #[test]
+    fn upgrades_a_repository_of_version_0() {
+        let world = temp_dir("migrate-legacy");
+        let long = format!("{}/r.0.0.mca", "region".repeat(30));
+        let level = [
+            b"level v1".repeat(40),
+            [&b"level v1".repeat(20)[..], &b"level v2".repeat(30)].concat(),
+        ];
+        let region = [
+            vec![7u8; 3000],
+            [vec![7u8; 1500], (0..4000u32).map(|i| i as u8).collect()].concat(),
+        ];
+
+        let data_path = format!("{world}/{DIRECTORY_NAME}/{DATA_DIRECTORY_NAME}");
+        write_world_file(
+            &data_path,
+            "level.dat.pkg",
+            &legacy_package(&level[0], &level[1]),
+        );
+        write_world_file(
+            &data_path,
+            &format!("{long}.pkg"),
+            &legacy_package(&region[0], &region[1]),
+        );
+
+        let mut commits = Vec::new();
+        let mut infos = Vec::new();
+        for version in 0..2 {
+            let pos = |v1: &[u8]| match version {
+                0 => 0,
+                _ => SnapshotHeader::SERIZIZED_SIZE as u64 + 4 + v1.len() as u64,
+            };
+            let info = legacy_info(
+                version as u32,
+                &[
+                    ("level.dat", &level[version], pos(&level[0])),
+                    (&long, &region[version], pos(&region[0])),
+                ],
+            );
+            let compressed = encode_all(Cursor::new(bitcode::encode(&info)), 0).unwrap();
+            let mut commit = Commit::zeroed();
+            commit.id = version as u32;
+            commit.parent_id = 0;
+            commit.info_pos = infos.len() as u64;
+            commit.info_length = compressed.len();
+            commits.push(commit);
+            infos.extend(compressed);
+        }
+        let root_path = format!("{world}/{DIRECTORY_NAME}");
+        write_world_file(
+            &root_path,
+            IGNORE_FILE_NAME,
+            b".git/*\ntarget/*\n.minegit/*\nsrc/*",
+        );
+        write_world_file(
+            &root_path,
+            COMMITS_FILE_NAME,
+            bytemuck::cast_slice(&commits),
+        );
+        write_world_file(&root_path, COMMITS_INFO_FILE_NAME, &infos);
+        committer::write_head(&world, 1).unwrap();
+
+        assert!(check_format(&world).is_err());
+        assert_eq!(upgrade(&world).unwrap(), 0);
+        check_format(&world).unwrap();
+        assert_eq!(upgrade(&world).unwrap(), FORMAT_VERSION);
+
+        for version in [0, 1] {
+            committer::restore(&world, version as u32, Vec::new(), true).unwrap();
+            assert_eq!(read_world_file(&world, "level.dat"), level[version]);
+            assert_eq!(read_world_file(&world, &long), region[version]);
+        }
+    }
+
+    #[test]
+    fn converts_fixed_bytes() {
+        let mut path = [0u8; 128];
+        path[..9].copy_from_slice(b"level.dat");
+        let mut hash = [0u8; 256];
+        hash[..64].copy_from_slice("00FF".repeat(16).as_bytes());
+        let legacy = LegacyCommitInfo {
+            id: 3,
+            file_info: HashMap::from([(
+                path,
+                LegacyFileInfo {
+                    hash,
+                    package_pos: 42,
+                },
+            )]),
+        };
+
+        let info = convert(legacy, &HashMap::new()).unwrap();
+        assert_eq!(info.id, 3);
+        let file_info = info.file_info["level.dat"];
+        assert_eq!(file_info.hash, [0x00, 0xFF].repeat(16).as_slice());
+        assert_eq!(file_info.package_pos, 42);
+    }
+
+    #[test]
+    fn recovers_paths_cut_off_at_128_bytes() {
+        let long = format!("{}/r.0.0.mca", "dir".repeat(50));
+        let key: [u8; 128] = long.as_bytes()[..128].try_into().unwrap();
+        let mut package_paths = HashMap::from([(key.to_vec(), vec![long.clone()])]);
+        assert_eq!(legacy_path(1, &key, &package_paths).unwrap(), long);
+
+        // Another file starting with the same 128 bytes makes the path ambiguous
+        package_paths
+            .get_mut(key.as_slice())
+            .unwrap()
+            .push(format!("{}/r.1.0.mca", "dir".repeat(50)));
+        assert!(legacy_path(1, &key, &package_paths).is_err());
+        assert!(legacy_path(1, &key, &HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Seek};
+
+use bitcode::{Decode, Encode};
+
+use crate::committer;
+use crate::protocol;
+use crate::savefiles::{Commit, CommitInfo, DIRECTORY_NAME, OBJECTS_INDEX_FILE_NAME};
+use crate::utils::fs_utils;
+
+// Content-addressed index of the stored file versions.
+//
+// Packages are per path, so the same content at another path (a copied world, a renamed
+// file, a datapack present in every dimension) would be stored again. The index maps the
+// SHA-256 of every stored version to the package and position of a snapshot recovering it.
+// A file whose content is already stored gets a small reference snapshot in its own package
+// instead of a copy, see `SnapshotHeader::save_reference`.
+//
+// The index is derived from the commit infos and only cached in `.minegit/objects.index`,
+// one frame per commit with the objects it added. Frames are checked against the commits
+// when loading, so commits received from other repositories or a prune are picked up by
+// rebuilding the part that no longer matches.
+
+#[derive(Debug, Clone)]
+pub struct ObjectLocation {
+    pub path: String,
+    pub pos: u64,
+}
+
+#[derive(Encode, Decode)]
+struct IndexFrame {
+    id: u32,
+    timestamp: i64,
+    info_pos: u64,
+    info_length: u64,
+    objects: Vec<([u8; 32], String, u64)>,
+}
+
+pub struct ObjectIndex {
+    path: String,
+    objects: HashMap<[u8; 32], ObjectLocation>,
+}
+
+impl ObjectIndex {
+    // Loads the cached index and adds the commits it does not cover yet
+    pub fn load(target_path: &str) -> Result<Self, Box<dyn Error>> {
+        let path = fs_utils::build_path([target_path, DIRECTORY_NAME, OBJECTS_INDEX_FILE_NAME])?;
+        let commits = committer::read_all_commits(target_path)?;
+        let mut index = Self {
+            path,
+            objects: HashMap::new(),
+        };
+
+        let mut covered = 0;
+        if fs_utils::is_path_exists(&index.path) {
+            let mut reader = BufReader::new(File::open(&index.path)?);
+            let mut valid_len = 0;
+            // A torn or stale frame ends the usable part of the cache
+            while let Ok(Some(frame)) = protocol::read_frame::<_, IndexFrame>(&mut reader) {
+                match commits.get(covered) {
+                    Some(commit) if frame.matches(commit) => {}
+                    _ => break,
+                }
+                for (digest, path, pos) in frame.objects {
+                    index.objects.insert(digest, ObjectLocation { path, pos });
+                }
+                covered += 1;
+                valid_len = reader.stream_position()?;
+            }
+            if fs_utils::get_file_size(&index.path)? != valid_len {
+                fs_utils::truncate_file(&index.path, valid_len)?;
+            }
+        }
+
+        for commit in &commits[covered..] {
+            let info = committer::get_commit_info_by_id(target_path, commit.id)?;
+            index.add_commit(commit, &info)?;
+        }
+        Ok(index)
+    }
+
+    // Location of a stored version with the given hash
+    pub fn get(&self, hash: &[u8; 32]) -> Option<&ObjectLocation> {
+        self.objects.get(hash)
+    }
+
+    // Adds the versions a commit stored and appends them to the cache
+    pub fn add_commit(&mut self, commit: &Commit, info: &CommitInfo) -> Result<(), Box<dyn Error>> {
+        // Sorted by path, so the first path storing a content is the one that is kept
+        let mut objects = Vec::new();
+        for (path, file_info) in &info.file_info {
+            if self.objects.contains_key(&file_info.hash) {
+                continue;
+            }
+            let location = ObjectLocation {
+                path: path.clone(),
+                pos: file_info.package_pos,
+            };
+            objects.push((file_info.hash, location.path.clone(), location.pos));
+            self.objects.insert(file_info.hash, location);
+        }
+
+        let frame = IndexFrame {
+            id: commit.id,
+            timestamp: commit.timestamp,
+            info_pos: commit.info_pos,
+            info_length: commit.info_length as u64,
+            objects,
+        };
+        let mut data = Vec::new();
+        protocol::write_frame(&mut data, &frame)?;
+        fs_utils::append_file(&self.path, &data)?;
+        Ok(())
+    }
+}
+
+impl IndexFrame {
+    fn matches(&self, commit: &Commit) -> bool {
+        self.id == commit.id
+            && self.timestamp == commit.timestamp
+            && self.info_pos == commit.info_pos
+            && self.info_length == commit.info_length as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter;
+    use crate::initializer;
+    use crate::savefiles::DATA_DIRECTORY_NAME;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+
+    #[test]
+    fn stores_copied_content_once() {
+        let world = temp_dir("objects-copy");
+        // Incompressible, so a second copy would be as large as the first one
+        let mut seed = 1u32;
+        let data: Vec<u8> = (0..50000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        write_world_file(&world, "datapacks/pack.zip", &data);
+        initializer::init(&world).unwrap();
+
+        write_world_file(&world, "DIM-1/datapacks/pack.zip", &data);
+        committer::add_commit(&world, "copied", Vec::new()).unwrap();
+
+        let package_len = |path: &str| {
+            let path = format!("{DIRECTORY_NAME}/{DATA_DIRECTORY_NAME}/{path}.pkg");
+            fs_utils::get_file_size(fs_utils::build_path([&world, &path]).unwrap()).unwrap()
+        };
+        assert!(package_len("datapacks/pack.zip") > 50000);
+        assert!(package_len("DIM-1/datapacks/pack.zip") < 1000);
+
+        let dest = temp_dir("objects-copy-checkout");
+        exporter::checkout(&world, 1, &dest, &[], &[], false).unwrap();
+        assert_eq!(read_world_file(&dest, "DIM-1/datapacks/pack.zip"), data);
+
+        // The cache is rebuilt from the commits when it is torn or missing
+        let hash = fs_utils::file_hash(&format!("{world}/datapacks/pack.zip")).unwrap();
+        let index_path =
+            fs_utils::build_path([&world, DIRECTORY_NAME, OBJECTS_INDEX_FILE_NAME]).unwrap();
+        fs_utils::append_file(&index_path, b"torn").unwrap();
+        let index = ObjectIndex::load(&world).unwrap();
+        assert_eq!(index.get(&hash).unwrap().path, "datapacks/pack.zip");
+        fs_utils::remove_file(&index_path).unwrap();
+        let index = ObjectIndex::load(&world).unwrap();
+        assert_eq!(index.get(&hash).unwrap().path, "datapacks/pack.zip");
+        assert!(fs_utils::is_path_exists(&index_path));
+    }
+}
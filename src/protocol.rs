@@ -0,0 +1,812 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use bitcode::{Decode, DecodeOwned, Encode};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::committer;
+use crate::journal;
+use crate::lock::RepoLock;
+use crate::migrate;
+use crate::savefiles::Commit;
+use crate::sync::{self, CommitPack, RepoState, SnapshotData};
+use crate::utils::fs_utils;
+
+// Wire protocol between `minegit serve` and a client pushing or fetching.
+//
+// Messages are bitcode-encoded `Message` values, each prefixed by its length as a big-endian
+// u32. The client starts with `Hello` and asks for the `State` of the served repository.
+// Commits are then sent one `Pack` at a time and each one is applied in its own journaled
+// transaction, so an interrupted transfer is resumed from the first missing commit by
+// running the same command again. The bytes of the snapshots of a pack follow it as frames
+// of at most `CHUNK_LEN` bytes, which the receiver collects in `.minegit/incoming`.
+//
+// Fetch: client `Fetch` -> server `Pack`... `Done`
+// Push:  client `Pack` -> server `Ack`, repeated, then client `Finish` -> server `Done`
+// Any request may be answered by `Error`.
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Anything bigger is treated as corrupted data
+const MAX_MESSAGE_LEN: u32 = 1 << 30;
+
+// Size of the frames snapshot bytes are sent in
+const CHUNK_LEN: u64 = 8 << 20;
+
+// A TCP peer silent for this long is disconnected, so it does not hold the repository lock
+pub const TCP_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Encode, Decode)]
+enum Message {
+    Hello {
+        version: u32,
+    },
+    GetState,
+    State(WireState),
+    Fetch {
+        from: u32,
+        packages: Vec<(String, u64)>,
+    },
+    Pack(WirePack),
+    Ack {
+        id: u32,
+    },
+    Finish {
+        head: Option<u32>,
+        stash_ids: Vec<u32>,
+    },
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Encode, Decode)]
+struct WireState {
+    commits: Vec<WireCommit>,
+    head: Option<u32>,
+    stash_ids: Vec<u32>,
+    packages: Vec<(String, u64)>,
+}
+
+// `Commit` is stored with its in-memory layout, it is sent field by field instead
+#[derive(Debug, Encode, Decode)]
+pub struct WireCommit {
+    tag: Vec<u8>,
+    id: u32,
+    timestamp: i64,
+    parent_id: u32,
+    info_length: u64,
+}
+
+// Path, position and length of the snapshots, their bytes follow the pack
+#[derive(Debug, Encode, Decode)]
+pub struct WirePack {
+    commit: WireCommit,
+    info: Vec<u8>,
+    snapshots: Vec<(String, u64, u64)>,
+}
+
+impl From<&Commit> for WireCommit {
+    fn from(commit: &Commit) -> Self {
+        Self {
+            tag: commit.tag.to_vec(),
+            id: commit.id,
+            timestamp: commit.timestamp,
+            parent_id: commit.parent_id,
+            info_length: commit.info_length as u64,
+        }
+    }
+}
+
+impl WireCommit {
+    pub fn to_commit(&self) -> Result<Commit, Box<dyn Error>> {
+        Ok(Commit {
+            tag: self
+                .tag
+                .as_slice()
+                .try_into()
+                .map_err(|_| "Received a commit with an invalid tag")?,
+            id: self.id,
+            timestamp: self.timestamp,
+            parent_id: self.parent_id,
+            // Set by the receiver when the commit info is appended
+            info_pos: 0,
+            info_length: self.info_length.try_into()?,
+        })
+    }
+}
+
+impl From<&CommitPack> for WirePack {
+    fn from(pack: &CommitPack) -> Self {
+        Self {
+            commit: WireCommit::from(&pack.commit),
+            info: pack.info.clone(),
+            snapshots: pack
+                .snapshots
+                .iter()
+                .map(|snapshot| (snapshot.path.clone(), snapshot.pos, snapshot.len))
+                .collect(),
+        }
+    }
+}
+
+impl WireState {
+    fn from_state(state: RepoState) -> Self {
+        Self {
+            commits: state.commits.iter().map(WireCommit::from).collect(),
+            head: state.head,
+            stash_ids: state.stash_ids,
+            packages: state.packages.into_iter().collect(),
+        }
+    }
+
+    fn commits(&self) -> Result<Vec<Commit>, Box<dyn Error>> {
+        self.commits.iter().map(WireCommit::to_commit).collect()
+    }
+}
+
+pub struct Connection<R: Read, W: Write> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+}
+
+impl<R: Read, W: Write> Connection<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        }
+    }
+
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        write_frame(&mut self.writer, message)?;
+        self.writer.flush()
+    }
+
+    fn send_pack(&mut self, pack: &CommitPack) -> Result<(), Box<dyn Error>> {
+        write_frame(&mut self.writer, &Message::Pack(WirePack::from(pack)))?;
+        write_snapshots(&mut self.writer, pack)?;
+        Ok(self.writer.flush()?)
+    }
+
+    // Returns None once the other side closed the connection
+    fn receive(&mut self) -> Result<Option<Message>, Box<dyn Error>> {
+        read_frame(&mut self.reader)
+    }
+
+    // Receives a message, a closed connection or an `Error` message is an error
+    fn expect(&mut self) -> Result<Message, Box<dyn Error>> {
+        match self.receive()? {
+            Some(Message::Error { message }) => Err(format!("Remote error: {message}").into()),
+            Some(message) => Ok(message),
+            None => Err("The remote closed the connection".into()),
+        }
+    }
+
+    fn handshake(&mut self) -> Result<WireState, Box<dyn Error>> {
+        self.send(&Message::Hello {
+            version: PROTOCOL_VERSION,
+        })?;
+        match self.expect()? {
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+            } => {}
+            Message::Hello { version } => {
+                return Err(format!(
+                    "The remote speaks protocol version {version}, this MineGit speaks {PROTOCOL_VERSION}"
+                )
+                .into())
+            }
+            message => return Err(unexpected(&message)),
+        }
+
+        self.send(&Message::GetState)?;
+        match self.expect()? {
+            Message::State(state) => Ok(state),
+            message => Err(unexpected(&message)),
+        }
+    }
+
+    // Sends the commits the remote misses and moves its head to the local head,
+    // returns the number of sent commits
+    pub fn push(&mut self, target_path: &str) -> Result<u32, Box<dyn Error>> {
+        let remote = self.handshake()?;
+        let local = RepoState::read(target_path)?;
+
+        let remote_commits = remote.commits()?;
+        if remote_commits.len() > local.commits.len() {
+            return Err(
+                "The remote has commits that are not present locally, fetch them first".into(),
+            );
+        }
+        let missing = sync::missing_commits(&local.commits, &remote_commits)?;
+        let count = missing.len() as u32;
+
+        let mut package_lens = remote.packages.into_iter().collect();
+        for id in missing {
+            let pack = sync::read_commit_pack(target_path, id, &mut package_lens)?;
+            self.send_pack(&pack)?;
+            match self.expect()? {
+                Message::Ack { id: acked } if acked == id => {}
+                message => return Err(unexpected(&message)),
+            }
+        }
+
+        self.send(&Message::Finish {
+            head: local.head,
+            stash_ids: local.stash_ids,
+        })?;
+        match self.expect()? {
+            Message::Done => Ok(count),
+            message => Err(unexpected(&message)),
+        }
+    }
+
+    // Receives the commits missing locally, returns their number and the head of the remote
+    pub fn fetch(&mut self, target_path: &str) -> Result<(u32, Option<u32>), Box<dyn Error>> {
+        let remote = self.handshake()?;
+        let local = RepoState::read(target_path)?;
+
+        let missing = sync::missing_commits(&remote.commits()?, &local.commits)?;
+        self.send(&Message::Fetch {
+            from: missing.start,
+            packages: local.packages.into_iter().collect(),
+        })?;
+
+        let mut count = 0;
+        loop {
+            match self.expect()? {
+                Message::Pack(pack) => {
+                    let pack = read_snapshots(&mut self.reader, pack, target_path)?;
+                    sync::apply_commit_pack(target_path, &pack)?;
+                    count += 1;
+                }
+                Message::Done => break,
+                message => return Err(unexpected(&message)),
+            }
+        }
+        sync::remove_incoming(target_path)?;
+
+        sync::merge_stash_ids(target_path, &remote.stash_ids)?;
+        Ok((count, remote.head))
+    }
+
+    // Answers requests until the client disconnects
+    pub fn serve(&mut self, target_path: &str, read_only: bool) -> Result<(), Box<dyn Error>> {
+        let served = self.answer_all(target_path, read_only);
+        sync::remove_incoming(target_path)?;
+        served
+    }
+
+    fn answer_all(&mut self, target_path: &str, read_only: bool) -> Result<(), Box<dyn Error>> {
+        while let Some(message) = self.receive()? {
+            if let Err(e) = self.answer(target_path, message, read_only) {
+                // The client may already be gone
+                let _ = self.send(&Message::Error {
+                    message: e.to_string(),
+                });
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn answer(
+        &mut self,
+        target_path: &str,
+        message: Message,
+        read_only: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        match message {
+            Message::Hello { .. } => self.send(&Message::Hello {
+                version: PROTOCOL_VERSION,
+            })?,
+            Message::GetState => {
+                let state = RepoState::read(target_path)?;
+                self.send(&Message::State(WireState::from_state(state)))?;
+            }
+            Message::Fetch { from, packages } => {
+                let mut package_lens = packages.into_iter().collect();
+                for id in from..committer::get_commit_count(target_path)? {
+                    let pack = sync::read_commit_pack(target_path, id, &mut package_lens)?;
+                    self.send_pack(&pack)?;
+                }
+                self.send(&Message::Done)?;
+            }
+            Message::Pack(pack) => {
+                if read_only {
+                    return Err("The repository is served read-only".into());
+                }
+                sync::check_accepts_push(target_path)?;
+                let pack = read_snapshots(&mut self.reader, pack, target_path)?;
+                let id = pack.commit.id;
+                sync::apply_commit_pack(target_path, &pack)?;
+                self.send(&Message::Ack { id })?;
+            }
+            Message::Finish { head, stash_ids } => {
+                if read_only {
+                    return Err("The repository is served read-only".into());
+                }
+                sync::check_accepts_push(target_path)?;
+                if let Some(head) = head {
+                    if head >= committer::get_commit_count(target_path)? {
+                        return Err(format!("Head {head} is not a commit of the repository").into());
+                    }
+                    committer::write_head(target_path, head)?;
+                }
+                sync::merge_stash_ids(target_path, &stash_ids)?;
+                self.send(&Message::Done)?;
+            }
+            message => return Err(unexpected(&message)),
+        }
+        Ok(())
+    }
+}
+
+// Writes a value as a frame: its bitcode encoding prefixed by the length
+pub fn write_frame<W: Write, T: Encode>(writer: &mut W, value: &T) -> io::Result<()> {
+    let data = bitcode::encode(value);
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|&len| len <= MAX_MESSAGE_LEN)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "A message of {} bytes is over the limit of {MAX_MESSAGE_LEN}",
+                    data.len()
+                ),
+            )
+        })?;
+    writer.write_u32::<BigEndian>(len)?;
+    writer.write_all(&data)
+}
+
+// Writes the bytes of the snapshots of a pack as frames, following the frame of its `WirePack`
+pub fn write_snapshots<W: Write>(writer: &mut W, pack: &CommitPack) -> io::Result<()> {
+    for snapshot in &pack.snapshots {
+        let mut data = snapshot.open()?;
+        let mut left = snapshot.len;
+        while left > 0 {
+            let mut chunk = Vec::with_capacity(left.min(CHUNK_LEN) as usize);
+            (&mut data).take(CHUNK_LEN).read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("The package of {} is truncated", snapshot.path),
+                ));
+            }
+            left -= chunk.len() as u64;
+            write_frame(writer, &chunk)?;
+        }
+    }
+    Ok(())
+}
+
+// Receives the bytes of the snapshots of `pack` into `.minegit/incoming`, replacing those of
+// the previous pack
+pub fn read_snapshots<R: Read>(
+    reader: &mut R,
+    pack: WirePack,
+    target_path: &str,
+) -> Result<CommitPack, Box<dyn Error>> {
+    let incoming_path = sync::clear_incoming(target_path)?;
+    let mut snapshots = Vec::with_capacity(pack.snapshots.len());
+    for (index, (path, pos, len)) in pack.snapshots.into_iter().enumerate() {
+        let source = fs_utils::build_path([&incoming_path, &index.to_string()])?;
+        let mut file = BufWriter::new(File::create(&source)?);
+        let mut left = len;
+        while left > 0 {
+            let chunk: Vec<u8> = read_frame(reader)?.ok_or("The pack is truncated")?;
+            if chunk.is_empty() || chunk.len() as u64 > left {
+                return Err(format!("Received more bytes than announced for {path}").into());
+            }
+            file.write_all(&chunk)?;
+            left -= chunk.len() as u64;
+        }
+        file.flush()?;
+        snapshots.push(SnapshotData {
+            path,
+            pos,
+            len,
+            source,
+            offset: 0,
+        });
+    }
+
+    Ok(CommitPack {
+        commit: pack.commit.to_commit()?,
+        info: pack.info,
+        snapshots,
+    })
+}
+
+// Reads a frame, returns None at the end of the stream
+pub fn read_frame<R: Read, T: DecodeOwned>(reader: &mut R) -> Result<Option<T>, Box<dyn Error>> {
+    let len = match reader.read_u32::<BigEndian>() {
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(
+            format!("Received a message of {len} bytes, the limit is {MAX_MESSAGE_LEN}").into(),
+        );
+    }
+
+    let mut data = vec![0u8; len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Some(bitcode::decode(&data)?))
+}
+
+impl Message {
+    fn name(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "Hello",
+            Message::GetState => "GetState",
+            Message::State(_) => "State",
+            Message::Fetch { .. } => "Fetch",
+            Message::Pack(_) => "Pack",
+            Message::Ack { .. } => "Ack",
+            Message::Finish { .. } => "Finish",
+            Message::Done => "Done",
+            Message::Error { .. } => "Error",
+        }
+    }
+}
+
+fn unexpected(message: &Message) -> Box<dyn Error> {
+    format!("Unexpected {} message", message.name()).into()
+}
+
+// Serves the repository over stdin and stdout, e.g. as the remote command of an SSH session
+pub fn serve_stdio(target_path: &str, read_only: bool) -> Result<(), Box<dyn Error>> {
+    let _lock = RepoLock::acquire(target_path, true)?;
+    journal::recover_all(target_path)?;
+    migrate::check_format(target_path)?;
+    Connection::new(io::stdin().lock(), io::stdout().lock()).serve(target_path, read_only)
+}
+
+// Serves the repository on a TCP address, one client at a time
+pub fn serve_tcp(target_path: &str, address: &str, read_only: bool) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("Serving {target_path} on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        if let Err(e) = serve_stream(target_path, stream, read_only) {
+            eprintln!("{peer}: {e}");
+        }
+    }
+    Ok(())
+}
+
+fn serve_stream(
+    target_path: &str,
+    stream: TcpStream,
+    read_only: bool,
+) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+    let _lock = RepoLock::acquire(target_path, true)?;
+    journal::recover_all(target_path)?;
+    migrate::check_format(target_path)?;
+    Connection::new(stream.try_clone()?, stream).serve(target_path, read_only)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initializer;
+    use crate::savefiles::{DATA_DIRECTORY_NAME, DIRECTORY_NAME};
+    use crate::utils::fs_utils;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+    use std::thread;
+
+    // Repository with an initial commit and two commits changing a region file
+    fn source_repo(name: &str) -> String {
+        let world = temp_dir(name);
+        let mut region: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        initializer::init(&world).unwrap();
+
+        region[100..200].fill(1);
+        write_world_file(&world, "level.dat", b"level");
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        committer::add_commit(&world, "first", Vec::new()).unwrap();
+
+        region[5000..5100].fill(2);
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        committer::add_commit(&world, "second", Vec::new()).unwrap();
+        world
+    }
+
+    // Serves `target` to a single client on a loopback port
+    fn serve_once(target: String, read_only: bool) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = serve_stream(&target, stream, read_only);
+        });
+        (TcpStream::connect(address).unwrap(), handle)
+    }
+
+    fn connect(stream: TcpStream) -> Connection<TcpStream, TcpStream> {
+        Connection::new(stream.try_clone().unwrap(), stream)
+    }
+
+    fn assert_same_repo(a: &str, b: &str) {
+        assert_eq!(
+            committer::get_commit_count(a).unwrap(),
+            committer::get_commit_count(b).unwrap()
+        );
+        assert_eq!(
+            committer::get_head(a).unwrap(),
+            committer::get_head(b).unwrap()
+        );
+        for path in ["region/r.0.0.mca.pkg", "level.dat.pkg"] {
+            let path = format!("{DIRECTORY_NAME}/{DATA_DIRECTORY_NAME}/{path}");
+            assert_eq!(read_world_file(a, &path), read_world_file(b, &path));
+        }
+    }
+
+    #[test]
+    fn pushes_and_fetches_over_loopback() {
+        let source = source_repo("push-source");
+        let server = temp_dir("push-server");
+        initializer::init_empty(&server).unwrap();
+
+        let (stream, handle) = serve_once(server.clone(), false);
+        assert_eq!(connect(stream).push(&source).unwrap(), 3);
+        handle.join().unwrap();
+        assert_same_repo(&source, &server);
+
+        let clone = temp_dir("push-clone");
+        initializer::init_empty(&clone).unwrap();
+        let (stream, handle) = serve_once(server.clone(), true);
+        assert_eq!(connect(stream).fetch(&clone).unwrap(), (3, Some(2)));
+        handle.join().unwrap();
+        assert_eq!(committer::get_commit_count(&clone).unwrap(), 3);
+    }
+
+    #[test]
+    fn resumes_from_first_missing_commit() {
+        let source = source_repo("resume-source");
+        let clone = temp_dir("resume-clone");
+        initializer::init_empty(&clone).unwrap();
+
+        // A transfer interrupted after the first commit
+        let mut package_lens = Default::default();
+        let pack = sync::read_commit_pack(&source, 0, &mut package_lens).unwrap();
+        sync::apply_commit_pack(&clone, &pack).unwrap();
+
+        let (stream, handle) = serve_once(source.clone(), true);
+        let (count, head) = connect(stream).fetch(&clone).unwrap();
+        handle.join().unwrap();
+        assert_eq!(count, 2);
+
+        committer::write_head(&clone, head.unwrap()).unwrap();
+        assert_same_repo(&source, &clone);
+    }
+
+    #[test]
+    fn transfers_rewritten_reverse_deltas() {
+        let world = temp_dir("reverse-source");
+        let mut region: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        initializer::init(&world).unwrap();
+        crate::config::set_value(&world, "core.layout", "reverse").unwrap();
+        let server = temp_dir("reverse-server");
+        initializer::init_empty(&server).unwrap();
+
+        // The server gets the first version in full, the next commit rewrites it locally
+        region[100..200].fill(1);
+        write_world_file(&world, "level.dat", b"level");
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        committer::add_commit(&world, "first", Vec::new()).unwrap();
+        let (stream, handle) = serve_once(server.clone(), false);
+        assert_eq!(connect(stream).push(&world).unwrap(), 2);
+        handle.join().unwrap();
+
+        let first = region.clone();
+        region[5000..5100].fill(2);
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        committer::add_commit(&world, "second", Vec::new()).unwrap();
+        let (stream, handle) = serve_once(server.clone(), false);
+        assert_eq!(connect(stream).push(&world).unwrap(), 1);
+        handle.join().unwrap();
+        assert_same_repo(&world, &server);
+
+        // A clone gets every rewritten version together with the one it depends on
+        let clone = temp_dir("reverse-clone");
+        initializer::init_empty(&clone).unwrap();
+        let (stream, handle) = serve_once(world.clone(), true);
+        assert_eq!(connect(stream).fetch(&clone).unwrap(), (3, Some(2)));
+        handle.join().unwrap();
+        committer::write_head(&clone, 2).unwrap();
+        assert_same_repo(&world, &clone);
+
+        let root = fs_utils::build_path([clone.as_str(), DIRECTORY_NAME]).unwrap();
+        let first_pos = committer::get_commit_info_by_id(&clone, 1)
+            .unwrap()
+            .file_info["region/r.0.0.mca"]
+            .package_pos;
+        for (id, data) in [(1, &first), (2, &region)] {
+            let info = committer::get_commit_info_by_id(&clone, id).unwrap();
+            let pos = info.file_info["region/r.0.0.mca"].package_pos;
+            let recovered = committer::recover_at(&root, "region/r.0.0.mca", pos, 0).unwrap();
+            assert_eq!(&recovered, data);
+        }
+        let chain = committer::get_chain(&root, "region/r.0.0.mca", first_pos).unwrap();
+        assert_eq!(chain.deltas, 1);
+    }
+
+    #[test]
+    fn read_only_server_rejects_push() {
+        let source = source_repo("read-only-source");
+        let server = temp_dir("read-only-server");
+        initializer::init_empty(&server).unwrap();
+
+        let (stream, handle) = serve_once(server.clone(), true);
+        let error = connect(stream).push(&source).unwrap_err();
+        handle.join().unwrap();
+        assert!(error.to_string().contains("read-only"));
+        assert_eq!(committer::get_commit_count(&server).unwrap(), 0);
+    }
+
+    #[test]
+    fn refuses_a_remote_of_another_protocol_version() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = connect(stream);
+            assert!(matches!(server.expect().unwrap(), Message::Hello { .. }));
+            server
+                .send(&Message::Hello {
+                    version: PROTOCOL_VERSION + 1,
+                })
+                .unwrap();
+        });
+
+        let clone = temp_dir("version-clone");
+        initializer::init_empty(&clone).unwrap();
+        let error = connect(TcpStream::connect(address).unwrap())
+            .fetch(&clone)
+            .unwrap_err();
+        handle.join().unwrap();
+        assert!(error.to_string().contains("protocol version"));
+        assert_eq!(committer::get_commit_count(&clone).unwrap(), 0);
+    }
+
+    #[test]
+    fn resumes_a_push_interrupted_within_a_pack() {
+        let source = source_repo("interrupted-source");
+        let server = temp_dir("interrupted-server");
+        initializer::init_empty(&server).unwrap();
+
+        let (stream, handle) = serve_once(server.clone(), false);
+        let mut client = connect(stream);
+        client.handshake().unwrap();
+        let mut package_lens = Default::default();
+        let pack = sync::read_commit_pack(&source, 0, &mut package_lens).unwrap();
+        client.send_pack(&pack).unwrap();
+        assert!(matches!(client.expect().unwrap(), Message::Ack { id: 0 }));
+
+        // The connection drops after the bytes of the first snapshot of the next commit
+        let pack = sync::read_commit_pack(&source, 1, &mut package_lens).unwrap();
+        assert!(pack.snapshots.len() > 1);
+        write_frame(&mut client.writer, &Message::Pack(WirePack::from(&pack))).unwrap();
+        let mut chunk = Vec::new();
+        pack.snapshots[0]
+            .open()
+            .unwrap()
+            .read_to_end(&mut chunk)
+            .unwrap();
+        write_frame(&mut client.writer, &chunk).unwrap();
+        client.writer.flush().unwrap();
+        drop(client);
+        handle.join().unwrap();
+        assert_eq!(committer::get_commit_count(&server).unwrap(), 1);
+
+        let (stream, handle) = serve_once(server.clone(), false);
+        assert_eq!(connect(stream).push(&source).unwrap(), 2);
+        handle.join().unwrap();
+        assert_same_repo(&source, &server);
+    }
+
+    #[test]
+    fn server_with_a_world_rejects_push() {
+        let source = source_repo("world-push-source");
+        let server = temp_dir("world-push-server");
+        initializer::init_empty(&server).unwrap();
+        write_world_file(&server, "level.dat", b"level");
+
+        let (stream, handle) = serve_once(server.clone(), false);
+        let error = connect(stream).push(&source).unwrap_err();
+        handle.join().unwrap();
+        assert!(error.to_string().contains("world"));
+        assert_eq!(committer::get_commit_count(&server).unwrap(), 0);
+    }
+
+    #[test]
+    fn streams_snapshots_larger_than_a_chunk() {
+        // Random bytes do not compress, so the snapshot spans several chunk frames
+        let world = temp_dir("chunk-source");
+        let mut state = 0x2545f4914f6cdd1du64;
+        let region: Vec<u8> = (0..CHUNK_LEN + CHUNK_LEN / 2)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        write_world_file(&world, "region/r.0.0.mca", &region);
+        write_world_file(&world, "level.dat", b"level");
+        initializer::init(&world).unwrap();
+
+        let clone = temp_dir("chunk-clone");
+        initializer::init_empty(&clone).unwrap();
+        let (stream, handle) = serve_once(world.clone(), true);
+        assert_eq!(connect(stream).fetch(&clone).unwrap(), (1, Some(0)));
+        handle.join().unwrap();
+        committer::write_head(&clone, 0).unwrap();
+        assert_same_repo(&world, &clone);
+        assert!(!fs_utils::is_path_exists(
+            &fs_utils::build_path([clone.as_str(), DIRECTORY_NAME, "incoming"]).unwrap()
+        ));
+    }
+
+    #[test]
+    fn rejects_packs_rewriting_history() {
+        let source = source_repo("rewrite-source");
+        let clone = temp_dir("rewrite-clone");
+        initializer::init_empty(&clone).unwrap();
+        let mut package_lens = Default::default();
+        for id in 0..2 {
+            let pack = sync::read_commit_pack(&source, id, &mut package_lens).unwrap();
+            sync::apply_commit_pack(&clone, &pack).unwrap();
+        }
+
+        // A pack built against empty packages starts over the clone's snapshots, which only
+        // passes while they hold the same bytes
+        let package_path = |target: &str| {
+            fs_utils::build_path([
+                target,
+                DIRECTORY_NAME,
+                DATA_DIRECTORY_NAME,
+                "region/r.0.0.mca.pkg",
+            ])
+            .unwrap()
+        };
+        let mut forged = Vec::new();
+        fs_utils::read_to_end(&package_path(&source), &mut forged).unwrap();
+        forged[40] ^= 1;
+        let forged_path = fs_utils::build_path([source.as_str(), "forged"]).unwrap();
+        std::fs::write(&forged_path, forged).unwrap();
+
+        let mut pack = sync::read_commit_pack(&source, 2, &mut Default::default()).unwrap();
+        for snapshot in &mut pack.snapshots {
+            if snapshot.path == "region/r.0.0.mca" {
+                snapshot.source = forged_path.clone();
+            }
+        }
+        let held = std::fs::read(package_path(&clone)).unwrap();
+        let error = sync::apply_commit_pack(&clone, &pack).unwrap_err();
+        assert!(error.to_string().contains("diverged"));
+        assert_eq!(committer::get_commit_count(&clone).unwrap(), 2);
+        assert_eq!(std::fs::read(package_path(&clone)).unwrap(), held);
+
+        let pack = sync::read_commit_pack(&source, 2, &mut Default::default()).unwrap();
+        sync::apply_commit_pack(&clone, &pack).unwrap();
+        committer::write_head(&clone, 2).unwrap();
+        assert_same_repo(&source, &clone);
+    }
+}
@@ -0,0 +1,192 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::config::RconConfig;
+
+const TYPE_RESPONSE: i32 = 0;
+const TYPE_COMMAND: i32 = 2;
+const TYPE_AUTH_RESPONSE: i32 = 2;
+const TYPE_LOGIN: i32 = 3;
+
+// `save-all flush` answers only once the world is written, which may take a while
+const READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Client of the Source RCON protocol implemented by Minecraft servers
+pub struct RconClient {
+    stream: TcpStream,
+    next_id: i32,
+}
+
+impl RconClient {
+    pub fn connect(config: &RconConfig) -> Result<Self, Box<dyn Error>> {
+        let stream = TcpStream::connect((config.host.as_str(), config.port))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let mut client = Self { stream, next_id: 1 };
+        let id = client.send(TYPE_LOGIN, &config.password)?;
+
+        // Some servers send an empty response before the auth response
+        loop {
+            let (response_id, kind, _) = client.receive()?;
+            if kind != TYPE_AUTH_RESPONSE {
+                continue;
+            }
+            if response_id == -1 || response_id != id {
+                return Err("RCON authentication failed, check the password in the config".into());
+            }
+            return Ok(client);
+        }
+    }
+
+    // Runs a console command and returns its output
+    pub fn command(&mut self, command: &str) -> io::Result<String> {
+        let id = self.send(TYPE_COMMAND, command)?;
+        loop {
+            let (response_id, kind, body) = self.receive()?;
+            if response_id == id && kind == TYPE_RESPONSE {
+                return Ok(body);
+            }
+        }
+    }
+
+    fn send(&mut self, kind: i32, body: &str) -> io::Result<i32> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut packet = Vec::with_capacity(body.len() + 14);
+        packet.write_i32::<LittleEndian>(body.len() as i32 + 10)?;
+        packet.write_i32::<LittleEndian>(id)?;
+        packet.write_i32::<LittleEndian>(kind)?;
+        packet.extend_from_slice(body.as_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        self.stream.write_all(&packet)?;
+        Ok(id)
+    }
+
+    fn receive(&mut self) -> io::Result<(i32, i32, String)> {
+        let len = self.stream.read_i32::<LittleEndian>()?;
+        if !(10..=1 << 16).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid RCON packet length",
+            ));
+        }
+
+        let mut packet = vec![0u8; len as usize];
+        self.stream.read_exact(&mut packet)?;
+        let mut reader = &packet[..];
+        let id = reader.read_i32::<LittleEndian>()?;
+        let kind = reader.read_i32::<LittleEndian>()?;
+        let body = String::from_utf8_lossy(&reader[..reader.len() - 2]).into_owned();
+        Ok((id, kind, body))
+    }
+}
+
+// Runs `f` while the server has automatic saving disabled and the world flushed to disk.
+// Saving is turned back on even if `f` fails.
+pub fn with_saving_paused<T>(
+    config: &RconConfig,
+    f: impl FnOnce() -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    let mut client = RconClient::connect(config)?;
+
+    client.command("save-off")?;
+    let result = client
+        .command("save-all flush")
+        .map_err(Into::into)
+        .and_then(|_| f());
+    client.command("save-on")?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Minimal server that accepts `password` and records received commands
+    fn fake_server(password: &'static str) -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut commands = Vec::new();
+            loop {
+                let Ok(len) = stream.read_i32::<LittleEndian>() else {
+                    return commands;
+                };
+                let mut packet = vec![0u8; len as usize];
+                stream.read_exact(&mut packet).unwrap();
+                let id = i32::from_le_bytes(packet[0..4].try_into().unwrap());
+                let kind = i32::from_le_bytes(packet[4..8].try_into().unwrap());
+                let body = String::from_utf8(packet[8..packet.len() - 2].to_vec()).unwrap();
+
+                let (response_id, response_kind) = match kind {
+                    TYPE_LOGIN if body == password => (id, TYPE_AUTH_RESPONSE),
+                    TYPE_LOGIN => (-1, TYPE_AUTH_RESPONSE),
+                    _ => {
+                        commands.push(body);
+                        (id, TYPE_RESPONSE)
+                    }
+                };
+                let mut response = Vec::new();
+                response.write_i32::<LittleEndian>(10).unwrap();
+                response.write_i32::<LittleEndian>(response_id).unwrap();
+                response.write_i32::<LittleEndian>(response_kind).unwrap();
+                response.extend_from_slice(&[0, 0]);
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (port, handle)
+    }
+
+    fn config(port: u16, password: &str) -> RconConfig {
+        RconConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            password: password.to_string(),
+        }
+    }
+
+    #[test]
+    fn pauses_saving_around_commit() {
+        let (port, server) = fake_server("secret");
+
+        let result = with_saving_paused(&config(port, "secret"), || Ok(42)).unwrap();
+        assert_eq!(result, 42);
+
+        assert_eq!(
+            server.join().unwrap(),
+            ["save-off", "save-all flush", "save-on"]
+        );
+    }
+
+    #[test]
+    fn resumes_saving_when_commit_fails() {
+        let (port, server) = fake_server("secret");
+
+        let result: Result<(), _> =
+            with_saving_paused(&config(port, "secret"), || Err("commit failed".into()));
+        assert!(result.is_err());
+
+        assert_eq!(
+            server.join().unwrap(),
+            ["save-off", "save-all flush", "save-on"]
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_password() {
+        let (port, server) = fake_server("secret");
+
+        assert!(RconClient::connect(&config(port, "wrong")).is_err());
+        assert!(server.join().unwrap().is_empty());
+    }
+}
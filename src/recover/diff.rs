@@ -1,6 +1,38 @@
 use std::io::{ self, Write, Read };
 use byteorder::{ReadBytesExt, BigEndian};
 
+// Diff commands are encoded with variable-length integers (LEB128), 7 bits per byte with
+// the high bit set on every byte but the last. Every command starts with its length shifted
+// left by two, the lowest two bits are its type:
+// - 0, copy: followed by its source index
+// - 1, insert: followed by the data
+// - 2, add: followed by its source index and a difference per byte, the source bytes plus
+//   the differences give the version. Small changes, e.g. of a counter, leave mostly zero
+//   differences which compress well.
+//
+// Older snapshots use one bit for the type and have no adds, see `deserialize_varint`, or
+// a fixed encoding with 32-bit fields, limiting copies and inserts to 2 GiB and the source
+// to 4 GiB, see `deserialize_fixed`. The snapshot flags tell which encoding a payload uses.
+
+// Longest command, the lowest two bits of the encoded length are its type
+pub const MAX_LEN: u64 = u64::MAX >> 2;
+
+const COPY_TAG: u64 = 0;
+const INSERT_TAG: u64 = 1;
+const ADD_TAG: u64 = 2;
+
+// Encoding of the commands of a snapshot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CommandEncoding {
+    // 32-bit fields
+    #[default]
+    Fixed,
+    // Variable-length integers with copies and inserts
+    Varint,
+    // Variable-length integers with copies, inserts and adds, written by this version
+    Tagged,
+}
+
 #[derive(Debug)]
 pub struct Copy {
     pub sidx: u64,
@@ -12,51 +44,149 @@ pub struct InsertHeader {
     pub len: u64
 }
 
+#[derive(Debug)]
+pub struct AddHeader {
+    pub sidx: u64,
+    pub len: u64
+}
+
 #[derive(Debug)]
 pub enum DiffCommandHeader {
     Copy(Copy),
-    Insert(InsertHeader)
+    Insert(InsertHeader),
+    Add(AddHeader)
 }
 
 impl Copy {
-    pub fn serialize<W: Write>(&self, out: &mut W) -> io::Result<()> {
-        debug_assert!(self.sidx <= u32::MAX as u64);
-        debug_assert!(self.len  <= (u32::MAX >> 1) as u64);
+    pub fn serialize<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        check_len(self.len)?;
+        write_varint(out, self.len << 2 | COPY_TAG)?;
+        write_varint(out, self.sidx)?;
+        Ok(())
+    }
+}
 
-        let d = (( self.len as u64 ) << 32) | (self.sidx as u64);
-        out.write_all(&d.to_be_bytes())?;
+pub struct Add {}
+impl Add {
+    // `diff` holds the difference of every byte to the source at `sidx`
+    pub fn serialize<W: Write + ?Sized>(sidx: u64, diff: &[u8], out: &mut W) -> io::Result<()> {
+        check_len(diff.len() as u64)?;
+        write_varint(out, (diff.len() as u64) << 2 | ADD_TAG)?;
+        write_varint(out, sidx)?;
+        out.write_all(diff)?;
         Ok(())
     }
 }
 
 pub struct Insert {}
 impl Insert {
-    pub const SERIZIZED_SIZE: u64 = 4;
-    pub fn serialize<W: Write>(data: &[u8], out: &mut W) -> io::Result<()> {
-        debug_assert!(data.len() <= (u32::MAX >> 1) as usize);
-        let size = ( 1u32 << 31 ) | (data.len() as u32);
-        out.write_all(&size.to_be_bytes())?;
+    // Longest encoding of an insert header
+    pub const SERIZIZED_SIZE: u64 = 10;
+    pub fn serialize<W: Write + ?Sized>(data: &[u8], out: &mut W) -> io::Result<()> {
+        check_len(data.len() as u64)?;
+        write_varint(out, (data.len() as u64) << 2 | INSERT_TAG)?;
         out.write_all(data)?;
         Ok(())
     }
+
+    // Like `serialize` with the `len` bytes of data read from `data`
+    pub fn serialize_from<R: Read, W: Write + ?Sized>(data: &mut R, len: u64, out: &mut W) -> io::Result<()> {
+        check_len(len)?;
+        write_varint(out, len << 2 | INSERT_TAG)?;
+        if io::copy(&mut data.take(len), out)? != len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is shorter than expected"));
+        }
+        Ok(())
+    }
 }
 
 impl DiffCommandHeader {
-    pub fn deserialize<R: Read>(r: &mut R) -> io::Result<(DiffCommandHeader, u64)> {
+    // Reads a command and returns it with the length of its header
+    pub fn deserialize<R: Read>(r: &mut R, encoding: CommandEncoding) -> io::Result<(DiffCommandHeader, u64)> {
+        match encoding {
+            CommandEncoding::Fixed => return Self::deserialize_fixed(r),
+            CommandEncoding::Varint => return Self::deserialize_varint(r),
+            CommandEncoding::Tagged => {}
+        }
+
+        let (head, head_size) = read_varint(r)?;
+        let len = head >> 2;
+        if head & 3 == INSERT_TAG {
+            return Ok((DiffCommandHeader::Insert(InsertHeader { len }), head_size));
+        }
+        let (sidx, sidx_size) = read_varint(r)?;
+        let command = match head & 3 {
+            COPY_TAG => DiffCommandHeader::Copy(Copy { sidx, len }),
+            ADD_TAG => DiffCommandHeader::Add(AddHeader { sidx, len }),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown diff command")),
+        };
+        Ok((command, head_size + sidx_size))
+    }
+
+    fn deserialize_varint<R: Read>(r: &mut R) -> io::Result<(DiffCommandHeader, u64)> {
+        let (head, head_size) = read_varint(r)?;
+        if head & 1 == 1 {
+            return Ok((DiffCommandHeader::Insert(InsertHeader { len: head >> 1 }), head_size));
+        }
+        let (sidx, sidx_size) = read_varint(r)?;
+        Ok((DiffCommandHeader::Copy(Copy { sidx, len: head >> 1 }), head_size + sidx_size))
+    }
+
+    fn deserialize_fixed<R: Read>(r: &mut R) -> io::Result<(DiffCommandHeader, u64)> {
         let len: u32 = r.read_u32::<BigEndian>()?;
         match (len >> 31) & 1 {
             0 => {
-                return Ok((DiffCommandHeader::Copy( Copy {
+                Ok((DiffCommandHeader::Copy( Copy {
                     len: len as u64,
                     sidx: r.read_u32::<BigEndian>()? as u64
-                }), 8));
+                }), 8))
             },
             1 => {
-                return Ok((DiffCommandHeader::Insert( InsertHeader {
+                Ok((DiffCommandHeader::Insert( InsertHeader {
                     len: (len & !(1 << 31)) as u64
-                }), 4));
+                }), 4))
             },
             _ => panic!("Imposible match for the single bit")
         }
     }
-}
\ No newline at end of file
+}
+
+fn check_len(len: u64) -> io::Result<()> {
+    if len > MAX_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Diff command of {len} bytes is longer than the supported {MAX_LEN} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+fn write_varint<W: Write + ?Sized>(out: &mut W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut i = 0;
+    while value >= 0x80 {
+        buf[i] = (value as u8) | 0x80;
+        value >>= 7;
+        i += 1;
+    }
+    buf[i] = value as u8;
+    out.write_all(&buf[..=i])
+}
+
+// Returns the value and the number of bytes it took
+fn read_varint<R: Read>(r: &mut R) -> io::Result<(u64, u64)> {
+    let mut value: u64 = 0;
+    for i in 0..10 {
+        let byte = r.read_u8()?;
+        let bits = (byte & 0x7f) as u64;
+        // The tenth byte holds the highest bit of a 64-bit value
+        if i == 9 && bits > 1 {
+            break;
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Diff command does not fit in 64 bits"))
+}
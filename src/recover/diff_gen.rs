@@ -1,9 +1,83 @@
 use divsufsort::sort_in_place;
 use std::io::{self, Write};
 use crate::recover::diff::*;
+use crate::recover::rolling_diff;
 
 const MIN_COPY_SIZE: usize = 16;
 
+// Mismatches more than matches after the best end of an approximate match, scanning stops
+const MAX_ADD_DRIFT: i64 = 64;
+
+// Algorithm finding the copies of a delta
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffEngine {
+    // Suffix array over both versions, finds every match of `MIN_COPY_SIZE` bytes
+    SuffixArray,
+    // Hashes of source blocks, much faster on large files for somewhat larger deltas
+    RollingHash,
+}
+
+impl DiffEngine {
+    // Bytes of memory taken per byte of source and target together
+    pub fn memory_factor(self) -> u64 {
+        match self {
+            DiffEngine::SuffixArray => 32,
+            DiffEngine::RollingHash => 4,
+        }
+    }
+
+    // Writes the commands turning `src` into `trg`, copies are relative to `offset`. Versions
+    // too large for a suffix array are diffed with rolling hashes. `adds` allows approximate
+    // matches, which only pay off in a compressed payload.
+    pub fn generate_at<W: Write + ?Sized>(self, src: Vec<u8>, trg: &[u8], out: &mut W, offset: u64, adds: bool) -> io::Result<()> {
+        if self == DiffEngine::RollingHash || src.len() + trg.len() > DiffGenerator::MAX_LEN {
+            return rolling_diff::generate_at(&src, trg, out, offset, adds);
+        }
+        let mut diff = DiffGenerator::new();
+        diff.init_new(src, trg)?;
+        diff.generate_at(out, offset, adds)
+    }
+}
+
+// `len` equal bytes at `sidx` of the source and `tidx` of the target
+pub struct Match {
+    pub sidx: usize,
+    pub tidx: usize,
+    pub len: usize,
+}
+
+// Writes a match and returns the length of the target it covers. With `adds` the match is
+// extended like in bsdiff as long as it has more equal bytes than differing ones, and
+// written as an add.
+pub fn write_match<W: Write + ?Sized>(src: &[u8], trg: &[u8], m: Match, offset: u64, adds: bool, out: &mut W) -> io::Result<usize> {
+    let Match { sidx, tidx, len } = m;
+    let mut end = len;
+    if adds {
+        let (mut score, mut best) = (0i64, 0i64);
+        let mut k = len;
+        while sidx + k < src.len() && tidx + k < trg.len() && score > best - MAX_ADD_DRIFT {
+            score += if src[sidx + k] == trg[tidx + k] { 1 } else { -1 };
+            k += 1;
+            if score > best {
+                best = score;
+                end = k;
+            }
+        }
+    }
+
+    if end == len {
+        Copy { sidx: offset + sidx as u64, len: len as u64 }.serialize(out)?;
+    } else {
+        let diff: Vec<u8> = trg[tidx..tidx + end]
+            .iter()
+            .zip(&src[sidx..sidx + end])
+            .map(|(t, s)| t.wrapping_sub(*s))
+            .collect();
+        Add::serialize(offset + sidx as u64, &diff, out)?;
+    }
+    Ok(end)
+}
+
 pub struct DiffGenerator {
     pub data: Vec<u8>,
     closest: Vec<(usize, usize)>,
@@ -11,6 +85,9 @@ pub struct DiffGenerator {
 }
 
 impl DiffGenerator {
+    // Longest source and target together, the suffix array has 32-bit indexes
+    pub const MAX_LEN: usize = i32::MAX as usize;
+
     pub fn new() -> Self {
         Self {
             data: Vec::new(),
@@ -28,6 +105,9 @@ impl DiffGenerator {
     }
 
     pub fn init_new(&mut self, mut src: Vec<u8>, trg: &[u8]) -> io::Result<()> {
+        if src.len() + trg.len() > Self::MAX_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Files are too large for a delta"));
+        }
         self.n = src.len();
         src.resize(self.n + trg.len(), 0);
         src[self.n..].copy_from_slice(trg);
@@ -62,7 +142,9 @@ impl DiffGenerator {
         }
     }
 
-    pub fn generate<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+    // Writes the commands turning the source into the target, copies are relative to
+    // `offset`, the position of the source in the version it is taken from
+    pub fn generate_at<W: Write + ?Sized>(&mut self, out: &mut W, offset: u64, adds: bool) -> io::Result<()> {
         self.init_closest();
         let data = &self.data;
         let n = self.n;
@@ -93,9 +175,7 @@ impl DiffGenerator {
             if save_from != i {
                 Insert::serialize(&data[save_from+n..i+n], out)?;
             }
-            Copy { sidx: j as u64, len: l as u64 }.serialize(out)?;
-
-            i += l;
+            i += write_match(&data[..n], &data[n..], Match { sidx: j, tidx: i, len: l }, offset, adds, out)?;
             save_from = i;
         }
         if save_from != m {
@@ -1,15 +1,26 @@
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
-use std::io::{self, Cursor, Read, Seek};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Take, Write};
 use zstd::Decoder;
-use crate::recover::diff::DiffCommandHeader;
+use crate::recover::diff::{CommandEncoding, DiffCommandHeader};
 use crate::recover::snapshot::SnapshotHeader;
 
+// Versions are recovered range by range: an instruction copies `len` bytes at `from` of the
+// version of a snapshot to `to` in the output. Walking down the delta chain, inserts are
+// written to the output and copies become instructions on the version they depend on. Adds
+// are copies whose differences are added to the output once the whole chain is walked.
+//
+// Payloads are read from the package as needed, compressed ones are decompressed as they
+// are read, so recovering a window of a large file only needs memory for the window.
+
+// Buffer of an uncompressed payload, the command headers are read through it
+const PAYLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, PartialEq, Eq)]
-struct Instruction {
-    from: u64,
-    to: u64,
-    len: u64,
+pub struct Instruction {
+    pub from: u64,
+    pub to: u64,
+    pub len: u64,
 }
 
 impl Ord for Instruction {
@@ -24,88 +35,209 @@ impl PartialOrd for Instruction {
     }
 }
 
-fn init_data<R: Read>(pack: &mut R, snap: &SnapshotHeader, buf: &mut Vec<u8>, res: &mut Vec<u8>) -> io::Result<()> {
-    if snap.is_zipped {
-        buf.resize(snap.payload_len as usize, 0);
-        pack.read_exact(buf)?;
-        let mut decoder = Decoder::new(Cursor::new(&buf))?;
-        decoder.read_to_end(res)?;
-    } else {
-        res.resize(snap.payload_len as usize, 0);
-        pack.read_exact(res)?;
+// Compressed payload decompressed as it is read. Seeking forward reads over the data,
+// seeking backward starts over from the beginning of the payload.
+struct ZstdPayload<'a, R: Read + Seek> {
+    decoder: Option<Decoder<'static, BufReader<Take<&'a mut R>>>>,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a, R: Read + Seek> ZstdPayload<'a, R> {
+    fn new(pack: &'a mut R, start: u64, len: u64) -> io::Result<Self> {
+        pack.seek(SeekFrom::Start(start))?;
+        Ok(Self { decoder: Some(Decoder::new(pack.take(len))?), start, len, pos: 0 })
+    }
+
+    fn restart(&mut self) -> io::Result<()> {
+        let pack = self.decoder.take().unwrap().finish().into_inner().into_inner();
+        *self = Self::new(pack, self.start, self.len)?;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for ZstdPayload<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.decoder.as_mut().unwrap().read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for ZstdPayload<'_, R> {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let target = match to {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        let target = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek in a compressed payload"))?;
+
+        if target < self.pos {
+            self.restart()?;
+        }
+        let n = target - self.pos;
+        if io::copy(&mut self.by_ref().take(n), &mut io::sink())? != n {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Seek past the end of the snapshot"));
+        }
+        Ok(self.pos)
+    }
+}
+
+// Moves `n` bytes forward in a payload. Short distances are read over, since seeking drops
+// the buffer of an uncompressed payload.
+fn skip<P: Read + Seek>(payload: &mut P, n: u64) -> io::Result<()> {
+    if n > PAYLOAD_BUFFER_SIZE as u64 {
+        payload.seek(SeekFrom::Current(n as i64))?;
+        return Ok(());
+    }
+    if io::copy(&mut payload.by_ref().take(n), &mut io::sink())? != n {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Insert is longer than the snapshot"));
+    }
+    Ok(())
+}
+
+// Moves from `pos` to `to` in the data of an insert or add
+fn move_to<P: Read + Seek>(payload: &mut P, pos: u64, to: u64) -> io::Result<()> {
+    if to >= pos {
+        return skip(payload, to - pos);
+    }
+    // Instructions may overlap, e.g. two copies of the same content
+    payload.seek(SeekFrom::Current(-((pos - to) as i64)))?;
+    Ok(())
+}
+
+// Applies the commands of one snapshot to the instructions on its version, the copies
+// become instructions on the version it depends on
+fn apply_commands<P: Read + Seek>(
+    payload: &mut P,
+    encoding: CommandEncoding,
+    ops: &mut BinaryHeap<Instruction>,
+    next: &mut Vec<Instruction>,
+    adds: &mut Vec<(u64, Vec<u8>)>,
+    file: &mut [u8],
+) -> io::Result<()> {
+    let mut idx: u64 = 0;
+    while let Some(op) = ops.peek() {
+        if op.len == 0 {
+            ops.pop();
+            continue;
+        }
+
+        let (op_head, _) = DiffCommandHeader::deserialize(payload, encoding)?;
+        match op_head {
+            DiffCommandHeader::Copy(c) => {
+                while let Some(op) = ops.peek() {
+                    if idx + c.len <= op.from { break; }
+                    let op = ops.pop().unwrap();
+                    let skip = op.from - idx;
+                    let len = op.len.min((c.len).saturating_sub(skip));
+                    let from = c.sidx + skip;
+                    next.push(Instruction {
+                        from,
+                        len,
+                        to: op.to,
+                    });
+
+                    if op.len == len { continue; }
+                    ops.push(Instruction {
+                        from: op.from + len,
+                        to: op.to + len,
+                        len: op.len - len,
+                    });
+                }
+                idx += c.len;
+            }
+            DiffCommandHeader::Insert(ins) => {
+                // Position in the inserted data
+                let mut pos: u64 = 0;
+                while let Some(op) = ops.peek() {
+                    if idx + ins.len <= op.from { break; }
+                    let op = ops.pop().unwrap();
+
+                    let skip = op.from - idx;
+                    let len = op.len.min(ins.len.saturating_sub(skip));
+                    move_to(payload, pos, skip)?;
+                    payload.read_exact(&mut file[(op.to as usize)..((op.to + len) as usize)])?;
+                    pos = skip + len;
+
+                    if len == op.len { continue; }
+                    ops.push(Instruction {
+                        from: op.from + len,
+                        to: op.to + len,
+                        len: op.len - len,
+                    });
+                }
+
+                idx += ins.len;
+                if !ops.is_empty() {
+                    self::skip(payload, ins.len - pos)?;
+                }
+            }
+            DiffCommandHeader::Add(add) => {
+                // Position in the differences
+                let mut pos: u64 = 0;
+                while let Some(op) = ops.peek() {
+                    if idx + add.len <= op.from { break; }
+                    let op = ops.pop().unwrap();
+
+                    let skip = op.from - idx;
+                    let len = op.len.min(add.len.saturating_sub(skip));
+                    move_to(payload, pos, skip)?;
+                    let mut diff = vec![0u8; len as usize];
+                    payload.read_exact(&mut diff)?;
+                    pos = skip + len;
+
+                    next.push(Instruction {
+                        from: add.sidx + skip,
+                        to: op.to,
+                        len,
+                    });
+                    adds.push((op.to, diff));
+
+                    if len == op.len { continue; }
+                    ops.push(Instruction {
+                        from: op.from + len,
+                        to: op.to + len,
+                        len: op.len - len,
+                    });
+                }
+
+                idx += add.len;
+                if !ops.is_empty() {
+                    self::skip(payload, add.len - pos)?;
+                }
+            }
+        }
     }
     Ok(())
 }
 
-fn _recover<R: Read + Seek>(
+fn _recover<R: Read + Seek, F: FnMut(&SnapshotHeader, &mut R, Vec<Instruction>, &mut [u8]) -> io::Result<()>>(
     pack: &mut R,
     mut ops: BinaryHeap<Instruction>,
     mut snap: SnapshotHeader,
-    file: &mut [u8]
+    file: &mut [u8],
+    resolve: &mut F
 ) -> io::Result<()> {
     let mut next: Vec<Instruction> = Vec::new();
-    let mut buf: Vec<u8> = Vec::new();
-    let mut buf_temp: Vec<u8> = Vec::new();
-    pack.seek(io::SeekFrom::Start(snap.pos))?;
+    let mut adds: Vec<(u64, Vec<u8>)> = Vec::new();
 
     while !ops.is_empty() {
-        let mut idx: u64 = 0;
-        init_data(pack, &snap, &mut buf_temp, &mut buf)?;
-        let mut buf_cursor = Cursor::new(&buf);
-        while !ops.is_empty() {
-            if ops.peek().unwrap().len == 0 {
-                ops.pop();
-                continue;
-            }
+        // The chain starts with the content of another package, the rest is copied from it
+        if snap.is_reference {
+            resolve(&snap, pack, ops.into_vec(), file)?;
+            break;
+        }
 
-            let (op_head, _) = DiffCommandHeader::deserialize(&mut buf_cursor).unwrap();
-            match op_head {
-                DiffCommandHeader::Copy(c) => {
-                    while let Some(op) = ops.peek() {
-                        if idx + c.len <= op.from { break; }
-                        let op = ops.pop().unwrap();
-                        let skip = op.from - idx;
-                        let len = op.len.min((c.len).saturating_sub(skip));
-                        let from = c.sidx + skip;
-                        next.push(Instruction {
-                            from,
-                            len,
-                            to: op.to,
-                        });
-
-                        if op.len == len { continue; }
-                        ops.push(Instruction {
-                            from: op.from + len,
-                            to: op.to + len,
-                            len: op.len - len,
-                        });
-                    }
-                    idx += c.len;
-                }
-                DiffCommandHeader::Insert(ins) => {
-                    while let Some(op) = ops.peek() {
-                        if idx + ins.len <= op.from { break; }
-                        let op = ops.pop().unwrap();
-
-                        let skip = op.from - idx;
-                        let len = op.len.min(ins.len.saturating_sub(skip));
-                        let f_idx = (buf_cursor.position() + skip) as usize;
-
-                        file[(op.to as usize)..((op.to + len) as usize)]
-                            .copy_from_slice(&buf[f_idx..(f_idx + len as usize)]);
-
-                        if len == op.len { continue; }
-                        ops.push(Instruction {
-                            from: op.from + len,
-                            to: op.to + len,
-                            len: op.len - len,
-                        });
-                    }
-
-                    idx += ins.len;
-                    buf_cursor.seek(io::SeekFrom::Current(ins.len as i64))?;
-                }
-            }
+        if snap.is_zipped {
+            let mut payload = ZstdPayload::new(&mut *pack, snap.pos, snap.payload_len)?;
+            apply_commands(&mut payload, snap.encoding, &mut ops, &mut next, &mut adds, file)?;
+        } else {
+            pack.seek(SeekFrom::Start(snap.pos))?;
+            let mut payload = BufReader::with_capacity(PAYLOAD_BUFFER_SIZE, &mut *pack);
+            apply_commands(&mut payload, snap.encoding, &mut ops, &mut next, &mut adds, file)?;
         }
 
         let temp = ops.into_vec();
@@ -113,26 +245,79 @@ fn _recover<R: Read + Seek>(
         next = temp;
         next.clear();
 
-        if snap.depend_on == u64::MAX || ops.len() == 0 {
+        if snap.depend_on == u64::MAX || ops.is_empty() {
             break;
         }
-        pack.seek(io::SeekFrom::Start(snap.depend_on))?;
+        pack.seek(SeekFrom::Start(snap.depend_on))?;
         snap = SnapshotHeader::deserialize(pack)?;
     }
 
+    // Every byte an add covers is in place now, additions can be done in any order
+    for (to, diff) in adds {
+        for (b, d) in file[to as usize..].iter_mut().zip(diff) {
+            *b = b.wrapping_add(d);
+        }
+    }
     Ok(())
 }
 
+fn to_usize(len: u64) -> io::Result<usize> {
+    usize::try_from(len).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("A file of {len} bytes does not fit in memory"))
+    })
+}
+
 pub fn recover<R: Read + Seek>(pack: &mut R, snap: SnapshotHeader) -> io::Result<Vec<u8>> {
-    pack.seek(io::SeekFrom::Start(snap.pos))?;
+    recover_with(pack, snap, |_, _, _, _| {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "Snapshot depends on another package"))
+    })
+}
+
+// Like `recover`, `resolve` carries out the instructions on a reference the chain starts with
+pub fn recover_with<R: Read + Seek, F: FnMut(&SnapshotHeader, &mut R, Vec<Instruction>, &mut [u8]) -> io::Result<()>>(
+    pack: &mut R,
+    snap: SnapshotHeader,
+    resolve: F
+) -> io::Result<Vec<u8>> {
+    let mut file = vec![0u8; to_usize(snap.file_len)?];
     let len = snap.file_len;
-    let bheap: BinaryHeap<Instruction> = BinaryHeap::from(vec![Instruction {
-        from: 0,
-        to: 0,
-        len,
-    }]);
-    let mut file = Vec::with_capacity(snap.file_len as usize);
-    file.resize(snap.file_len as usize, 0);
-    _recover(pack, bheap, snap, &mut file)?;
+    recover_ops(pack, snap, vec![Instruction { from: 0, to: 0, len }], &mut file, resolve)?;
     Ok(file)
-}
\ No newline at end of file
+}
+
+// Carries out instructions on the version of `snap`, writing into `file`
+pub fn recover_ops<R: Read + Seek, F: FnMut(&SnapshotHeader, &mut R, Vec<Instruction>, &mut [u8]) -> io::Result<()>>(
+    pack: &mut R,
+    snap: SnapshotHeader,
+    ops: Vec<Instruction>,
+    file: &mut [u8],
+    mut resolve: F
+) -> io::Result<()> {
+    if let Some(op) = ops.iter().find(|op| op.from + op.len > snap.file_len || op.to + op.len > file.len() as u64) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Range {}..{} is outside of the version", op.from, op.from + op.len),
+        ));
+    }
+    _recover(pack, BinaryHeap::from(ops), snap, file, &mut resolve)
+}
+
+// Writes the version of `snap` to `out`, recovering `window` bytes at a time
+pub fn recover_to<R: Read + Seek, W: Write, F: FnMut(&SnapshotHeader, &mut R, Vec<Instruction>, &mut [u8]) -> io::Result<()>>(
+    pack: &mut R,
+    snap: SnapshotHeader,
+    out: &mut W,
+    window: u64,
+    mut resolve: F
+) -> io::Result<()> {
+    let mut buf = vec![0u8; to_usize(window.clamp(1, snap.file_len.max(1)))?];
+    let mut from = 0;
+    while from < snap.file_len {
+        let len = (snap.file_len - from).min(buf.len() as u64);
+        let op = Instruction { from, to: 0, len };
+        _recover(pack, BinaryHeap::from(vec![op]), snap.clone(), &mut buf[..len as usize], &mut resolve)?;
+        out.write_all(&buf[..len as usize])?;
+        from += len;
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, Write};
+use crate::recover::diff::*;
+use crate::recover::diff_gen::{write_match, Match};
+
+// Delta engine in the style of rsync and xdelta. The source is split into blocks whose
+// rolling hashes are indexed, then a window slides over the target one byte at a time and
+// a block with the same hash is extended to the longest match in both directions. Finding
+// a copy costs one lookup per byte instead of sorting the suffixes of both versions, at the
+// cost of missing matches shorter than a block.
+
+const BLOCK_SIZE: usize = 32;
+
+// Multiplier of the polynomial rolling hash
+const BASE: u64 = 0x100000001b3;
+
+// The keys are hashes already, they are only mixed so the low bits pick the bucket
+#[derive(Default)]
+struct BlockHasher(u64);
+
+impl Hasher for BlockHasher {
+    fn finish(&self) -> u64 {
+        (self.0 ^ (self.0 >> 29)).wrapping_mul(0x9e3779b97f4a7c15)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = self.0.rotate_left(8) ^ b as u64;
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0, |h, &b| h.wrapping_mul(BASE).wrapping_add(b as u64))
+}
+
+// Writes the commands turning `src` into `trg`, copies are relative to `offset`. `adds`
+// allows approximate matches, see `write_match`.
+pub fn generate_at<W: Write + ?Sized>(src: &[u8], trg: &[u8], out: &mut W, offset: u64, adds: bool) -> io::Result<()> {
+    let mut save_from = 0;
+    if src.len() >= BLOCK_SIZE && trg.len() >= BLOCK_SIZE {
+        // Weight of the byte leaving the window
+        let out_weight = (1..BLOCK_SIZE).fold(1u64, |w, _| w.wrapping_mul(BASE));
+
+        // The first block with a hash is kept, later ones are usually repeated content
+        let mut blocks: HashMap<u64, usize, BuildHasherDefault<BlockHasher>> = HashMap::default();
+        blocks.reserve(src.len() / BLOCK_SIZE);
+        for start in (0..=src.len() - BLOCK_SIZE).step_by(BLOCK_SIZE) {
+            blocks.entry(hash(&src[start..start + BLOCK_SIZE])).or_insert(start);
+        }
+
+        let mut i = 0;
+        let mut h = hash(&trg[..BLOCK_SIZE]);
+        while i + BLOCK_SIZE <= trg.len() {
+            let found = blocks.get(&h).copied().filter(|&j| src[j..j + BLOCK_SIZE] == trg[i..i + BLOCK_SIZE]);
+            let Some(j) = found else {
+                if i + BLOCK_SIZE < trg.len() {
+                    h = h.wrapping_sub((trg[i] as u64).wrapping_mul(out_weight))
+                        .wrapping_mul(BASE)
+                        .wrapping_add(trg[i + BLOCK_SIZE] as u64);
+                }
+                i += 1;
+                continue;
+            };
+
+            // Extend the match backwards over bytes not stored yet, then forwards
+            let mut back = 0;
+            while i - back > save_from && j - back > 0 && src[j - back - 1] == trg[i - back - 1] {
+                back += 1;
+            }
+            let mut len = BLOCK_SIZE;
+            while j + len < src.len() && i + len < trg.len() && src[j + len] == trg[i + len] {
+                len += 1;
+            }
+
+            if save_from != i - back {
+                Insert::serialize(&trg[save_from..i - back], out)?;
+            }
+            let m = Match { sidx: j - back, tidx: i - back, len: back + len };
+            i += write_match(src, trg, m, offset, adds, out)? - back;
+            save_from = i;
+            if i + BLOCK_SIZE <= trg.len() {
+                h = hash(&trg[i..i + BLOCK_SIZE]);
+            }
+        }
+    }
+    if save_from != trg.len() {
+        Insert::serialize(&trg[save_from..], out)?;
+    }
+    Ok(())
+}
@@ -1,9 +1,10 @@
-use crate::recover::diff::Insert;
+use crate::recover::diff::{CommandEncoding, Insert};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, BufWriter, Cursor, Read, Seek, Write};
+use zstd::{encode_all, Encoder};
 
-use super::diff_gen::DiffGenerator;
-use super::recover::recover;
+use super::diff_gen::DiffEngine;
+use super::recover::{recover, recover_ops, recover_to, recover_with, Instruction};
 
 #[derive(Clone)]
 pub struct SnapshotHeader {
@@ -12,6 +13,10 @@ pub struct SnapshotHeader {
     pub file_len: u64,
     pub pos: u64,
     pub is_zipped: bool,
+    // The payload names another snapshot holding the same content, see `save_reference`
+    pub is_reference: bool,
+    // Encoding of the diff commands, see `diff`
+    pub encoding: CommandEncoding,
 }
 
 impl Default for SnapshotHeader {
@@ -22,67 +27,293 @@ impl Default for SnapshotHeader {
             file_len: 0,
             pos: u64::MAX,
             is_zipped: false,
+            is_reference: false,
+            encoding: CommandEncoding::Fixed,
         }
     }
 }
 
 impl SnapshotHeader {
     pub const SERIZIZED_SIZE: usize = 25;
-    pub fn save_new<W: Write + Seek>(f: &mut W, data: &[u8]) -> io::Result<Self> {
+    // `level` is the zstd compression level of the payload, 0 stores it uncompressed
+    pub fn save_new<W: Write + Seek>(f: &mut W, data: &[u8], level: i32) -> io::Result<Self> {
+        let mut payload: Vec<u8> = Vec::with_capacity(data.len() + Insert::SERIZIZED_SIZE as usize);
+        Insert::serialize(data, &mut payload)?;
+        Self::write(f, u64::MAX, payload, data.len() as u64, level)
+    }
+
+    // Like `save_new` with the `len` bytes of the file read from `data`, so they are never held
+    // in memory whole
+    pub fn save_new_from<W: Write + Seek, R: Read>(f: &mut W, data: &mut R, len: u64, level: i32) -> io::Result<Self> {
+        Self::write_with(f, u64::MAX, level, |out| {
+            Insert::serialize_from(data, len, out)?;
+            Ok(len)
+        })
+    }
+
+    // Stores a file whose content is already stored by the snapshot at `pos` in the package of
+    // `path`, so identical files and renamed files are kept once
+    pub fn save_reference<W: Write + Seek>(f: &mut W, path: &str, pos: u64, file_len: u64) -> io::Result<Self> {
+        let mut payload = Vec::with_capacity(8 + path.len());
+        payload.write_u64::<BigEndian>(pos)?;
+        payload.write_all(path.as_bytes())?;
+
         f.seek(io::SeekFrom::End(0))?;
-        let snap = SnapshotHeader {
+        let snap = Self {
             depend_on: u64::MAX,
-            payload_len: data.len() as u64 + Insert::SERIZIZED_SIZE,
-            file_len: data.len() as u64,
+            payload_len: payload.len() as u64,
+            file_len,
             pos: f.stream_position()? + Self::SERIZIZED_SIZE as u64,
             is_zipped: false,
+            is_reference: true,
+            encoding: CommandEncoding::Fixed,
         };
         snap.serialize(f)?;
-        Insert::serialize(data, f)?;
+        f.write_all(&payload)?;
         Ok(snap)
     }
 
-    pub fn update<F: Read + Seek + Write>(&self, pack: &mut F, f: &[u8]) -> io::Result<Self> {
+    // Path and position of the snapshot a reference points to
+    pub fn read_reference<R: Read + Seek>(&self, pack: &mut R) -> io::Result<(String, u64)> {
+        pack.seek(io::SeekFrom::Start(self.pos))?;
+        let pos = pack.read_u64::<BigEndian>()?;
+        let mut path = vec![0u8; self.payload_len.saturating_sub(8) as usize];
+        pack.read_exact(&mut path)?;
+        let path = String::from_utf8(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((path, pos))
+    }
+
+    pub fn update<F: Read + Seek + Write>(&self, pack: &mut F, f: &[u8], engine: DiffEngine, level: i32) -> io::Result<Self> {
         let data = recover(pack, self.clone())?; // self.file_len as usize + f.len()
-        let mut diff = DiffGenerator::new();
-        diff.init_new(data, f)?;
+        self.update_from(pack, data, f, engine, level)
+    }
+
+    // Like `update` with the content of this snapshot already recovered, e.g. from a
+    // chain starting in another package
+    pub fn update_from<F: Read + Seek + Write>(&self, pack: &mut F, data: Vec<u8>, f: &[u8], engine: DiffEngine, level: i32) -> io::Result<Self> {
         let mut diff_data: Vec<u8> = Vec::new();
-        diff.generate(&mut diff_data)?;
+        engine.generate_at(data, f, &mut diff_data, 0, level != 0)?;
 
-        //let diff_data = encode_all(Cursor::new(diff_data), 16).expect("Compression failed");
+        Self::write(pack, self.pos - Self::SERIZIZED_SIZE as u64, diff_data, f.len() as u64, level)
+    }
 
-        pack.seek(io::SeekFrom::End(0))?;
-        let snap = Self {
-            depend_on: self.pos - Self::SERIZIZED_SIZE as u64,
+    // Stores `f` in full right after this version and replaces this one with a delta against
+    // it, so the newest version is the one recovered fastest. This version has to be a full one
+    // ending the package, which is written over from its header on. The new end has to lie past
+    // the old one, so a copy of the package still holding this version in full is never taken
+    // for one holding the delta, see `sync`. Otherwise, or if the delta is not smaller than
+    // this version, nothing is written and None is returned.
+    pub fn update_reverse<F: Read + Seek + Write>(&self, pack: &mut F, f: &[u8], engine: DiffEngine, level: i32) -> io::Result<Option<Self>> {
+        let data = recover(pack, self.clone())?;
+        let mut diff_data: Vec<u8> = Vec::new();
+        engine.generate_at(f.to_vec(), &data, &mut diff_data, 0, level != 0)?;
+        let diff_data = Self::compress(diff_data, level)?;
+
+        let mut payload: Vec<u8> = Vec::with_capacity(f.len() + Insert::SERIZIZED_SIZE as usize);
+        Insert::serialize(f, &mut payload)?;
+        let payload = Self::compress(payload, level)?;
+
+        let next = self.pos + diff_data.len() as u64;
+        let end = next + Self::SERIZIZED_SIZE as u64 + payload.len() as u64;
+        if diff_data.len() as u64 >= self.payload_len || end <= self.pos + self.payload_len {
+            return Ok(None);
+        }
+
+        let delta = Self {
+            depend_on: next,
             payload_len: diff_data.len() as u64,
+            file_len: self.file_len,
+            pos: self.pos,
+            is_zipped: level != 0,
+            is_reference: false,
+            encoding: CommandEncoding::Tagged,
+        };
+        let snap = Self {
+            depend_on: u64::MAX,
+            payload_len: payload.len() as u64,
             file_len: f.len() as u64,
-            pos: pack.stream_position()? + Self::SERIZIZED_SIZE as u64,
-            is_zipped: false,
+            pos: next + Self::SERIZIZED_SIZE as u64,
+            ..delta.clone()
         };
-        snap.serialize(pack)?;
+        pack.seek(io::SeekFrom::Start(self.pos - Self::SERIZIZED_SIZE as u64))?;
+        delta.serialize(pack)?;
         pack.write_all(&diff_data)?;
+        snap.serialize(pack)?;
+        pack.write_all(&payload)?;
+        Ok(Some(snap))
+    }
+
+    // Like `update` for versions too large to hold in memory. The new version is read from `f`
+    // up to its end and compared `window` bytes at a time with the same region of this
+    // version, widened by half a window on both sides, which `read_base` recovers from its
+    // position.
+    pub fn update_windowed<F: Write + Seek, R: Read, B: FnMut(u64, &mut [u8]) -> io::Result<()>>(
+        &self,
+        pack: &mut F,
+        f: &mut R,
+        window: u64,
+        mut read_base: B,
+        engine: DiffEngine,
+        level: i32,
+    ) -> io::Result<Self> {
+        let base_len = self.file_len;
+        let window = window.max(1);
+        Self::write_with(pack, self.pos - Self::SERIZIZED_SIZE as u64, level, |out| {
+            let mut from = 0;
+            loop {
+                let mut target = Vec::new();
+                f.by_ref().take(window).read_to_end(&mut target)?;
+                if target.is_empty() {
+                    return Ok(from);
+                }
+                let len = target.len() as u64;
+
+                let src_start = from.saturating_sub(window / 2).min(base_len);
+                let src_end = (from + len + window / 2).min(base_len);
+                let mut src = vec![0u8; (src_end - src_start) as usize];
+                read_base(src_start, &mut src)?;
+
+                engine.generate_at(src, &target, out, src_start, level != 0)?;
+                from += len;
+            }
+        })
+    }
+
+    fn write<W: Write + Seek>(
+        f: &mut W,
+        depend_on: u64,
+        payload: Vec<u8>,
+        file_len: u64,
+        level: i32,
+    ) -> io::Result<Self> {
+        let is_zipped = level != 0;
+        let payload = Self::compress(payload, level)?;
+
+        f.seek(io::SeekFrom::End(0))?;
+        let snap = Self {
+            depend_on,
+            payload_len: payload.len() as u64,
+            file_len,
+            pos: f.stream_position()? + Self::SERIZIZED_SIZE as u64,
+            is_zipped,
+            is_reference: false,
+            encoding: CommandEncoding::Tagged,
+        };
+        snap.serialize(f)?;
+        f.write_all(&payload)?;
+        Ok(snap)
+    }
+
+    fn compress(payload: Vec<u8>, level: i32) -> io::Result<Vec<u8>> {
+        if level != 0 {
+            encode_all(Cursor::new(payload), level)
+        } else {
+            Ok(payload)
+        }
+    }
+
+    // Writes a snapshot whose payload is streamed by `generate`, which returns the length of
+    // the file. The header is completed once the payload is written.
+    fn write_with<W: Write + Seek, G: FnOnce(&mut dyn Write) -> io::Result<u64>>(
+        f: &mut W,
+        depend_on: u64,
+        level: i32,
+        generate: G,
+    ) -> io::Result<Self> {
+        let start = f.seek(io::SeekFrom::End(0))?;
+        let mut snap = Self {
+            depend_on,
+            payload_len: 0,
+            file_len: 0,
+            pos: start + Self::SERIZIZED_SIZE as u64,
+            is_zipped: level != 0,
+            is_reference: false,
+            encoding: CommandEncoding::Tagged,
+        };
+        snap.serialize(f)?;
+        if snap.is_zipped {
+            let mut encoder = Encoder::new(BufWriter::new(&mut *f), level)?;
+            snap.file_len = generate(&mut encoder)?;
+            encoder.finish()?.flush()?;
+        } else {
+            let mut out = BufWriter::new(&mut *f);
+            snap.file_len = generate(&mut out)?;
+            out.flush()?;
+        }
+
+        let end = f.stream_position()?;
+        snap.payload_len = end - snap.pos;
+        f.seek(io::SeekFrom::Start(start))?;
+        snap.serialize(f)?;
+        f.seek(io::SeekFrom::Start(end))?;
         Ok(snap)
     }
 
     pub fn recover<R: Read + Seek>(&self, pack: &mut R) -> io::Result<Vec<u8>> {
-        Ok(recover(pack, self.clone())?)
+        recover(pack, self.clone())
+    }
+
+    // Recovers a snapshot whose chain may start with a reference, `resolve` carries out the
+    // remaining instructions on the content it points to
+    pub fn recover_with<R: Read + Seek, F: FnMut(&SnapshotHeader, &mut R, Vec<Instruction>, &mut [u8]) -> io::Result<()>>(
+        &self,
+        pack: &mut R,
+        resolve: F,
+    ) -> io::Result<Vec<u8>> {
+        recover_with(pack, self.clone(), resolve)
+    }
+
+    // Recovers parts of the version into `file`, see `Instruction`
+    pub fn recover_ops<R: Read + Seek, F: FnMut(&SnapshotHeader, &mut R, Vec<Instruction>, &mut [u8]) -> io::Result<()>>(
+        &self,
+        pack: &mut R,
+        ops: Vec<Instruction>,
+        file: &mut [u8],
+        resolve: F,
+    ) -> io::Result<()> {
+        recover_ops(pack, self.clone(), ops, file, resolve)
+    }
+
+    // Writes the version to `out` without holding more than `window` bytes of it in memory
+    pub fn recover_to<R: Read + Seek, W: Write, F: FnMut(&SnapshotHeader, &mut R, Vec<Instruction>, &mut [u8]) -> io::Result<()>>(
+        &self,
+        pack: &mut R,
+        out: &mut W,
+        window: u64,
+        resolve: F,
+    ) -> io::Result<()> {
+        recover_to(pack, self.clone(), out, window, resolve)
     }
 
     pub fn serialize<W: Write>(&self, out: &mut W) -> io::Result<()> {
         out.write_u64::<BigEndian>(self.depend_on)?;
         out.write_u64::<BigEndian>(self.payload_len)?;
         out.write_u64::<BigEndian>(self.file_len)?;
-        out.write_u8(self.is_zipped as u8)?;
+        let encoding = match self.encoding {
+            CommandEncoding::Fixed => 0,
+            CommandEncoding::Varint => 4,
+            CommandEncoding::Tagged => 8,
+        };
+        out.write_u8(self.is_zipped as u8 | (self.is_reference as u8) << 1 | encoding)?;
         Ok(())
     }
 
     pub fn deserialize<R: Read + Seek>(r: &mut R) -> io::Result<Self> {
-        let mut res = Self::default();
-        res.depend_on = r.read_u64::<BigEndian>()?;
-        res.payload_len = r.read_u64::<BigEndian>()?;
-        res.file_len = r.read_u64::<BigEndian>()?;
+        let mut res = Self {
+            depend_on: r.read_u64::<BigEndian>()?,
+            payload_len: r.read_u64::<BigEndian>()?,
+            file_len: r.read_u64::<BigEndian>()?,
+            ..Self::default()
+        };
         let bits = r.read_u8()?;
         res.is_zipped = (bits & 1) != 0;
+        res.is_reference = (bits & 2) != 0;
+        res.encoding = match bits & 12 {
+            0 => CommandEncoding::Fixed,
+            4 => CommandEncoding::Varint,
+            8 => CommandEncoding::Tagged,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown diff command encoding")),
+        };
         res.pos = r.stream_position()?;
         Ok(res)
     }
@@ -1,30 +1,25 @@
 #[test]
 pub fn test() {
+    use super::diff_gen::DiffEngine;
     use super::snapshot::SnapshotHeader;
-    use std::{
-        fs::{File, OpenOptions},
-        io::Read,
-    };
+    use std::io::Cursor;
+
+    // Two versions of a region file, the second one with moved and changed chunks
+    let mut seed: u32 = 5;
+    let data1: Vec<u8> = (0..300_000).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }).collect();
+    let mut data2 = data1[100_000..200_000].to_vec();
+    data2.extend_from_slice(&data1[..100_000]);
+    data2.extend_from_slice(&data1[200_000..]);
+    data2[5_000..9_096].fill(0);
+    data2.truncate(290_000);
 
-    let mut f1 = File::open("/home/vr/Desktop/MineGit/test_files/r.0.0.mca")
-        .expect("/home/vr/Desktop/MineGit/test_files/r.0.0.mca was not found for testing");
-    let mut f2 = File::open("/home/vr/Desktop/MineGit/test_files/r.0.1.mca")
-        .expect("/home/vr/Desktop/MineGit/test_files/r.0.1.mca was not found for testing");
-    let mut data1 = Vec::new();
-    let mut data2 = Vec::new();
-    f1.read_to_end(&mut data1).unwrap();
-    f2.read_to_end(&mut data2).unwrap();
-
-    let mut pack = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .read(true)
-        .open("/home/vr/Desktop/MineGit/test_files/pkg.pkg")
-        .unwrap();
-
-    let snap1 = SnapshotHeader::save_new(&mut pack, &data1).unwrap();
-    let snap2 = snap1.update(&mut pack, &data2).unwrap();
+    let mut pack = Cursor::new(Vec::new());
+
+    let snap1 = SnapshotHeader::save_new(&mut pack, &data1, 0).unwrap();
+    let snap2 = snap1.update(&mut pack, &data2, DiffEngine::SuffixArray, 0).unwrap();
 
     let _data1 = snap1.recover(&mut pack).unwrap();
     let _data2 = snap2.recover(&mut pack).unwrap();
@@ -35,8 +30,8 @@ pub fn test() {
         panic!("Recover test fail because of incorrect snap2 recovery");
     }
 
-    let snap3 = snap2.update(&mut pack, &data1).unwrap();
-    let snap4 = snap3.update(&mut pack, &data2).unwrap();
+    let snap3 = snap2.update(&mut pack, &data1, DiffEngine::SuffixArray, 0).unwrap();
+    let snap4 = snap3.update(&mut pack, &data2, DiffEngine::SuffixArray, 0).unwrap();
     if snap4
         .recover(&mut pack)
         .expect("Error while recovering snap4")
@@ -46,7 +41,7 @@ pub fn test() {
     }
 
     let snap3 = snap1
-        .update(&mut pack, &data2)
+        .update(&mut pack, &data2, DiffEngine::SuffixArray, 0)
         .expect("Error while updating snap3 in test");
     let data3 = snap3
         .recover(&mut pack)
@@ -55,3 +50,223 @@ pub fn test() {
         panic!("Recover test fail because of incorrect snap3 recovery");
     }
 }
+
+#[test]
+pub fn encodes_long_commands() {
+    use super::diff::{Add, CommandEncoding, Copy, DiffCommandHeader, Insert, MAX_LEN};
+    use std::io::Cursor;
+
+    let mut out = Vec::new();
+    Copy { sidx: 5 << 32, len: 3 << 31 }.serialize(&mut out).unwrap();
+    Copy { sidx: u64::MAX, len: MAX_LEN }.serialize(&mut out).unwrap();
+    Insert::serialize(&[1, 2, 3], &mut out).unwrap();
+    Add::serialize(7, &[0, 1], &mut out).unwrap();
+    assert!(Copy { sidx: 0, len: MAX_LEN + 1 }.serialize(&mut Vec::new()).is_err());
+
+    let mut r = Cursor::new(&out);
+    match DiffCommandHeader::deserialize(&mut r, CommandEncoding::Tagged).unwrap().0 {
+        DiffCommandHeader::Copy(c) => assert_eq!((c.sidx, c.len), (5 << 32, 3 << 31)),
+        _ => panic!("Expected a copy"),
+    }
+    match DiffCommandHeader::deserialize(&mut r, CommandEncoding::Tagged).unwrap().0 {
+        DiffCommandHeader::Copy(c) => assert_eq!((c.sidx, c.len), (u64::MAX, MAX_LEN)),
+        _ => panic!("Expected a copy"),
+    }
+    match DiffCommandHeader::deserialize(&mut r, CommandEncoding::Tagged).unwrap() {
+        (DiffCommandHeader::Insert(i), 1) => assert_eq!(i.len, 3),
+        _ => panic!("Expected an insert"),
+    }
+    r.set_position(r.position() + 3);
+    match DiffCommandHeader::deserialize(&mut r, CommandEncoding::Tagged).unwrap() {
+        (DiffCommandHeader::Add(a), 2) => assert_eq!((a.sidx, a.len), (7, 2)),
+        _ => panic!("Expected an add"),
+    }
+
+    // Snapshots of older versions use one bit for the type or 32-bit fields
+    match DiffCommandHeader::deserialize(&mut Cursor::new([5]), CommandEncoding::Varint).unwrap() {
+        (DiffCommandHeader::Insert(i), 1) => assert_eq!(i.len, 2),
+        _ => panic!("Expected an insert"),
+    }
+    let mut r = Cursor::new([0x80, 0, 0, 2]);
+    match DiffCommandHeader::deserialize(&mut r, CommandEncoding::Fixed).unwrap() {
+        (DiffCommandHeader::Insert(i), 4) => assert_eq!(i.len, 2),
+        _ => panic!("Expected an insert"),
+    }
+    assert!(DiffCommandHeader::deserialize(&mut Cursor::new([0xff; 11]), CommandEncoding::Tagged).is_err());
+    assert!(DiffCommandHeader::deserialize(&mut Cursor::new([3, 0]), CommandEncoding::Tagged).is_err());
+}
+
+#[test]
+pub fn diffs_and_recovers_in_windows() {
+    use super::diff_gen::DiffEngine;
+    use super::recover::Instruction;
+    use super::snapshot::SnapshotHeader;
+    use std::io::{self, Cursor};
+
+    let no_reference = |_: &SnapshotHeader, _: &mut Cursor<Vec<u8>>, _: Vec<Instruction>, _: &mut [u8]| -> io::Result<()> {
+        panic!("No references in the package")
+    };
+
+    let mut seed: u32 = 1;
+    let base: Vec<u8> = (0..100_000).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }).collect();
+    // Shifted by an insert, with a changed part further on
+    let mut new = base[..30_000].to_vec();
+    new.extend_from_slice(&[7; 500]);
+    new.extend_from_slice(&base[30_000..]);
+    new[70_000..70_100].fill(0);
+
+    for level in [0, 3] {
+        let mut pack = Cursor::new(Vec::new());
+        let base_snap = SnapshotHeader::save_new_from(&mut pack, &mut Cursor::new(&base), base.len() as u64, level).unwrap();
+        let base_pack = pack.clone();
+        let read_base = |from, buf: &mut [u8]| {
+            let ops = vec![Instruction { from, to: 0, len: buf.len() as u64 }];
+            base_snap.recover_ops(&mut base_pack.clone(), ops, buf, no_reference)
+        };
+        let new_snap = base_snap.update_windowed(&mut pack, &mut Cursor::new(&new), 4096, read_base, DiffEngine::SuffixArray, level).unwrap();
+        if level == 0 {
+            assert!(new_snap.payload_len < 2_000);
+        }
+
+        assert_eq!(new_snap.recover(&mut pack).unwrap(), new);
+
+        let mut out = Vec::new();
+        new_snap.recover_to(&mut pack, &mut out, 1000, no_reference).unwrap();
+        assert_eq!(out, new);
+
+        // Overlapping ranges of the inserted part and the rest
+        let mut file = vec![0u8; 900];
+        let ops = vec![
+            Instruction { from: 29_900, to: 0, len: 400 },
+            Instruction { from: 29_800, to: 400, len: 500 },
+        ];
+        new_snap.recover_ops(&mut pack, ops, &mut file, no_reference).unwrap();
+        assert_eq!(file[..400], new[29_900..30_300]);
+        assert_eq!(file[400..], new[29_800..30_300]);
+
+        let ops = vec![Instruction { from: 100_400, to: 0, len: 200 }];
+        assert!(new_snap.recover_ops(&mut pack, ops, &mut [0u8; 200], no_reference).is_err());
+    }
+}
+
+#[test]
+pub fn rolling_hash_engine_roundtrips() {
+    use super::diff_gen::DiffEngine;
+    use super::snapshot::SnapshotHeader;
+    use std::io::Cursor;
+
+    let mut seed: u32 = 7;
+    let base: Vec<u8> = (0..200_000).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }).collect();
+    let mut new = base.clone();
+    new[1000..1004].copy_from_slice(&[1, 2, 3, 4]);
+    new.drain(50_000..50_100);
+    new.splice(120_000..120_000, [9; 300]);
+    new.extend_from_slice(b"tail");
+
+    let mut pack = Cursor::new(Vec::new());
+    let snap = SnapshotHeader::save_new(&mut pack, &base, 0).unwrap();
+    let rolling = snap.update(&mut pack, &new, DiffEngine::RollingHash, 0).unwrap();
+    assert_eq!(rolling.recover(&mut pack).unwrap(), new);
+    assert!(rolling.payload_len < 1_000);
+
+    // Versions shorter than a block are inserted
+    for data in [&b""[..], b"short", &base[..31]] {
+        let small = snap.update(&mut pack, data, DiffEngine::RollingHash, 0).unwrap();
+        assert_eq!(small.recover(&mut pack).unwrap(), data);
+        let empty = SnapshotHeader::save_new(&mut pack, b"", 0).unwrap();
+        let grown = empty.update(&mut pack, &new, DiffEngine::RollingHash, 0).unwrap();
+        assert_eq!(grown.recover(&mut pack).unwrap(), new);
+    }
+}
+
+#[test]
+pub fn adds_small_changes() {
+    use super::diff_gen::DiffEngine;
+    use super::recover::Instruction;
+    use super::snapshot::SnapshotHeader;
+    use std::io::Cursor;
+
+    // Records with a counter changed in every one of them
+    let base: Vec<u8> = (0..2000u32).flat_map(|i| {
+        let mut record = format!("{{name:\"entity{i}\",pos:[1.5,64.0,-3.25]}}").into_bytes();
+        record.extend_from_slice(&i.to_be_bytes());
+        record
+    }).collect();
+    let mut new = base.clone();
+    for k in (40..new.len()).step_by(97) {
+        new[k] = new[k].wrapping_add(1);
+    }
+
+    for engine in [DiffEngine::SuffixArray, DiffEngine::RollingHash] {
+        let mut pack = Cursor::new(Vec::new());
+        let snap = SnapshotHeader::save_new(&mut pack, &base, 0).unwrap();
+        let plain = snap.update(&mut pack, &new, engine, 0).unwrap();
+        let added = snap.update(&mut pack, &new, engine, 3).unwrap();
+        // Smaller than the copies and inserts compressed alike
+        let payload = &pack.get_ref()[plain.pos as usize..(plain.pos + plain.payload_len) as usize];
+        let compressed = zstd::encode_all(payload, 3).unwrap();
+        assert!(added.payload_len < compressed.len() as u64 / 2);
+
+        let changed = added.update(&mut pack, &base, engine, 3).unwrap();
+        for s in [&plain, &added] {
+            assert_eq!(s.recover(&mut pack).unwrap(), new);
+        }
+        assert_eq!(changed.recover(&mut pack).unwrap(), base);
+
+        // Parts of a version built from adds over adds
+        let mut file = vec![0u8; 600];
+        let ops = vec![
+            Instruction { from: 1000, to: 0, len: 300 },
+            Instruction { from: 950, to: 300, len: 300 },
+        ];
+        changed.recover_ops(&mut pack, ops, &mut file, |_, _, _, _| unreachable!()).unwrap();
+        assert_eq!(file[..300], base[1000..1300]);
+        assert_eq!(file[300..], base[950..1250]);
+    }
+}
+
+#[test]
+pub fn reverses_deltas_in_place() {
+    use super::diff_gen::DiffEngine;
+    use super::snapshot::SnapshotHeader;
+    use std::io::{Cursor, Seek, SeekFrom};
+
+    let mut seed: u32 = 3;
+    let v0: Vec<u8> = (0..100_000).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }).collect();
+    let mut v1 = v0.clone();
+    v1[500..520].fill(1);
+    let mut v2 = v1.clone();
+    v2.extend_from_slice(b"appended");
+
+    let mut pack = Cursor::new(Vec::new());
+    let first = SnapshotHeader::save_new(&mut pack, &v0, 0).unwrap();
+    let second = first.update_reverse(&mut pack, &v1, DiffEngine::SuffixArray, 0).unwrap().unwrap();
+    let third = second.update_reverse(&mut pack, &v2, DiffEngine::SuffixArray, 0).unwrap().unwrap();
+    assert_eq!(pack.get_ref().len() as u64, third.pos + third.payload_len);
+    assert_eq!(third.depend_on, u64::MAX);
+
+    // The older versions keep their positions and depend on the newer ones
+    let mut versions = Vec::new();
+    for (snap, data) in [(&first, &v0), (&second, &v1), (&third, &v2)] {
+        pack.seek(SeekFrom::Start(snap.pos - SnapshotHeader::SERIZIZED_SIZE as u64)).unwrap();
+        let header = SnapshotHeader::deserialize(&mut pack).unwrap();
+        assert_eq!(header.recover(&mut pack).unwrap(), *data);
+        versions.push(header);
+    }
+    assert_eq!(versions[0].depend_on, second.pos - SnapshotHeader::SERIZIZED_SIZE as u64);
+    assert!(versions[0].payload_len < 1_000);
+
+    // A shrinking version ending before the old one is stored in full instead
+    let shrunk = third.update_reverse(&mut pack, &v2[..10], DiffEngine::SuffixArray, 0).unwrap();
+    assert!(shrunk.is_none());
+    assert_eq!(pack.get_ref().len() as u64, third.pos + third.payload_len);
+}
@@ -0,0 +1,307 @@
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+
+use crate::committer;
+use crate::config::{Config, RemoteConfig};
+use crate::initializer;
+use crate::journal;
+use crate::lock::RepoLock;
+use crate::migrate;
+use crate::protocol::{self, Connection};
+use crate::savefiles::{DIRECTORY_NAME, IGNORE_FILE_NAME};
+use crate::sync::{self, RepoState};
+use crate::utils::fs_utils;
+
+pub fn add(target_path: &str, name: &str, url: &str) -> Result<(), Box<dyn Error>> {
+    let mut config = Config::load(target_path)?;
+    if config.remotes.contains_key(name) {
+        return Err(format!("Remote {name} already exists").into());
+    }
+    config.remotes.insert(
+        name.to_string(),
+        RemoteConfig {
+            url: url.to_string(),
+        },
+    );
+    config.save(target_path)
+}
+
+pub fn remove(target_path: &str, name: &str) -> Result<(), Box<dyn Error>> {
+    let mut config = Config::load(target_path)?;
+    if config.remotes.remove(name).is_none() {
+        return Err(format!("No remote named {name}").into());
+    }
+    config.save(target_path)
+}
+
+pub fn list(target_path: &str) -> Result<(), Box<dyn Error>> {
+    for (name, remote) in Config::load(target_path)?.remotes {
+        println!("{name}\t{}", remote.url);
+    }
+    Ok(())
+}
+
+// Where a remote repository is reached
+pub enum Location {
+    // World directory of the repository
+    Path(String),
+    // `tcp://host:port`, served by `minegit serve --listen`
+    Tcp(String),
+    // `ssh://[user@]host[:port]/path`, runs `minegit serve --stdio --allow-push` on the host,
+    // ssh already authenticates the user
+    Ssh {
+        host: String,
+        port: Option<u16>,
+        path: String,
+    },
+}
+
+// Returns the name and location of a remote. `remote` is a configured name, a path or a URL,
+// without it the only configured remote is used.
+pub fn resolve(
+    target_path: &str,
+    remote: Option<&str>,
+) -> Result<(String, Location), Box<dyn Error>> {
+    let remotes = Config::load(target_path)?.remotes;
+    let (name, url) = match remote {
+        Some(remote) => match remotes.get(remote) {
+            Some(config) => (remote.to_string(), config.url.clone()),
+            None => (remote.to_string(), remote.to_string()),
+        },
+        None => match remotes.len() {
+            1 => {
+                let (name, config) = remotes.into_iter().next().unwrap();
+                (name, config.url)
+            }
+            0 => return Err("No remote configured, add one with `minegit remote add`".into()),
+            _ => return Err("Several remotes configured, name the one to use".into()),
+        },
+    };
+
+    if let Some(address) = url.strip_prefix("tcp://") {
+        return Ok((
+            name,
+            Location::Tcp(address.trim_end_matches('/').to_string()),
+        ));
+    }
+    if let Some(rest) = url.strip_prefix("ssh://") {
+        let (authority, path) = rest.split_once('/').ok_or("An ssh URL needs a path")?;
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse()?)),
+            None => (authority, None),
+        };
+        // ssh://host/~/world is relative to the home directory
+        let path = match path.strip_prefix("~/") {
+            Some(relative) => relative.to_string(),
+            None => format!("/{path}"),
+        };
+        return Ok((
+            name,
+            Location::Ssh {
+                host: host.to_string(),
+                port,
+                path,
+            },
+        ));
+    }
+
+    // Relative paths are relative to the world
+    let path = fs_utils::build_path([target_path, &url])?;
+    if let (Ok(a), Ok(b)) = (fs::canonicalize(&path), fs::canonicalize(target_path)) {
+        if a == b {
+            return Err(format!("Remote {name} is this repository").into());
+        }
+    }
+    Ok((name, Location::Path(path)))
+}
+
+type RemoteConnection = Connection<Box<dyn Read>, Box<dyn Write>>;
+
+// Connects to a network remote and runs `f`, the ssh process is waited for afterwards
+fn with_connection<T>(
+    location: &Location,
+    f: impl FnOnce(&mut RemoteConnection) -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    match location {
+        Location::Path(_) => unreachable!("filesystem remotes do not use the protocol"),
+        Location::Tcp(address) => {
+            let stream = TcpStream::connect(address)?;
+            // A stalled server would keep the lock of the local repository otherwise
+            stream.set_read_timeout(Some(protocol::TCP_TIMEOUT))?;
+            stream.set_write_timeout(Some(protocol::TCP_TIMEOUT))?;
+            let mut connection: RemoteConnection =
+                Connection::new(Box::new(stream.try_clone()?), Box::new(stream));
+            f(&mut connection)
+        }
+        Location::Ssh { host, port, path } => {
+            let mut command = Command::new("ssh");
+            if let Some(port) = port {
+                command.arg("-p").arg(port.to_string());
+            }
+            let mut child = command
+                .arg(host)
+                .arg(format!(
+                    "cd {} && minegit serve --stdio --allow-push",
+                    quote_shell_argument(path)
+                ))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(|e| format!("Failed to run ssh: {e}"))?;
+
+            let reader: Box<dyn Read> = Box::new(child.stdout.take().unwrap());
+            let writer: Box<dyn Write> = Box::new(child.stdin.take().unwrap());
+            let result = f(&mut Connection::new(reader, writer));
+            // The connection is closed, so the server exits
+            let status = child.wait()?;
+            match result {
+                Ok(_) if !status.success() => Err(format!("ssh exited with {status}").into()),
+                result => result,
+            }
+        }
+    }
+}
+
+fn quote_shell_argument(argument: &str) -> String {
+    format!("'{}'", argument.replace('\'', "'\\''"))
+}
+
+// Sends the missing commits to the remote and moves its head to the local head.
+// A remote directory without a repository gets an empty one.
+pub fn push(target_path: &str, remote: Option<&str>, wait: bool) -> Result<(), Box<dyn Error>> {
+    let (name, location) = resolve(target_path, remote)?;
+    let Location::Path(path) = location else {
+        let count = with_connection(&location, |connection| connection.push(target_path))?;
+        println!("Pushed {count} commit(s) to {name}");
+        return Ok(());
+    };
+
+    let root_path = fs_utils::build_path([&path, DIRECTORY_NAME])?;
+    if !fs_utils::is_path_exists(&root_path) {
+        fs::create_dir_all(&path)?;
+        initializer::init_empty(&path)?;
+        // The remote ignores the same files
+        let ignore_path = fs_utils::build_path([target_path, DIRECTORY_NAME, IGNORE_FILE_NAME])?;
+        if fs_utils::is_path_exists(&ignore_path) {
+            fs::copy(
+                ignore_path,
+                fs_utils::build_path([&root_path, IGNORE_FILE_NAME])?,
+            )?;
+        }
+        println!("Created an empty repository in {path}");
+    }
+
+    let _lock = RepoLock::acquire(&path, wait)?;
+    journal::recover_all(&path)?;
+    migrate::check_format(&path)?;
+    sync::check_accepts_push(&path)?;
+
+    let local = RepoState::read(target_path)?;
+    if committer::get_commit_count(&path)? as usize > local.commits.len() {
+        return Err(
+            format!("{name} has commits that are not present locally, fetch them first").into(),
+        );
+    }
+
+    let count = sync::transfer(target_path, &path)?;
+    if let Some(head) = local.head {
+        committer::write_head(&path, head)?;
+    }
+    println!("Pushed {count} commit(s) to {name}");
+    Ok(())
+}
+
+// Receives the commits missing locally, the world and head are not changed.
+// Returns the head of the remote.
+pub fn fetch(target_path: &str, remote: Option<&str>, wait: bool) -> Result<u32, Box<dyn Error>> {
+    let (name, location) = resolve(target_path, remote)?;
+    let Location::Path(path) = location else {
+        let (count, head) = with_connection(&location, |connection| connection.fetch(target_path))?;
+        println!("Fetched {count} commit(s) from {name}");
+        return head.ok_or_else(|| format!("{name} has no commits").into());
+    };
+    if !fs_utils::is_path_exists(&fs_utils::build_path([&path, DIRECTORY_NAME])?) {
+        return Err(format!("{path} is not a MineGit repository").into());
+    }
+
+    let _lock = RepoLock::acquire(&path, wait)?;
+    journal::recover_all(&path)?;
+    migrate::check_format(&path)?;
+
+    let count = sync::transfer(&path, target_path)?;
+    println!("Fetched {count} commit(s) from {name}");
+    committer::get_head(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::savefiles::DATA_DIRECTORY_NAME;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+
+    fn package(world: &str) -> Vec<u8> {
+        read_world_file(
+            world,
+            &format!("{DIRECTORY_NAME}/{DATA_DIRECTORY_NAME}/region/r.0.0.mca.pkg"),
+        )
+    }
+
+    #[test]
+    fn pushes_and_fetches_through_a_directory() {
+        let source = temp_dir("remote-source");
+        let mut region: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        write_world_file(&source, "region/r.0.0.mca", &region);
+        initializer::init(&source).unwrap();
+        region[100..200].fill(1);
+        write_world_file(&source, "region/r.0.0.mca", &region);
+        committer::add_commit(&source, "changed", Vec::new()).unwrap();
+
+        // Pushing to a missing directory creates the repository
+        let shared = format!("{}/repo", temp_dir("remote-shared"));
+        add(&source, "origin", &shared).unwrap();
+        push(&source, None, false).unwrap();
+        assert_eq!(committer::get_commit_count(&shared).unwrap(), 2);
+        assert_eq!(committer::get_head(&shared).unwrap(), 1);
+        assert_eq!(package(&shared), package(&source));
+
+        let clone = temp_dir("remote-clone");
+        initializer::init_empty(&clone).unwrap();
+        add(&clone, "origin", &shared).unwrap();
+        assert_eq!(fetch(&clone, Some("origin"), false).unwrap(), 1);
+        assert_eq!(committer::get_commit_count(&clone).unwrap(), 2);
+        assert_eq!(package(&clone), package(&source));
+
+        // Only the new commit is sent, and a repository missing it can not push
+        region[5000..5100].fill(2);
+        write_world_file(&source, "region/r.0.0.mca", &region);
+        committer::add_commit(&source, "changed again", Vec::new()).unwrap();
+        push(&source, None, false).unwrap();
+        assert_eq!(package(&shared), package(&source));
+        assert!(push(&clone, None, false).is_err());
+        assert_eq!(fetch(&clone, None, false).unwrap(), 2);
+        assert_eq!(package(&clone), package(&source));
+    }
+
+    #[test]
+    fn refuses_to_push_into_a_repository_with_a_world() {
+        let source = temp_dir("remote-world-source");
+        write_world_file(&source, "level.dat", b"level");
+        initializer::init(&source).unwrap();
+
+        let target = temp_dir("remote-world-target");
+        initializer::init_empty(&target).unwrap();
+        add(&source, "origin", &target).unwrap();
+        write_world_file(&target, "level.dat", b"other level");
+        assert!(push(&source, None, false).is_err());
+        assert_eq!(committer::get_commit_count(&target).unwrap(), 0);
+
+        // Ignored files are not part of the world
+        fs_utils::remove_file(&format!("{target}/level.dat")).unwrap();
+        write_world_file(&target, "session.lock", b"");
+        push(&source, None, false).unwrap();
+        assert_eq!(committer::get_head(&target).unwrap(), 0);
+    }
+}
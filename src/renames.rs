@@ -0,0 +1,231 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+
+use crate::committer::{self, ChangeKind};
+use crate::savefiles::{CommitInfo, FileInfo, DIRECTORY_NAME};
+use crate::utils::fs_utils;
+
+// Detection of renamed and moved files.
+//
+// A file at a new path is a rename of a deleted one if both have the same content, or if
+// they have the same file name and most of the new content is found in the old one. Moving
+// a dimension (`world_nether/DIM-1` to `DIM-1`) keeps the names of its region files, so
+// only files with the same name are compared, which keeps the number of recovered files low.
+
+// Share of the new file found in the old one, in percent, for a rename
+pub const MIN_SIMILARITY: u32 = 50;
+
+// Region files are made of 4 KiB sectors, unchanged chunks keep their blocks
+const BLOCK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+    // Share of the content found in the old file in percent, if it was measured
+    pub similarity: Option<u32>,
+}
+
+// Percentage of the blocks of `new` that are also in `old`
+pub fn similarity(old: &[u8], new: &[u8]) -> u32 {
+    if new.is_empty() {
+        return if old.is_empty() { 100 } else { 0 };
+    }
+
+    let old_blocks: HashSet<u64> = old.chunks(BLOCK_SIZE).map(block_hash).collect();
+    let blocks = new.chunks(BLOCK_SIZE).count();
+    let found = new
+        .chunks(BLOCK_SIZE)
+        .filter(|block| old_blocks.contains(&block_hash(block)))
+        .count();
+    (found * 100 / blocks) as u32
+}
+
+fn block_hash(block: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    block.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Pairs added paths with deleted paths of the same file name, preferring the deleted path
+// with the longest common suffix of directories. Returns (deleted, added) pairs.
+pub fn pair_by_name<'a>(added: &[&'a str], deleted: &[&'a str]) -> Vec<(&'a str, &'a str)> {
+    let mut by_name: HashMap<&str, Vec<&str>> = HashMap::new();
+    for path in deleted {
+        by_name.entry(file_name(path)).or_default().push(path);
+    }
+
+    let mut pairs = Vec::new();
+    for to in added {
+        let Some(candidates) = by_name.get_mut(file_name(to)) else {
+            continue;
+        };
+        let Some((i, _)) = candidates
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, from)| (common_suffix(from, to), std::cmp::Reverse(**from)))
+        else {
+            continue;
+        };
+        pairs.push((candidates.remove(i), *to));
+    }
+    pairs
+}
+
+// Renames among the uncommitted changes of the world, the renamed files are still listed
+// as added and deleted in `changes`
+pub fn uncommitted_renames(
+    target_path: &str,
+    changes: &[(String, ChangeKind)],
+) -> Result<Vec<Rename>, Box<dyn Error>> {
+    let Ok(head) = committer::get_head(target_path) else {
+        return Ok(Vec::new());
+    };
+    if head >= committer::get_commit_count(target_path)? {
+        return Ok(Vec::new());
+    }
+    let head_info = committer::get_commit_info_by_id(target_path, head)?;
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+
+    let mut deleted: Vec<(&str, &FileInfo)> = Vec::new();
+    let mut added: Vec<(&str, [u8; 32])> = Vec::new();
+    for (path, kind) in changes {
+        match kind {
+            ChangeKind::Deleted => {
+                if let Some(file_info) = head_info.file_info.get(path) {
+                    deleted.push((path, file_info));
+                }
+            }
+            ChangeKind::Added => {
+                let world_path = fs_utils::build_path([target_path, path])?;
+                added.push((path, fs_utils::file_hash(&world_path)?));
+            }
+            ChangeKind::Modified => {}
+        }
+    }
+
+    // Same content first
+    let mut renames = Vec::new();
+    added.retain(|(to, hash)| {
+        match deleted
+            .iter()
+            .position(|(_, file_info)| file_info.hash == *hash)
+        {
+            Some(i) => {
+                renames.push(Rename {
+                    from: deleted.remove(i).0.to_string(),
+                    to: to.to_string(),
+                    similarity: Some(100),
+                });
+                false
+            }
+            None => true,
+        }
+    });
+
+    let deleted_paths: Vec<&str> = deleted.iter().map(|(path, _)| *path).collect();
+    let added_paths: Vec<&str> = added.iter().map(|(path, _)| *path).collect();
+    for (from, to) in pair_by_name(&added_paths, &deleted_paths) {
+        let file_info = deleted.iter().find(|(path, _)| *path == from).unwrap().1;
+        let old = committer::recover_at(&root_path, from, file_info.package_pos, 0)?;
+        let mut new = Vec::new();
+        fs_utils::read_to_end(&fs_utils::build_path([target_path, to])?, &mut new)?;
+
+        let similarity = similarity(&old, &new);
+        if similarity >= MIN_SIMILARITY {
+            renames.push(Rename {
+                from: from.to_string(),
+                to: to.to_string(),
+                similarity: Some(similarity),
+            });
+        }
+    }
+    renames.sort_by(|a, b| a.to.cmp(&b.to));
+    Ok(renames)
+}
+
+// Renames recorded by a commit: new files stored as a reference to, or a delta of, a file
+// the commit deleted
+pub fn commit_renames(
+    root_path: &str,
+    info: &CommitInfo,
+    parent_info: &CommitInfo,
+) -> io::Result<Vec<Rename>> {
+    let mut renames = Vec::new();
+    for (to, file_info) in &info.file_info {
+        if parent_info.file_info.contains_key(to) {
+            continue;
+        }
+        let chain = committer::get_chain(root_path, to, file_info.package_pos)?;
+        let Some(from) = chain.referenced_path else {
+            continue;
+        };
+
+        if info.file_info.contains_key(&from) {
+            continue;
+        }
+        let Some(old_info) = parent_info.file_info.get(&from) else {
+            continue;
+        };
+        renames.push(Rename {
+            from,
+            to: to.clone(),
+            similarity: (old_info.hash == file_info.hash).then_some(100),
+        });
+    }
+    renames.sort_by(|a, b| a.to.cmp(&b.to));
+    Ok(renames)
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+fn common_suffix(a: &str, b: &str) -> usize {
+    Path::new(a)
+        .components()
+        .rev()
+        .zip(Path::new(b).components().rev())
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_moved_dimension_by_name() {
+        let added = [
+            "DIM-1/region/r.0.0.mca",
+            "DIM-1/region/r.1.0.mca",
+            "level.dat",
+        ];
+        let deleted = [
+            "world_nether/DIM-1/region/r.0.0.mca",
+            "world_nether/DIM-1/region/r.1.0.mca",
+            "world_nether/DIM-1/poi/r.0.0.mca",
+        ];
+        assert_eq!(
+            pair_by_name(&added, &deleted),
+            vec![(deleted[0], added[0]), (deleted[1], added[1]),]
+        );
+    }
+
+    #[test]
+    fn measures_shared_blocks() {
+        let old: Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| (i / 7) as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 1;
+        assert_eq!(similarity(&old, &old), 100);
+        assert_eq!(similarity(&old, &new), 75);
+        assert_eq!(similarity(&old, &[1, 2, 3]), 0);
+    }
+}
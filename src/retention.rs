@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use chrono::Local;
+
+use crate::committer;
+use crate::config::Config;
+use crate::journal;
+use crate::savefiles::{
+    Commit, CONFIG_FILE_NAME, DIRECTORY_NAME, IGNORE_FILE_NAME, PRUNE_DIRECTORY_NAME,
+};
+use crate::utils::fs_utils;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Removes commits not kept by the retention rules of the config.
+//
+// Commits can not be removed from packages in place, since later versions are deltas of
+// earlier ones. The kept commits are instead replayed into a new repository built in
+// `.minegit/prune`, which then replaces the old commits and packages, see
+// `journal::swap_in_pruned`. Commit ids are positional, so kept commits get new ids.
+pub fn prune(target_path: &str) -> Result<(), Box<dyn Error>> {
+    let retention = Config::load(target_path)?.retention;
+    if retention.keep_last == 0 && retention.keep_days == 0 {
+        return Err(
+            "No retention rules configured, set retention.keep_last or retention.keep_days".into(),
+        );
+    }
+
+    let commits = committer::read_all_commits(target_path)?;
+    let head = committer::get_head(target_path)?;
+    let now = Local::now().timestamp();
+    let first_kept = match retention.keep_last {
+        0 => commits.len(),
+        keep_last => commits.len().saturating_sub(keep_last as usize),
+    };
+
+    // The head is always kept, so the world still matches a commit
+    let kept: Vec<_> = commits
+        .iter()
+        .enumerate()
+        .filter(|(i, commit)| {
+            commit.id == head
+                || *i >= first_kept
+                || (retention.keep_days != 0
+                    && now - commit.timestamp <= retention.keep_days as i64 * SECONDS_PER_DAY)
+        })
+        .map(|(_, commit)| commit)
+        .collect();
+
+    if kept.len() == commits.len() {
+        println!("Nothing to prune.");
+        return Ok(());
+    }
+
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+    let work_path = fs_utils::build_path([&root_path, PRUNE_DIRECTORY_NAME])?;
+    let work_root_path = fs_utils::build_path([&work_path, DIRECTORY_NAME])?;
+
+    // Left over from a prune interrupted before the rebuilt repository was complete, a
+    // complete one is swapped in by `journal::recover_prune`
+    if fs_utils::is_path_exists(&work_path) {
+        fs_utils::remove_dir_all(&work_path)?;
+    }
+    fs::create_dir_all(&work_root_path)?;
+    for name in [IGNORE_FILE_NAME, CONFIG_FILE_NAME] {
+        let path = fs_utils::build_path([&root_path, name])?;
+        if fs_utils::is_path_exists(&path) {
+            fs::copy(&path, fs_utils::build_path([&work_root_path, name])?)?;
+        }
+    }
+
+    // Replay the kept commits, each on top of its nearest kept ancestor so branches stay
+    // apart. Commits without one are put on the first kept commit.
+    let mut new_ids = HashMap::new();
+    for (new_id, commit) in kept.iter().enumerate() {
+        let parent = match kept_ancestor(&commits, commit.id, &new_ids) {
+            Some(parent) => Some(parent),
+            None => (new_id > 0).then_some(0),
+        };
+        clear_world(&work_path)?;
+        committer::write_commit_files(target_path, commit.id, &work_path)?;
+        committer::add_commit_at(
+            &work_path,
+            &work_path,
+            commit.tag_as_str()?,
+            Vec::new(),
+            commit.timestamp,
+            None,
+            parent,
+        )?;
+        new_ids.insert(commit.id, new_id as u32);
+    }
+
+    for id in committer::read_stash_ids(target_path)? {
+        if let Some(&new_id) = new_ids.get(&id) {
+            committer::add_stash_id(&work_path, new_id)?;
+        }
+    }
+    committer::write_head(&work_path, new_ids[&head])?;
+
+    journal::swap_in_pruned(target_path)?;
+
+    println!(
+        "Pruned {} commit(s), kept commits got new ids:",
+        commits.len() - kept.len()
+    );
+    for commit in kept {
+        println!("{} -> {}", commit.id, new_ids[&commit.id]);
+    }
+    Ok(())
+}
+
+// New id of the nearest ancestor of the commit that is kept
+fn kept_ancestor(commits: &[Commit], id: u32, new_ids: &HashMap<u32, u32>) -> Option<u32> {
+    let mut id = id;
+    loop {
+        let parent_id = commits.get(id as usize)?.parent_id;
+        // The first commit is its own parent, parents are always older
+        if parent_id >= id {
+            return None;
+        }
+        if let Some(&new_id) = new_ids.get(&parent_id) {
+            return Some(new_id);
+        }
+        id = parent_id;
+    }
+}
+
+// Removes everything except the repository from the directory
+fn clear_world(path: &str) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_name() == DIRECTORY_NAME {
+            continue;
+        }
+        if entry.path().is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::initializer;
+    use crate::utils::test_utils::{read_world_file, temp_dir, write_world_file};
+
+    #[test]
+    fn kept_commits_keep_their_parents() {
+        let world = temp_dir("prune-parents");
+        write_world_file(&world, "level.dat", b"0");
+        initializer::init(&world).unwrap();
+        for version in [b"1", b"2"] {
+            write_world_file(&world, "level.dat", version);
+            committer::add_commit(&world, "version", Vec::new()).unwrap();
+        }
+        // Branch off version 1
+        committer::restore(&world, 1, Vec::new(), true).unwrap();
+        write_world_file(&world, "level.dat", b"3");
+        committer::add_commit(&world, "branch", Vec::new()).unwrap();
+
+        config::set_value(&world, "retention.keep_last", "3").unwrap();
+        prune(&world).unwrap();
+
+        let parents: Vec<_> = committer::read_all_commits(&world)
+            .unwrap()
+            .iter()
+            .map(|commit| commit.parent_id)
+            .collect();
+        assert_eq!(parents, [0, 0, 0]);
+        assert_eq!(committer::get_head(&world).unwrap(), 2);
+        for (id, data) in [(0, b"1"), (1, b"2"), (2, b"3")] {
+            committer::restore(&world, id, Vec::new(), true).unwrap();
+            assert_eq!(read_world_file(&world, "level.dat"), data);
+        }
+    }
+}
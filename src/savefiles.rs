@@ -1,14 +1,42 @@
-use std::{collections::HashMap, fmt};
+use std::{collections::BTreeMap, fmt};
 
 use bitcode::{Decode, Encode};
 use bytemuck::{Pod, Zeroable};
 use chrono::DateTime;
 
 pub const DIRECTORY_NAME: &str = ".minegit";
+pub const FORMAT_FILE_NAME: &str = "format";
 pub const COMMITS_FILE_NAME: &str = "commits.byte";
 pub const HEAD_FILE_NAME: &str = "head.byte";
 pub const COMMITS_INFO_FILE_NAME: &str = "commits_info.bitcode";
+pub const DATA_DIRECTORY_NAME: &str = "data";
+pub const PRUNE_DIRECTORY_NAME: &str = "prune";
+pub const IMPORT_DIRECTORY_NAME: &str = "import";
+pub const OBJECTS_INDEX_FILE_NAME: &str = "objects.index";
 pub const IGNORE_FILE_NAME: &str = "ignore";
+pub const CONFIG_FILE_NAME: &str = "config";
+pub const HOOKS_DIRECTORY_NAME: &str = "hooks";
+pub const SESSION_LOCK_FILE_NAME: &str = "session.lock";
+pub const STASH_FILE_NAME: &str = "stash.byte";
+pub const LOCK_FILE_NAME: &str = "lock";
+pub const DATA_LOCK_FILE_NAME: &str = "data.lock";
+pub const JOURNAL_FILE_NAME: &str = "journal";
+pub const JOURNAL_BACKUP_DIRECTORY_NAME: &str = "journal_backup";
+pub const RESTORE_JOURNAL_FILE_NAME: &str = "restore_journal";
+pub const STAGING_DIRECTORY_NAME: &str = "staging";
+pub const BACKUP_DIRECTORY_NAME: &str = "backup";
+pub const MIGRATE_DIRECTORY_NAME: &str = "migrate";
+pub const INCOMING_DIRECTORY_NAME: &str = "incoming";
+
+// Version of the repository layout, stored in the format file. Older repositories are
+// upgraded by `minegit upgrade`, see `migrate` for the changes of every version.
+pub const FORMAT_VERSION: u32 = 1;
+
+// Start of every commits file, commit info file and package, so a file of another type or
+// layout is not read as one of this version
+pub const COMMITS_MAGIC: &[u8; 8] = b"MGCMIT01";
+pub const COMMITS_INFO_MAGIC: &[u8; 8] = b"MGINFO01";
+pub const PACKAGE_MAGIC: &[u8; 8] = b"MGPACK01";
 
 #[derive(Debug, Copy, Clone)]
 pub struct Commit {
@@ -25,10 +53,7 @@ unsafe impl Zeroable for Commit {}
 impl fmt::Display for Commit {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Safely extract tag as string, stopping at first zero byte
-        let tag_str = match self.tag_as_str() {
-            Ok(s) => s,
-            Err(_) => "<invalid UTF-8>",
-        };
+        let tag_str = self.tag_as_str().unwrap_or("<invalid UTF-8>");
 
         // Convert timestamp to readable date (assuming seconds)
         let datetime = DateTime::from_timestamp(self.timestamp, 0)
@@ -63,11 +88,13 @@ impl Commit {
 #[derive(Debug, Encode, Decode)]
 pub struct CommitInfo {
     pub id: u32,
-    pub file_info: HashMap<[u8; 128], FileInfo>,
+    // Sorted by path, so equal commits encode to the same bytes
+    pub file_info: BTreeMap<String, FileInfo>,
 }
 
-#[derive(Debug, Encode, Decode)]
+#[derive(Debug, Clone, Copy, Encode, Decode)]
 pub struct FileInfo {
-    pub hash: [u8; 256],
+    // SHA-256 of the file
+    pub hash: [u8; 32],
     pub package_pos: u64,
 }
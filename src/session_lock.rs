@@ -0,0 +1,101 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+
+use crate::savefiles::SESSION_LOCK_FILE_NAME;
+use crate::utils::fs_utils;
+
+// Checks whether the world is open in the game or a running server.
+//
+// Minecraft keeps an exclusive lock on `session.lock` while the world is loaded, so the
+// world is in use if the same lock can not be acquired. The lock is released right away.
+pub fn is_world_in_use(target_path: &str) -> io::Result<bool> {
+    let path = fs_utils::build_path([target_path, SESSION_LOCK_FILE_NAME])?;
+    if !fs_utils::is_path_exists(&path) {
+        return Ok(false);
+    }
+
+    let file = OpenOptions::new().write(true).open(&path)?;
+    Ok(!try_lock(&file)?)
+}
+
+// Java locks files with fcntl on unix, which does not interact with flock used by std
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // Whole file write lock, the same one FileChannel::tryLock takes
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+        // Closing the file releases the lock
+        return Ok(true);
+    }
+
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EAGAIN) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock(file: &File) -> io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => {
+            file.unlock()?;
+            Ok(true)
+        }
+        Err(std::fs::TryLockError::WouldBlock) => Ok(false),
+        Err(std::fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_utils::{temp_dir, write_world_file};
+
+    // fcntl locks never conflict within a process, so the game is played by a forked child
+    #[cfg(unix)]
+    #[test]
+    fn detects_a_session_lock_held_by_another_process() {
+        let world = temp_dir("session-lock");
+        assert!(!is_world_in_use(&world).unwrap());
+        write_world_file(&world, SESSION_LOCK_FILE_NAME, b"");
+        assert!(!is_world_in_use(&world).unwrap());
+
+        let path = fs_utils::build_path([&world, SESSION_LOCK_FILE_NAME]).unwrap();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let mut pipe = [0; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            // Holds the lock until it is killed
+            unsafe {
+                if !try_lock(&file).unwrap_or(false) {
+                    libc::_exit(1);
+                }
+                libc::write(pipe[1], b"l".as_ptr().cast(), 1);
+                loop {
+                    libc::pause();
+                }
+            }
+        }
+        drop(file);
+        unsafe { libc::close(pipe[1]) };
+
+        let mut locked = 0u8;
+        let read = unsafe { libc::read(pipe[0], (&mut locked as *mut u8).cast(), 1) };
+        let in_use = is_world_in_use(&world).unwrap();
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+            libc::close(pipe[0]);
+        }
+        assert_eq!(read, 1);
+        assert!(in_use);
+        assert!(!is_world_in_use(&world).unwrap());
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Component, Path};
+
+use crate::committer;
+use crate::journal::{self, Journal};
+use crate::recover::snapshot::SnapshotHeader;
+use crate::savefiles::{
+    Commit, COMMITS_FILE_NAME, COMMITS_INFO_FILE_NAME, DATA_DIRECTORY_NAME, DIRECTORY_NAME,
+    INCOMING_DIRECTORY_NAME, PACKAGE_MAGIC,
+};
+use crate::utils::fs_utils;
+
+// Transfer of commits between repositories.
+//
+// Every repository file is append-only, and the snapshots of a commit are appended to the
+// packages right after those of the previous commit. A repository whose commits are a prefix
+// of another one's therefore also has a byte prefix of its packages. Missing commits are
+// sent one by one as a `CommitPack` holding the commit, its commit info and the snapshots
+// that start at or after the end of the receiver's packages.
+//
+// The one exception are reverse deltas, where committing a version rewrites the previous one
+// in place as a delta against it. The previous version keeps its position, so both copies of
+// it are valid, but the receiver's package is then replaced from that version on.
+
+// What a repository has, used to negotiate what is missing on the other side
+pub struct RepoState {
+    pub commits: Vec<Commit>,
+    pub head: Option<u32>,
+    pub stash_ids: Vec<u32>,
+    // Length of every package by the path of its file
+    pub packages: HashMap<String, u64>,
+}
+
+// A commit with everything the receiver needs to add it
+pub struct CommitPack {
+    pub commit: Commit,
+    // Compressed commit info as stored in the commit info file
+    pub info: Vec<u8>,
+    pub snapshots: Vec<SnapshotData>,
+}
+
+// Raw bytes (headers and payloads) of the snapshots starting at `pos` in the package of `path`,
+// the receiver's package is cut at `pos` if it is longer. They are the `len` bytes at `offset`
+// of the file `source`, a package of the sender or a file received over the network, so a
+// pack is never held in memory.
+pub struct SnapshotData {
+    pub path: String,
+    pub pos: u64,
+    pub len: u64,
+    pub source: String,
+    pub offset: u64,
+}
+
+impl SnapshotData {
+    pub fn open(&self) -> io::Result<io::Take<BufReader<File>>> {
+        let mut file = fs_utils::read_file(&self.source)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        Ok(BufReader::new(file).take(self.len))
+    }
+}
+
+impl RepoState {
+    pub fn read(target_path: &str) -> Result<Self, Box<dyn Error>> {
+        let data_path = fs_utils::build_path([target_path, DIRECTORY_NAME, DATA_DIRECTORY_NAME])?;
+        let mut packages = HashMap::new();
+        if fs_utils::is_path_exists(&data_path) {
+            for entry in fs_utils::get_all_files_in_directory(&data_path)? {
+                let full_path = entry.path();
+                let relative = full_path.strip_prefix(&data_path)?;
+                let Some(path) = relative.to_str().and_then(|p| p.strip_suffix(".pkg")) else {
+                    continue;
+                };
+                packages.insert(path.to_string(), entry.metadata()?.len());
+            }
+        }
+
+        Ok(Self {
+            commits: committer::read_all_commits(target_path)?,
+            head: committer::get_head(target_path).ok(),
+            stash_ids: committer::read_stash_ids(target_path)?,
+            packages,
+        })
+    }
+}
+
+// Commits the sender has and the receiver misses. The common commits must be equal, otherwise
+// the histories diverged, e.g. both sides committed independently or one was pruned.
+pub fn missing_commits(
+    sender: &[Commit],
+    receiver: &[Commit],
+) -> Result<Range<u32>, Box<dyn Error>> {
+    for (sent, received) in sender.iter().zip(receiver) {
+        if !is_same_commit(sent, received) {
+            return Err(format!(
+                "The repositories diverged at commit {}, they were committed to independently or pruned",
+                sent.id
+            )
+            .into());
+        }
+    }
+    Ok(receiver.len().min(sender.len()) as u32..sender.len() as u32)
+}
+
+// The commit info position is local to a repository and not compared
+pub fn is_same_commit(a: &Commit, b: &Commit) -> bool {
+    a.id == b.id
+        && a.parent_id == b.parent_id
+        && a.timestamp == b.timestamp
+        && a.info_length == b.info_length
+        && a.tag == b.tag
+}
+
+// Reads a commit and the snapshots it added, `package_lens` holds the lengths of the receiver's
+// packages and is updated as if the pack was applied
+pub fn read_commit_pack(
+    target_path: &str,
+    id: u32,
+    package_lens: &mut HashMap<String, u64>,
+) -> Result<CommitPack, Box<dyn Error>> {
+    let commit = committer::get_commit_by_id(target_path, id)?;
+    let info = committer::read_raw_commit_info(target_path, &commit)?;
+    let commit_info = committer::read_commit_info(Cursor::new(&info), 0, info.len())?;
+    let parent_info = match commit.parent_id {
+        parent_id if parent_id != commit.id => {
+            Some(committer::get_commit_info_by_id(target_path, parent_id)?)
+        }
+        _ => None,
+    };
+
+    // The file infos are sorted, so the snapshots of a pack are in a stable order
+    let mut snapshots = Vec::new();
+    for (path, file_info) in commit_info.file_info {
+        let pos = file_info.package_pos;
+        // Files excluded from a commit before they were ever saved
+        if pos == u64::MAX {
+            continue;
+        }
+        let len = package_lens.get(&path).copied().unwrap_or(0);
+        let parent_pos = parent_info
+            .as_ref()
+            .and_then(|info| info.file_info.get(&path))
+            .map(|info| info.package_pos)
+            .filter(|&parent_pos| parent_pos < pos);
+        if pos < len && parent_pos.is_none() {
+            continue;
+        }
+
+        // A renamed file is stored as a reference followed by a delta, everything after the
+        // end of the receiver's package up to the version of the commit is sent
+        let package_path = get_package_path(target_path, &path)?;
+        let mut package = committer::read_package(&package_path)?;
+        package.seek(SeekFrom::Start(pos))?;
+        let mut header = SnapshotHeader::deserialize(&mut package)?;
+        let mut end = pos + SnapshotHeader::SERIZIZED_SIZE as u64 + header.payload_len;
+
+        // With reverse deltas the previous version was rewritten as a delta against this one.
+        // A receiver without this version holds the previous one in full, the rewrite ends
+        // before this version does, and gets the package again from the previous version on.
+        let mut start = len;
+        if let Some(parent_pos) = parent_pos {
+            package.seek(SeekFrom::Start(parent_pos))?;
+            let parent = SnapshotHeader::deserialize(&mut package)?;
+            if parent.depend_on == pos && len < end {
+                start = parent_pos;
+            }
+        }
+        if start == len && pos < len {
+            continue;
+        }
+
+        // A version rewritten as a delta against a later one is sent along with it
+        while header.depend_on != u64::MAX && header.depend_on > header.pos {
+            package.seek(SeekFrom::Start(header.depend_on))?;
+            header = SnapshotHeader::deserialize(&mut package)?;
+            end = header.pos + header.payload_len;
+        }
+
+        package_lens.insert(path.clone(), end);
+        snapshots.push(SnapshotData {
+            path,
+            pos: start,
+            len: end - start,
+            source: package_path,
+            offset: start,
+        });
+    }
+
+    Ok(CommitPack {
+        commit,
+        info,
+        snapshots,
+    })
+}
+
+// Lengths the packages had right after the first `count` commits were added. The snapshots of
+// a commit are appended after those of the commits before it, so a package ends after the
+// newest snapshot referenced by one of these commits.
+pub fn package_lens_at(
+    target_path: &str,
+    count: u32,
+) -> Result<HashMap<String, u64>, Box<dyn Error>> {
+    let mut newest: HashMap<String, u64> = HashMap::new();
+    for id in 0..count {
+        for (path, file_info) in committer::get_commit_info_by_id(target_path, id)?.file_info {
+            if file_info.package_pos == u64::MAX {
+                continue;
+            }
+            let pos = newest.entry(path).or_insert(0);
+            *pos = (*pos).max(file_info.package_pos);
+        }
+    }
+
+    let mut package_lens = HashMap::new();
+    for (path, pos) in newest {
+        let mut package = committer::read_package(&get_package_path(target_path, &path)?)?;
+        package.seek(SeekFrom::Start(pos))?;
+        let header = SnapshotHeader::deserialize(&mut package)?;
+        package_lens.insert(
+            path,
+            pos + SnapshotHeader::SERIZIZED_SIZE as u64 + header.payload_len,
+        );
+    }
+    Ok(package_lens)
+}
+
+// Appends a commit pack to the repository. The writes are journaled like a local commit, so
+// an interrupted transfer keeps every commit applied before it.
+pub fn apply_commit_pack(target_path: &str, pack: &CommitPack) -> Result<(), Box<dyn Error>> {
+    let id = committer::get_commit_count(target_path)?;
+    if pack.commit.id != id {
+        return Err(format!(
+            "Received commit {} while the repository expects commit {id}",
+            pack.commit.id
+        )
+        .into());
+    }
+    validate_pack(pack)?;
+
+    let mut journal = Journal::begin(target_path)?;
+    let result = append_pack(target_path, pack, &mut journal);
+    match result {
+        Ok(()) => Ok(journal.finish()?),
+        Err(e) => {
+            drop(journal);
+            journal::recover(target_path)?;
+            Err(e)
+        }
+    }
+}
+
+fn append_pack(
+    target_path: &str,
+    pack: &CommitPack,
+    journal: &mut Journal,
+) -> Result<(), Box<dyn Error>> {
+    let root_path = fs_utils::build_path([target_path, DIRECTORY_NAME])?;
+
+    for snapshot in &pack.snapshots {
+        let package_path = get_package_path(target_path, &snapshot.path)?;
+        let len = fs_utils::get_file_size(&package_path).unwrap_or(0);
+        let rewrite = snapshot.pos < len && is_reverse_rewrite(target_path, pack, snapshot, len)?;
+        // A bundle may hold bytes the receiver already got with a version depending on a
+        // later one, see `read_commit_pack`
+        let present =
+            if snapshot.pos < len && !rewrite && holds_start(&package_path, snapshot, len)? {
+                len - snapshot.pos
+            } else {
+                0
+            };
+        if len < snapshot.pos || (snapshot.pos < len && !rewrite && present == 0) {
+            return Err(format!(
+                "The package of {} does not match the sender's, the repositories diverged",
+                snapshot.path
+            )
+            .into());
+        }
+
+        journal.record_package(&package_path)?;
+        if let Some(parent) = Path::new(&package_path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // The end of the package was rewritten by the sender, see `read_commit_pack`
+        if rewrite {
+            journal.record_rewrite(&package_path, snapshot.pos)?;
+            fs_utils::truncate_file(&package_path, snapshot.pos)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&package_path)?;
+        let mut data = snapshot.open()?;
+        io::copy(&mut (&mut data).take(present), &mut io::sink())?;
+        let copied = io::copy(&mut data, &mut file)?;
+        if present + copied != snapshot.len {
+            return Err(format!("The snapshots of {} are truncated", snapshot.path).into());
+        }
+        file.sync_all()?;
+    }
+
+    let (info_file, info_pos) = committer::append_commit_info(
+        &fs_utils::build_path([&root_path, COMMITS_INFO_FILE_NAME])?,
+        &pack.info,
+    )?;
+    info_file.sync_all()?;
+
+    let mut commit = pack.commit;
+    commit.info_pos = info_pos;
+    committer::append_commit(
+        &fs_utils::build_path([&root_path, COMMITS_FILE_NAME])?,
+        &commit,
+    )?
+    .sync_all()?;
+    Ok(())
+}
+
+// A sender only rewrites the end of a package for reverse deltas: the version of the parent
+// commit, stored in full at the end of the package, is replaced by a delta against the version
+// following it. Any other rewrite would drop history the receiver already has.
+fn is_reverse_rewrite(
+    target_path: &str,
+    pack: &CommitPack,
+    snapshot: &SnapshotData,
+    len: u64,
+) -> Result<bool, Box<dyn Error>> {
+    if pack.commit.parent_id == pack.commit.id || snapshot.pos + snapshot.len <= len {
+        return Ok(false);
+    }
+    let parent_info = committer::get_commit_info_by_id(target_path, pack.commit.parent_id)?;
+    let parent_pos = parent_info
+        .file_info
+        .get(&snapshot.path)
+        .map(|info| info.package_pos);
+    if parent_pos != Some(snapshot.pos) {
+        return Ok(false);
+    }
+
+    let mut package = committer::read_package(&get_package_path(target_path, &snapshot.path)?)?;
+    package.seek(SeekFrom::Start(snapshot.pos))?;
+    let header = SnapshotHeader::deserialize(&mut package)?;
+    Ok(header.depend_on == u64::MAX
+        && !header.is_reference
+        && header.pos + header.payload_len == len)
+}
+
+// Whether the package already ends with the start of the snapshots, which then reach past it
+fn holds_start(
+    package_path: &str,
+    snapshot: &SnapshotData,
+    len: u64,
+) -> Result<bool, Box<dyn Error>> {
+    if snapshot.pos + snapshot.len < len {
+        return Ok(false);
+    }
+    let mut package = BufReader::new(fs_utils::read_file(package_path)?);
+    package.seek(SeekFrom::Start(snapshot.pos))?;
+    let mut data = snapshot.open()?;
+    let (mut held, mut received) = (vec![0u8; 1 << 16], vec![0u8; 1 << 16]);
+    let mut left = len - snapshot.pos;
+    while left > 0 {
+        let n = left.min(held.len() as u64) as usize;
+        package.read_exact(&mut held[..n])?;
+        data.read_exact(&mut received[..n])?;
+        if held[..n] != received[..n] {
+            return Ok(false);
+        }
+        left -= n as u64;
+    }
+    Ok(true)
+}
+
+// Checks a pack from another repository before anything is written
+fn validate_pack(pack: &CommitPack) -> Result<(), Box<dyn Error>> {
+    committer::read_commit_info(Cursor::new(&pack.info), 0, pack.info.len())
+        .map_err(|e| format!("Received an invalid commit info: {e}"))?;
+
+    for snapshot in &pack.snapshots {
+        if !is_relative(&snapshot.path) {
+            return Err(format!("Received a snapshot with invalid path {}", snapshot.path).into());
+        }
+
+        // Positions are those of the source file, the snapshots are only walked by their headers
+        let mut data = fs_utils::read_file(&snapshot.source)?;
+        let data_end = snapshot.offset + snapshot.len;
+        if fs_utils::get_file_size(&snapshot.source)? < data_end {
+            return Err(format!("Received truncated snapshots of {}", snapshot.path).into());
+        }
+        let mut position = snapshot.offset;
+
+        // A new package starts with the format magic
+        if snapshot.pos == 0 {
+            let mut magic = [0u8; PACKAGE_MAGIC.len()];
+            data.seek(SeekFrom::Start(position))?;
+            if snapshot.len < magic.len() as u64
+                || data.read_exact(&mut magic).is_err()
+                || &magic != PACKAGE_MAGIC
+            {
+                return Err(
+                    format!("Received a package of {} in another format", snapshot.path).into(),
+                );
+            }
+            position += PACKAGE_MAGIC.len() as u64;
+        }
+        while position < data_end {
+            data.seek(SeekFrom::Start(position))?;
+            let header = SnapshotHeader::deserialize(&mut data)?;
+            let end = header.pos + header.payload_len;
+            if end > data_end {
+                return Err(format!("Received a truncated snapshot of {}", snapshot.path).into());
+            }
+            if header.is_reference {
+                let (path, _) = header.read_reference(&mut data)?;
+                if !is_relative(&path) {
+                    return Err(format!("Received a reference to invalid path {path}").into());
+                }
+            }
+            position = end;
+        }
+    }
+    Ok(())
+}
+
+fn is_relative(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn get_package_path(target_path: &str, path: &str) -> Result<String, Box<dyn Error>> {
+    Ok(fs_utils::build_path([
+        target_path,
+        DIRECTORY_NAME,
+        DATA_DIRECTORY_NAME,
+        &format!("{path}.pkg"),
+    ])?)
+}
+
+// Empty directory for the snapshots of a pack received over the network or from a bundle
+pub fn clear_incoming(target_path: &str) -> io::Result<String> {
+    remove_incoming(target_path)?;
+    let incoming_path =
+        fs_utils::build_path([target_path, DIRECTORY_NAME, INCOMING_DIRECTORY_NAME])?;
+    fs_utils::make_dir(&incoming_path)?;
+    Ok(incoming_path)
+}
+
+pub fn remove_incoming(target_path: &str) -> io::Result<()> {
+    let incoming_path =
+        fs_utils::build_path([target_path, DIRECTORY_NAME, INCOMING_DIRECTORY_NAME])?;
+    if fs_utils::is_path_exists(&incoming_path) {
+        fs_utils::remove_dir_all(&incoming_path)?;
+    }
+    Ok(())
+}
+
+// A push moves the head of the receiver without restoring its world, so only repositories
+// without world files, like those created by a push, accept one
+pub fn check_accepts_push(target_path: &str) -> Result<(), Box<dyn Error>> {
+    if committer::has_world_files(target_path)? {
+        return Err(format!(
+            "{target_path} has a world that a push would leave behind its head, push only into repositories without world files"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Adds the stash ids of the sender the receiver does not have yet
+pub fn merge_stash_ids(target_path: &str, stash_ids: &[u32]) -> Result<(), Box<dyn Error>> {
+    let existing = committer::read_stash_ids(target_path)?;
+    for &id in stash_ids {
+        if !existing.contains(&id) {
+            committer::add_stash_id(target_path, id)?;
+        }
+    }
+    Ok(())
+}
+
+// Sends the commits `from` has and `to` misses, returns the number of sent commits
+pub fn transfer(from_path: &str, to_path: &str) -> Result<u32, Box<dyn Error>> {
+    let from = RepoState::read(from_path)?;
+    let to = RepoState::read(to_path)?;
+
+    let missing = missing_commits(&from.commits, &to.commits)?;
+    let count = missing.len() as u32;
+    let mut package_lens = to.packages;
+    for id in missing {
+        let pack = read_commit_pack(from_path, id, &mut package_lens)?;
+        apply_commit_pack(to_path, &pack)?;
+    }
+
+    merge_stash_ids(to_path, &from.stash_ids)?;
+    Ok(count)
+}
@@ -39,24 +39,56 @@ pub fn get_current_path() -> io::Result<String> {
 }
 
 pub fn make_dir(path: &str) -> io::Result<()> {
-    fs::create_dir(&path)?;
+    fs::create_dir(path)?;
     Ok(())
 }
 
 pub fn remove_file(path: &str) -> io::Result<()> {
-    fs::remove_file(&path)?;
+    fs::remove_file(path)?;
     Ok(())
 }
 
+pub fn remove_dir_all(path: &str) -> io::Result<()> {
+    fs::remove_dir_all(path)?;
+    Ok(())
+}
+
+// Moves a file, creating missing parent directories of the destination
+pub fn move_file(from: &str, to: &str) -> io::Result<()> {
+    if let Some(parent) = Path::new(to).parent() {
+        create_dir_all(parent)?;
+    }
+    fs::rename(from, to)
+}
+
 pub fn write_file(path: &str, buf: &[u8]) -> io::Result<File> {
     let mut file = File::create(path)?;
     file.write_all(buf)?;
     Ok(file)
 }
 
+// Writes the buffer to a temporary file next to `path` and renames it into place,
+// so readers see either the old or the new content, never a partial write
+pub fn write_file_atomic(path: &str, buf: &[u8]) -> io::Result<()> {
+    let temp_path = format!("{path}.tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+pub fn truncate_file(path: &str, len: u64) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
+
 pub fn append_file(path: &str, buf: &[u8]) -> io::Result<(File, u64)> {
     let mut file = OpenOptions::new()
         .create(true)
+        .truncate(false)
         .read(true) // Needed for seeking
         .write(true) // Needed for writing
         .open(path)?;
@@ -88,7 +120,7 @@ pub fn read_file(path: &str) -> Result<File, io::Error> {
 }
 
 pub fn read_to_end(path: &str, buf: &mut Vec<u8>) -> Result<usize, io::Error> {
-    let mut file = read_file(&path)?;
+    let mut file = read_file(path)?;
 
     file.read_to_end(buf)
 }
@@ -132,7 +164,7 @@ pub fn get_all_files_in_directory(root_path: &str) -> io::Result<Vec<DirEntry>>
     Ok(paths)
 }
 
-pub fn file_hash(path: &str) -> io::Result<String> {
+pub fn file_hash(path: &str) -> io::Result<[u8; 32]> {
     // Create hasher
     let mut hasher = Sha256::new();
     // Open file
@@ -140,8 +172,39 @@ pub fn file_hash(path: &str) -> io::Result<String> {
     // Create and return file hash
     let _n = io::copy(&mut file, &mut hasher)?;
 
-    let bytes = hasher.finalize();
-    Ok(bytes.iter().map(|b| format!("{:02X}", b)).collect())
+    Ok(hasher.finalize().into())
+}
+
+// Passes writes on to `inner` and hashes them like `file_hash`
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // Returns the hash of everything written and the inner writer
+    pub fn finish(self) -> ([u8; 32], W) {
+        (self.hasher.finalize().into(), self.inner)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 pub fn encode_to_bytes<T: Encode>(content: &T) -> Vec<u8> {
@@ -1 +1,3 @@
 pub mod fs_utils;
+#[cfg(test)]
+pub mod test_utils;
@@ -0,0 +1,23 @@
+use std::io::Write;
+
+use crate::utils::fs_utils;
+
+// Empty directory in the system temp directory, unique per test
+pub fn temp_dir(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("minegit-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+pub fn write_world_file(world: &str, path: &str, data: &[u8]) {
+    let mut file =
+        fs_utils::open_to_write(&fs_utils::build_path([world, path]).unwrap(), true).unwrap();
+    file.write_all(data).unwrap();
+}
+
+pub fn read_world_file(world: &str, path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    fs_utils::read_to_end(&fs_utils::build_path([world, path]).unwrap(), &mut data).unwrap();
+    data
+}
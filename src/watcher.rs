@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::committer::{self, parse_region_path};
+use crate::config::Config;
+use crate::ignore_filter::IgnoreFilter;
+use crate::journal;
+use crate::lock::RepoLock;
+use crate::savefiles::DIRECTORY_NAME;
+use crate::session_lock;
+use crate::utils::fs_utils;
+
+// Regions listed per dimension in an automatic commit message before they are only counted
+const MAX_LISTED_REGIONS: usize = 8;
+
+// Watches the world and commits changes once no file was modified for `debounce`,
+// with at least `interval` between commits
+pub fn watch(
+    target_path: &str,
+    interval: Duration,
+    debounce: Duration,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    let filter = IgnoreFilter::new(&fs_utils::build_path([target_path, DIRECTORY_NAME])?);
+
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(Path::new(target_path), RecursiveMode::Recursive)?;
+
+    println!(
+        "Watching {target_path} (interval {}, debounce {})",
+        humantime::format_duration(interval),
+        humantime::format_duration(debounce)
+    );
+    commit_events(target_path, &filter, rx, interval, debounce, force)
+}
+
+// Commits the changes reported by `rx` like `watch`, until the sender is dropped
+fn commit_events(
+    target_path: &str,
+    filter: &IgnoreFilter,
+    rx: mpsc::Receiver<notify::Result<Event>>,
+    interval: Duration,
+    debounce: Duration,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut changed = BTreeSet::new();
+    let mut last_change = Instant::now();
+    let mut last_commit: Option<Instant> = None;
+
+    loop {
+        // Wait for the next event, or until the pending changes are due to be committed
+        let timeout = if changed.is_empty() {
+            Duration::MAX
+        } else {
+            let quiet_at = last_change + debounce;
+            let allowed_at = last_commit.map_or(quiet_at, |at| at + interval);
+            quiet_at
+                .max(allowed_at)
+                .saturating_duration_since(Instant::now())
+        };
+
+        match rx.recv_timeout(timeout) {
+            Ok(event) => {
+                if collect_changes(target_path, filter, event?, &mut changed) {
+                    last_change = Instant::now();
+                }
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let config = Config::load(target_path)?;
+        if !force && config.rcon.is_none() && session_lock::is_world_in_use(target_path)? {
+            println!("The world is in use, the commit is postponed until it is closed.");
+            last_change = Instant::now();
+            continue;
+        }
+
+        // Events received while committing, e.g. of the flush before it, start the next
+        // debounce, a commit is only made if they changed anything
+        changed.clear();
+        match commit_changes(target_path, &config) {
+            Ok(Some(tag)) => {
+                println!("[{}] {tag}", Local::now().format("%Y-%m-%d %H:%M:%S"));
+                last_commit = Some(Instant::now());
+            }
+            Ok(None) => {}
+            // The changes are still uncommitted, so they are part of the next commit
+            Err(e) => {
+                eprintln!("The commit failed, it is retried on the next change: {e}");
+                last_commit = Some(Instant::now());
+            }
+        }
+    }
+}
+
+// Commits the changes of the world against the head, returns the message of the commit or
+// `None` if nothing changed
+fn commit_changes(target_path: &str, config: &Config) -> Result<Option<String>, Box<dyn Error>> {
+    let _lock = RepoLock::acquire(target_path, true)?;
+    journal::recover_all(target_path)?;
+
+    let changes = committer::get_uncommitted_changes(target_path, &[])?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    let tag = describe_changes(&changes.into_iter().map(|(path, _)| path).collect());
+    committer::commit_world(target_path, &tag, Vec::new(), None, config.rcon.as_ref())?;
+    Ok(Some(tag))
+}
+
+// Adds the not ignored paths changed by the event, returns true if any was added
+fn collect_changes(
+    target_path: &str,
+    filter: &IgnoreFilter,
+    event: Event,
+    changed: &mut BTreeSet<String>,
+) -> bool {
+    if !matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    ) {
+        return false;
+    }
+
+    let mut any = false;
+    for path in event.paths {
+        if path.is_dir() {
+            continue;
+        }
+        let Ok(relative) = path.strip_prefix(target_path) else {
+            continue;
+        };
+        if filter.is_ignored(relative) {
+            continue;
+        }
+        if let Some(relative) = relative.to_str() {
+            any |= changed.insert(relative.to_string());
+        }
+    }
+    any
+}
+
+// Builds a commit message listing changed regions per dimension and the number of other files
+fn describe_changes(changed: &BTreeSet<String>) -> String {
+    let mut regions: BTreeMap<i32, BTreeSet<(i32, i32)>> = BTreeMap::new();
+    let mut other = 0;
+
+    for path in changed {
+        match parse_region_path(path) {
+            Some([dim, x, z]) => {
+                regions.entry(dim).or_default().insert((x, z));
+            }
+            None => other += 1,
+        }
+    }
+
+    let mut parts = Vec::new();
+    for (dim, coords) in &regions {
+        let name = match dim {
+            -1 => "Nether",
+            1 => "End",
+            _ => "Overworld",
+        };
+        if coords.len() > MAX_LISTED_REGIONS {
+            parts.push(format!("{name} {} regions", coords.len()));
+        } else {
+            let list: Vec<String> = coords.iter().map(|(x, z)| format!("r.{x}.{z}")).collect();
+            parts.push(format!("{name} {}", list.join(" ")));
+        }
+    }
+    if other > 0 {
+        parts.push(format!("{other} other file(s)"));
+    }
+
+    format!("Auto commit: {}", parts.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initializer;
+    use crate::utils::test_utils::{temp_dir, write_world_file};
+    use notify::event::ModifyKind;
+    use std::thread;
+
+    fn modified(world: &str, path: &str) -> notify::Result<Event> {
+        Ok(Event::new(EventKind::Modify(ModifyKind::Any)).add_path(Path::new(world).join(path)))
+    }
+
+    // Commits the events sent to the returned sender until it is dropped
+    fn watch_in_thread(
+        world: &str,
+        debounce: Duration,
+    ) -> (mpsc::Sender<notify::Result<Event>>, thread::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel();
+        let world = world.to_string();
+        let handle = thread::spawn(move || {
+            let filter =
+                IgnoreFilter::new(&fs_utils::build_path([&world, DIRECTORY_NAME]).unwrap());
+            commit_events(&world, &filter, rx, Duration::ZERO, debounce, true).unwrap();
+        });
+        (tx, handle)
+    }
+
+    #[test]
+    fn commits_once_the_world_is_quiet_for_the_debounce() {
+        let world = temp_dir("watch-debounce");
+        write_world_file(&world, "region/r.0.0.mca", &[1; 5000]);
+        initializer::init(&world).unwrap();
+
+        let (tx, handle) = watch_in_thread(&world, Duration::from_millis(500));
+
+        write_world_file(&world, "region/r.0.0.mca", &[2; 5000]);
+        tx.send(modified(&world, "region/r.0.0.mca")).unwrap();
+        thread::sleep(Duration::from_millis(250));
+        write_world_file(&world, "level.dat", b"level");
+        tx.send(modified(&world, "level.dat")).unwrap();
+        // Events of the repository itself are ignored
+        tx.send(modified(&world, ".minegit/HEAD")).unwrap();
+
+        // The second change postponed the commit
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(committer::get_commit_count(&world).unwrap(), 1);
+
+        thread::sleep(Duration::from_millis(700));
+        assert_eq!(committer::get_commit_count(&world).unwrap(), 2);
+        drop(tx);
+        handle.join().unwrap();
+
+        let commit = committer::get_commit_by_id(&world, 1).unwrap();
+        assert_eq!(
+            commit.tag_as_str().unwrap(),
+            "Auto commit: Overworld r.0.0, 1 other file(s)"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_watching_after_a_failed_commit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let world = temp_dir("watch-failed");
+        write_world_file(&world, "level.dat", b"level");
+        initializer::init(&world).unwrap();
+        crate::config::set_value(&world, "hooks.enabled", "true").unwrap();
+        let hook_path = ".minegit/hooks/pre-commit";
+        write_world_file(&world, hook_path, b"#!/bin/sh\nexit 1\n");
+        let hook_path = fs_utils::build_path([&world, hook_path]).unwrap();
+        std::fs::set_permissions(&hook_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let (tx, handle) = watch_in_thread(&world, Duration::from_millis(200));
+
+        // Nothing changed, e.g. a flush of an already committed world
+        tx.send(modified(&world, "level.dat")).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(committer::get_commit_count(&world).unwrap(), 1);
+
+        write_world_file(&world, "level.dat", b"changed");
+        tx.send(modified(&world, "level.dat")).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(committer::get_commit_count(&world).unwrap(), 1);
+        assert!(!handle.is_finished());
+
+        // The next change commits the one that failed with it
+        fs_utils::remove_file(&hook_path).unwrap();
+        write_world_file(&world, "new.dat", b"new");
+        tx.send(modified(&world, "new.dat")).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(committer::get_commit_count(&world).unwrap(), 2);
+        drop(tx);
+        handle.join().unwrap();
+
+        let commit = committer::get_commit_by_id(&world, 1).unwrap();
+        assert_eq!(commit.tag_as_str().unwrap(), "Auto commit: 2 other file(s)");
+    }
+}